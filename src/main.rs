@@ -1,3 +1,42 @@
-fn main() {
-    println!("Hello, world!");
+use std::env;
+use std::fs;
+use std::time::Instant;
+
+use anyhow::{bail, Context, Result};
+
+use trimrs::report::{full_report, qc_tsv, JsonReport, MultiqcReport};
+use trimrs::runner::RunConf;
+
+fn main() -> Result<()> {
+    let args = env::args().collect::<Vec<String>>();
+    if args.len() != 2 {
+        bail!("Exactly one argument, the TOML run configuration");
+    }
+
+    let conf_text = fs::read_to_string(&args[1]).with_context(|| format!("Reading configuration {:?}", args[1]))?;
+    let conf: RunConf = toml::from_str(&conf_text).with_context(|| format!("Parsing configuration {:?}", args[1]))?;
+
+    let start = Instant::now();
+    let outcome = conf.run()?;
+    let elapsed = start.elapsed();
+    for collision in outcome.barcode_collisions() {
+        eprintln!("WARNING: {}", collision);
+    }
+    println!("{}", full_report(&outcome, elapsed, 0.5));
+
+    if let Some(path) = conf.json_report() {
+        let json = JsonReport::new(&conf, &outcome, elapsed).to_json()?;
+        fs::write(path, json + "\n").with_context(|| format!("Writing JSON report {:?}", path))?;
+    }
+
+    if let Some(path) = conf.multiqc_report() {
+        let json = MultiqcReport::new(&conf.sample_name(), outcome.counts()).to_json()?;
+        fs::write(path, json + "\n").with_context(|| format!("Writing MultiQC report {:?}", path))?;
+    }
+
+    if let Some(path) = conf.qc_tsv() {
+        fs::write(path, qc_tsv(outcome.qc())).with_context(|| format!("Writing QC statistics {:?}", path))?;
+    }
+
+    Ok(())
 }
@@ -0,0 +1,718 @@
+//! Adapter identification and removal.
+//!
+//! Each adapter is aligned against the read, and the best match
+//! among all adapters is chosen: the one with the most matches,
+//! breaking ties by the fewest errors and then by the order in which
+//! the adapters were given. The adapter and the read sequence on its
+//! far side are trimmed, and the match is recorded on the read.
+//!
+//! * A 3' adapter may occur anywhere in the read, or partially at its
+//!   end, and is trimmed along with everything after it.
+//! * A 5' adapter may occur anywhere in the read, or partially at its
+//!   start, and is trimmed along with everything before it.
+//! * An anchored 3' adapter must occur in full at the end of the read.
+//! * An anchored 5' adapter must occur in full at the start of the
+//!   read.
+//!
+//! The alignment modes for each kind of adapter follow `Cutadapt`.
+
+use std::sync::Arc;
+
+use anyhow::{ensure, Context, Result};
+use serde::{Serialize, Deserialize};
+
+use crate::align::{AlignEnds, AlignMatching, Aligner, AlignerConf, Location, PrefixComparer, SuffixComparer};
+use crate::stats::{ErrorHistogram, ReadCount};
+use crate::trimming::Trimming;
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdapterKind {
+    ThreePrime,
+    FivePrime,
+    AnchoredThreePrime,
+    AnchoredFivePrime,
+}
+
+impl AdapterKind {
+    /// Alignment ends for the adapter (reference) and the read
+    /// (query).
+    fn ends(self) -> (AlignEnds, AlignEnds) {
+        match self {
+            AdapterKind::ThreePrime => (AlignEnds::LocalStop, AlignEnds::Local),
+            AdapterKind::FivePrime => (AlignEnds::LocalStart, AlignEnds::Local),
+            AdapterKind::AnchoredThreePrime => (AlignEnds::Global, AlignEnds::LocalStart),
+            AdapterKind::AnchoredFivePrime => (AlignEnds::Global, AlignEnds::LocalStop),
+        }
+    }
+
+    /// Whether the read sequence after the adapter is trimmed, rather
+    /// than the sequence before it.
+    pub fn trims_end(self) -> bool {
+        self == AdapterKind::ThreePrime || self == AdapterKind::AnchoredThreePrime
+    }
+}
+
+fn default_max_error_rate() -> f64 { 0.1 }
+
+fn default_min_overlap() -> usize { 3 }
+
+fn default_indels() -> bool { true }
+
+fn default_quality_base() -> u8 { 33 }
+
+#[derive(Debug, PartialEq, PartialOrd, Clone, Serialize, Deserialize)]
+pub struct AdapterConf {
+    pub name: String,
+    pub sequence: String,
+    pub kind: AdapterKind,
+    #[serde(default = "default_max_error_rate")]
+    pub max_error_rate: f64,
+    #[serde(default = "default_min_overlap")]
+    pub min_overlap: usize,
+    #[serde(default = "default_indels")]
+    pub indels: bool,
+    /// Weight mismatches by the quality of the read base, so that
+    /// mismatches against low-quality bases count as fractional
+    /// errors
+    #[serde(default)]
+    pub quality_weighted: bool,
+    /// Offset of the Phred scores in the qualities weighting the
+    /// mismatches
+    #[serde(default = "default_quality_base")]
+    pub quality_base: u8,
+    /// Count the trimmed reads with more than one non-overlapping
+    /// match of the adapter, such as concatemers
+    #[serde(default)]
+    pub count_concatemers: bool,
+    /// Alignment of the adapter (the reference) with the read (the
+    /// query), replacing the one given by `kind`, `max_error_rate`,
+    /// `min_overlap`, and `indels`. The minimum overlap must not be
+    /// longer than the adapter.
+    #[serde(default)]
+    pub aligner: Option<AlignerConf>,
+}
+
+impl AdapterConf {
+    pub fn adapter(&self) -> Result<Adapter> {
+        let (reference_ends, query_ends) = self.kind.ends();
+        let min_overlap = match self.kind {
+            AdapterKind::AnchoredThreePrime | AdapterKind::AnchoredFivePrime => self.sequence.len(),
+            AdapterKind::ThreePrime | AdapterKind::FivePrime => self.min_overlap.min(self.sequence.len()),
+        };
+        let conf = match &self.aligner {
+            Some(conf) => {
+                conf.validate_reference(self.sequence.as_bytes())
+                    .with_context(|| format!("Invalid alignment for adapter {:?}", self.name))?;
+                conf.clone()
+            },
+            None => AlignerConf {
+                max_error_rate: self.max_error_rate,
+                reference_ends,
+                query_ends,
+                matching: AlignMatching::RefWildcard,
+                query_n: None,
+                mismatch_cost: 1,
+                insertion_cost: 1,
+                deletion_cost: 1,
+                gap_open_cost: 0,
+                mismatch_only: !self.indels,
+                min_overlap,
+            },
+        };
+        // Anchored adapters without indels are compared directly
+        // with the end of the read, finding the same match as the
+        // aligner.
+        let comparer = match (self.kind, &self.aligner, self.indels) {
+            (AdapterKind::AnchoredFivePrime, None, false) =>
+                Some(Comparer::Prefix(PrefixComparer::new(&conf, self.sequence.as_bytes())?)),
+            (AdapterKind::AnchoredThreePrime, None, false) =>
+                Some(Comparer::Suffix(SuffixComparer::new(&conf, self.sequence.as_bytes())?)),
+            _ => None,
+        };
+        Ok(Adapter {
+            name: Arc::from(self.name.as_str()),
+            sequence: self.sequence.clone(),
+            kind: self.kind,
+            max_error_rate: conf.max_error_rate,
+            quality_weighted: self.quality_weighted,
+            quality_base: self.quality_base,
+            count_concatemers: self.count_concatemers,
+            aligner: Aligner::new(&conf, self.sequence.as_bytes())
+                .with_context(|| format!("Invalid adapter {:?}", self.name))?,
+            comparer,
+        })
+    }
+}
+
+#[cfg(test)]
+impl AdapterConf {
+    /// Adapter with the default alignment, as when only its name,
+    /// sequence, and kind are configured
+    pub(crate) fn with_defaults(name: &str, sequence: &str, kind: AdapterKind) -> Self {
+        AdapterConf {
+            name: name.to_string(),
+            sequence: sequence.to_string(),
+            kind,
+            max_error_rate: default_max_error_rate(),
+            min_overlap: default_min_overlap(),
+            indels: default_indels(),
+            quality_weighted: false,
+            quality_base: default_quality_base(),
+            count_concatemers: false,
+            aligner: None,
+        }
+    }
+}
+
+/// Comparer of an anchored adapter without indels, used instead of
+/// the aligner
+#[derive(Clone, Debug)]
+enum Comparer {
+    Prefix(PrefixComparer),
+    Suffix(SuffixComparer),
+}
+
+impl Comparer {
+    fn locate(&mut self, seq: &[u8]) -> Option<Location> {
+        match self {
+            Comparer::Prefix(comparer) => comparer.locate(seq),
+            Comparer::Suffix(comparer) => comparer.locate(seq),
+        }
+    }
+
+    fn locate_encoded(&self, bseq: &[u8]) -> Option<Location> {
+        match self {
+            Comparer::Prefix(comparer) => comparer.locate_encoded(bseq),
+            Comparer::Suffix(comparer) => comparer.locate_encoded(bseq),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Adapter {
+    name: Arc<str>,
+    sequence: String,
+    kind: AdapterKind,
+    max_error_rate: f64,
+    quality_weighted: bool,
+    quality_base: u8,
+    count_concatemers: bool,
+    aligner: Aligner,
+    comparer: Option<Comparer>,
+}
+
+impl Adapter {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn sequence(&self) -> &str {
+        &self.sequence
+    }
+
+    pub fn kind(&self) -> AdapterKind {
+        self.kind
+    }
+}
+
+/// Statistics on the reads trimmed by one adapter.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AdapterStats {
+    pub name: String,
+    pub sequence: String,
+    pub kind: AdapterKind,
+    pub max_error_rate: f64,
+    /// Adapter length, not counting `N` wildcards
+    pub effective_length: usize,
+    /// Maximum number of errors in a match, for each number of
+    /// matching adapter bases not counting `N` wildcards, from 0 to
+    /// `effective_length`
+    pub allowed_errors: Vec<usize>,
+    /// Number of reads trimmed and of bases removed
+    pub trimmed: ReadCount,
+    /// Lengths of the removed sequences, by the number of errors in
+    /// the adapter match
+    pub removed: ErrorHistogram,
+    /// Bases preceding removed 3' adapters, counting `A`, `C`, `G`,
+    /// `T`, and any other base or none
+    pub adjacent_bases: [usize; 5],
+    /// Number of trimmed reads with more than one non-overlapping
+    /// match, when counted
+    pub concatemers: Option<usize>,
+}
+
+impl AdapterStats {
+    fn new(adapter: &Adapter) -> Self {
+        AdapterStats {
+            name: adapter.name.to_string(),
+            sequence: adapter.sequence.clone(),
+            kind: adapter.kind,
+            max_error_rate: adapter.max_error_rate,
+            effective_length: adapter.aligner.effective_length(),
+            allowed_errors: (0..=adapter.aligner.effective_length())
+                .map(|len| adapter.aligner.max_errors(len))
+                .collect(),
+            trimmed: ReadCount::default(),
+            removed: ErrorHistogram::new(),
+            adjacent_bases: [0; 5],
+            concatemers: if adapter.count_concatemers { Some(0) } else { None },
+        }
+    }
+
+    pub fn merge(&mut self, other: &AdapterStats) {
+        self.trimmed.merge(&other.trimmed);
+        self.removed.merge(&other.removed);
+        for (count, other_count) in self.adjacent_bases.iter_mut().zip(other.adjacent_bases.iter()) {
+            *count += *other_count;
+        }
+        if let (Some(count), Some(other_count)) = (&mut self.concatemers, other.concatemers) {
+            *count += other_count;
+        }
+    }
+
+    /// Maximum number of errors allowed in a match of `len` adapter
+    /// bases, of which at most `effective_length` count towards the
+    /// error rate
+    pub fn max_errors(&self, len: usize) -> usize {
+        self.allowed_errors[len.min(self.effective_length)]
+    }
+
+    /// Probabilities that the first `i` adapter bases removed with a
+    /// read match a random sequence with GC content `gc_content`,
+    /// for each `i` from 0 to the adapter length. Indels are not
+    /// taken into account.
+    pub fn random_match_probabilities(&self, gc_content: f64) -> Vec<f64> {
+        // Removing `i` bases of a 5' adapter removes its last `i` bases.
+        let seq: Vec<u8> = if self.kind.trims_end() {
+            self.sequence.bytes().collect()
+        } else {
+            self.sequence.bytes().rev().collect()
+        };
+        let mut p = 1.0;
+        let mut probabilities = vec![p];
+        for nt in seq {
+            // Wildcards match G or C as well as A or T, so they are
+            // treated like G or C as in `Cutadapt`.
+            p *= if b"CGRYSKMBDHVN".contains(&nt.to_ascii_uppercase()) {
+                gc_content / 2.0
+            } else {
+                (1.0 - gc_content) / 2.0
+            };
+            probabilities.push(p);
+        }
+        probabilities
+    }
+}
+
+/// A match of an adapter against a read.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AdapterMatch {
+    adapter: Arc<str>,
+    kind: AdapterKind,
+    location: Location,
+    offset: usize,
+    length: usize,
+    wildcards: Vec<u8>,
+    cigar: Option<String>,
+}
+
+impl AdapterMatch {
+    /// Name of the matching adapter
+    pub fn adapter(&self) -> &str {
+        &self.adapter
+    }
+
+    pub fn kind(&self) -> AdapterKind {
+        self.kind
+    }
+
+    /// Location of the match, with query positions relative to the
+    /// trimmed read sequence that was aligned.
+    pub fn location(&self) -> &Location {
+        &self.location
+    }
+
+    /// Starting position of the aligned read sequence within the raw
+    /// read.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Length of the aligned read sequence
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    /// Read bases matching `N` wildcards in the adapter
+    pub fn wildcards(&self) -> &[u8] {
+        &self.wildcards
+    }
+
+    /// Extended CIGAR string of the alignment of the read with the
+    /// adapter, when the adapters compute alignments
+    pub fn cigar(&self) -> Option<&str> {
+        self.cigar.as_deref()
+    }
+
+    /// Part of the aligned read sequence that is trimmed along with
+    /// the adapter but is not part of the match: the sequence after a
+    /// 3' adapter or before a 5' adapter. The aligned sequence starts
+    /// at `offset` in `seq_raw`.
+    pub fn rest<'a>(&self, seq_raw: &'a [u8]) -> &'a [u8] {
+        if self.kind.trims_end() {
+            &seq_raw[self.raw_querystop()..(self.offset + self.length)]
+        } else {
+            &seq_raw[self.offset..self.raw_querystart()]
+        }
+    }
+
+    /// Starting position of the match within the raw read
+    pub fn raw_querystart(&self) -> usize {
+        self.offset + self.location.querystart()
+    }
+
+    /// Stopping position of the match within the raw read
+    pub fn raw_querystop(&self) -> usize {
+        self.offset + self.location.querystop()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Adapters {
+    adapters: Vec<Adapter>,
+    stats: Vec<AdapterStats>,
+    /// Encoded read, aligned with each adapter for which
+    /// `shared_encoding` is set
+    bseq: Vec<u8>,
+    /// Whether each adapter encodes reads as the first one does,
+    /// which all do unless given their own alignment
+    shared_encoding: Vec<bool>,
+    traceback: bool,
+}
+
+impl Adapters {
+    pub fn new(adapters: Vec<Adapter>) -> Result<Self> {
+        ensure!(!adapters.is_empty(), "No adapters given");
+        for (i, adapter) in adapters.iter().enumerate() {
+            ensure!(adapters[..i].iter().all(|a| a.name != adapter.name),
+                    "Duplicate adapter name {:?}", adapter.name);
+        }
+        let stats = adapters.iter().map(AdapterStats::new).collect();
+        let shared_encoding = adapters.iter()
+            .map(|adapter| adapter.aligner.same_query_encoding(&adapters[0].aligner))
+            .collect();
+        Ok(Adapters { adapters, stats, bseq: Vec::new(), shared_encoding, traceback: false })
+    }
+
+    /// Sets whether the alignment of each match is computed, for its
+    /// CIGAR string. Matches are only located by default, which is
+    /// faster.
+    pub fn set_traceback(&mut self, traceback: bool) {
+        self.traceback = traceback;
+    }
+
+    pub fn adapters(&self) -> &[Adapter] {
+        &self.adapters
+    }
+
+    /// Statistics for each adapter, in the order of `adapters`
+    pub fn stats(&self) -> &[AdapterStats] {
+        &self.stats
+    }
+
+    /// Adds the statistics of `other`, a copy of these adapters.
+    pub fn merge(&mut self, other: &Self) {
+        for (stats, other_stats) in self.stats.iter_mut().zip(other.stats.iter()) {
+            stats.merge(other_stats);
+        }
+    }
+
+    /// Finds the best adapter match in `seq`, with qualities `qual`
+    /// for quality-weighted adapters, returning the index of the
+    /// adapter along with its location.
+    pub fn find(&mut self, seq: &[u8], qual: Option<&[u8]>) -> Option<(usize, Location)> {
+        let mut best: Option<(usize, Location)> = None;
+        self.adapters[0].aligner.encode_query(seq, &mut self.bseq);
+        for (idx, adapter) in self.adapters.iter_mut().enumerate() {
+            let location = match (qual, self.shared_encoding[idx], &mut adapter.comparer) {
+                (Some(qual), true, _) if adapter.quality_weighted =>
+                    adapter.aligner.locate_weighted_encoded(&self.bseq, qual, adapter.quality_base),
+                (Some(qual), false, _) if adapter.quality_weighted =>
+                    adapter.aligner.locate_weighted(seq, qual, adapter.quality_base),
+                (_, true, Some(comparer)) => comparer.locate_encoded(&self.bseq),
+                (_, false, Some(comparer)) => comparer.locate(seq),
+                (_, true, None) => adapter.aligner.locate_encoded(&self.bseq),
+                (_, false, None) => adapter.aligner.locate(seq),
+            };
+            if let Some(loc) = location {
+                let better = match &best {
+                    None => true,
+                    Some((_, best_loc)) => {
+                        loc.matches() > best_loc.matches()
+                            || (loc.matches() == best_loc.matches() && loc.cost() < best_loc.cost())
+                    }
+                };
+                if better {
+                    best = Some((idx, loc));
+                }
+            }
+        }
+        best
+    }
+
+    /// Trims the best adapter match from the read and records it.
+    pub fn trim(&mut self, trimming: &mut Trimming) {
+        let offset = trimming.trim_start();
+        let len = trimming.len_trimmed();
+        let seq = trimming.seq_trimmed();
+        if let Some((idx, location)) = self.find(seq, trimming.qual_trimmed()) {
+            let adapter = &mut self.adapters[idx];
+            let stats = &mut self.stats[idx];
+            // The other matches are found without quality weighting.
+            if let Some(count) = &mut stats.concatemers {
+                if adapter.aligner.locate_all(seq).nth(1).is_some() {
+                    *count += 1;
+                }
+            }
+            let removed = if adapter.kind.trims_end() {
+                let adjacent = match location.querystart().checked_sub(1).map(|i| seq[i]) {
+                    Some(b'A') | Some(b'a') => 0,
+                    Some(b'C') | Some(b'c') => 1,
+                    Some(b'G') | Some(b'g') => 2,
+                    Some(b'T') | Some(b't') => 3,
+                    _ => 4,
+                };
+                stats.adjacent_bases[adjacent] += 1;
+                trimming.trim_from_end(len - location.querystart())
+            } else {
+                trimming.trim_from_start(location.querystop())
+            };
+            stats.trimmed.add(removed);
+            stats.removed.add(removed, location.errors());
+            let wildcards = adapter.aligner.wildcards(seq, &location);
+            let cigar = if self.traceback { Some(adapter.aligner.traceback(seq, &location).cigar()) } else { None };
+            trimming.add_adapter_match(AdapterMatch {
+                adapter: adapter.name.clone(),
+                kind: adapter.kind,
+                location,
+                offset,
+                length: len,
+                wildcards,
+                cigar,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn adapter(name: &str, sequence: &str, kind: AdapterKind) -> Adapter {
+        AdapterConf::with_defaults(name, sequence, kind).adapter().unwrap()
+    }
+
+    fn trimmed(adapters: &mut Adapters, seq: &[u8]) -> (Vec<u8>, Vec<String>) {
+        let mut trimming = Trimming::new(b"read", seq, None);
+        adapters.trim(&mut trimming);
+        (trimming.seq_trimmed().to_vec(),
+         trimming.adapter_matches().iter().map(|m| m.adapter().to_string()).collect())
+    }
+
+    #[test]
+    fn three_prime() {
+        let mut adapters = Adapters::new(vec![adapter("a", "AGATCGGAAG", AdapterKind::ThreePrime)]).unwrap();
+        assert_eq!(trimmed(&mut adapters, b"CCCCCCAGATCGGAAGTTTT"), (b"CCCCCC".to_vec(), vec!["a".to_string()]));
+        assert_eq!(trimmed(&mut adapters, b"CCCCCCCCCCAGAT"), (b"CCCCCCCCCC".to_vec(), vec!["a".to_string()]));
+        assert_eq!(trimmed(&mut adapters, b"CCCCCCCCCCCCAG"), (b"CCCCCCCCCCCCAG".to_vec(), vec![]));
+    }
+
+    #[test]
+    fn five_prime() {
+        let mut adapters = Adapters::new(vec![adapter("a", "AGATCGGAAG", AdapterKind::FivePrime)]).unwrap();
+        assert_eq!(trimmed(&mut adapters, b"TTAGATCGGAAGCCCC"), (b"CCCC".to_vec(), vec!["a".to_string()]));
+        assert_eq!(trimmed(&mut adapters, b"GGAAGCCCCCCCC"), (b"CCCCCCCC".to_vec(), vec!["a".to_string()]));
+    }
+
+    #[test]
+    fn anchored() {
+        for indels in [true, false] {
+            // Without indels, anchored adapters use a comparer.
+            let anchored = |kind| {
+                let adapter = AdapterConf { indels, ..AdapterConf::with_defaults("a", "AGATCGGAAG", kind) }.adapter().unwrap();
+                assert_eq!(adapter.comparer.is_some(), !indels);
+                adapter
+            };
+            let mut adapters = Adapters::new(vec![anchored(AdapterKind::AnchoredFivePrime)]).unwrap();
+            assert_eq!(trimmed(&mut adapters, b"AGATCGGAAGCCCC"), (b"CCCC".to_vec(), vec!["a".to_string()]));
+            assert_eq!(trimmed(&mut adapters, b"AGATCGCAAGCCCC"), (b"CCCC".to_vec(), vec!["a".to_string()]));
+            assert_eq!(trimmed(&mut adapters, b"TTAGATCGGAAGCCCC").1.len(), 0);
+
+            let mut adapters = Adapters::new(vec![anchored(AdapterKind::AnchoredThreePrime)]).unwrap();
+            assert_eq!(trimmed(&mut adapters, b"CCCCAGATCGGAAG"), (b"CCCC".to_vec(), vec!["a".to_string()]));
+            assert_eq!(trimmed(&mut adapters, b"CCCCAGATCGGAAGTT").1.len(), 0);
+        }
+    }
+
+    #[test]
+    fn wildcards_and_rest() {
+        let mut adapters = Adapters::new(vec![adapter("a", "ACNNGTAC", AdapterKind::ThreePrime)]).unwrap();
+        let mut trimming = Trimming::new(b"read", b"GGGACTAGTACCAT", None);
+        trimming.trim_from_start(1);
+        adapters.trim(&mut trimming);
+        let adapter_match = &trimming.adapter_matches()[0];
+        assert_eq!(adapter_match.wildcards(), b"TA");
+        assert_eq!(adapter_match.rest(trimming.seq_raw()), b"CAT");
+
+        let mut adapters = Adapters::new(vec![adapter("a", "ACNNGTAC", AdapterKind::FivePrime)]).unwrap();
+        let mut trimming = Trimming::new(b"read", b"GGGACTAGTACCAT", None);
+        adapters.trim(&mut trimming);
+        let adapter_match = &trimming.adapter_matches()[0];
+        assert_eq!(adapter_match.wildcards(), b"TA");
+        assert_eq!(adapter_match.rest(trimming.seq_raw()), b"GGG");
+
+        // Wildcards follow the alignment of a match with a deletion.
+        let mut adapters = Adapters::new(vec![adapter("a", "AGATCNNGAAGAGCAC", AdapterKind::ThreePrime)]).unwrap();
+        let mut trimming = Trimming::new(b"read", b"CCCCAGTCTAGAAGAGCAC", None);
+        adapters.trim(&mut trimming);
+        let adapter_match = &trimming.adapter_matches()[0];
+        assert_eq!(adapter_match.location().errors(), 1);
+        assert_eq!(adapter_match.wildcards(), b"TA");
+    }
+
+    #[test]
+    fn quality_weighted() {
+        let mut conf = AdapterConf::with_defaults("a", "AGATCGGAAGAGC", AdapterKind::ThreePrime);
+        let seq = b"CCCCCCAGTTCGCAAGAGC";
+        let qual = b"IIIIIIII#III#IIIIII";
+        let mut trimming = Trimming::new(b"read", seq, Some(qual));
+        Adapters::new(vec![conf.adapter().unwrap()]).unwrap().trim(&mut trimming);
+        assert_eq!(trimming.len_trimmed(), seq.len());
+
+        conf.quality_weighted = true;
+        let mut trimming = Trimming::new(b"read", seq, Some(qual));
+        Adapters::new(vec![conf.adapter().unwrap()]).unwrap().trim(&mut trimming);
+        assert_eq!(trimming.len_trimmed(), 6);
+        let location = trimming.adapter_matches()[0].location();
+        assert_eq!((location.errors(), location.cost()), (1, 74));
+
+        // The same qualities with Phred+64 encoding
+        conf.quality_base = 64;
+        let qual = b"hhhhhhhhBhhhBhhhhhh";
+        let mut trimming = Trimming::new(b"read", seq, Some(qual));
+        Adapters::new(vec![conf.adapter().unwrap()]).unwrap().trim(&mut trimming);
+        assert_eq!(trimming.len_trimmed(), 6);
+        let location = trimming.adapter_matches()[0].location();
+        assert_eq!((location.errors(), location.cost()), (1, 74));
+    }
+
+    #[test]
+    fn random_matches() {
+        let mut adapters = Adapters::new(vec![adapter("a", "ACGT", AdapterKind::FivePrime)]).unwrap();
+        trimmed(&mut adapters, b"ACGTCCCC");
+        let stats = &adapters.stats()[0];
+        assert_eq!(stats.random_match_probabilities(0.5), vec![1.0, 0.25, 0.0625, 0.015625, 0.00390625]);
+        assert_eq!(stats.random_match_probabilities(0.0)[1], 0.5);
+        assert_eq!(stats.max_errors(3), 0);
+        assert_eq!(stats.max_errors(40), 0);
+
+        // `N` wildcards do not count towards the error rate.
+        let mut adapters = Adapters::new(vec![adapter("a", "ACGTNNNNNNNNACGTACGT", AdapterKind::ThreePrime)]).unwrap();
+        trimmed(&mut adapters, b"CCCC");
+        let stats = &adapters.stats()[0];
+        assert_eq!((stats.max_errors(10), stats.max_errors(12), stats.max_errors(40)), (1, 1, 1));
+        assert_eq!(stats.adjacent_bases, [0; 5]);
+    }
+
+    #[test]
+    fn best_adapter() {
+        let mut adapters = Adapters::new(vec![adapter("a", "AGATCGGAAG", AdapterKind::ThreePrime),
+                                              adapter("b", "TTTTTTTTTT", AdapterKind::ThreePrime)]).unwrap();
+        assert_eq!(trimmed(&mut adapters, b"CCCCTTTTTTTTTTGG"), (b"CCCC".to_vec(), vec!["b".to_string()]));
+        assert_eq!(adapters.stats()[1].trimmed, ReadCount { reads: 1, bases: 12 });
+        assert_eq!(adapters.stats()[1].adjacent_bases, [0, 1, 0, 0, 0]);
+        assert_eq!(adapters.stats()[1].removed.count(12), 1);
+        assert_eq!(adapters.stats()[0].trimmed, ReadCount::default());
+        assert!(Adapters::new(vec![adapter("a", "ACGT", AdapterKind::ThreePrime),
+                                   adapter("a", "TTTT", AdapterKind::ThreePrime)]).is_err());
+    }
+
+    #[test]
+    fn concatemers() {
+        let conf = AdapterConf {
+            count_concatemers: true,
+            ..AdapterConf::with_defaults("a", "AGATCGGAAG", AdapterKind::ThreePrime)
+        };
+        let mut adapters = Adapters::new(vec![conf.adapter().unwrap()]).unwrap();
+        trimmed(&mut adapters, b"CCCCAGATCGGAAGTTTTAGATCGGAAGTT");
+        trimmed(&mut adapters, b"CCCCAGATCGGAAGTTTT");
+        trimmed(&mut adapters, b"CCCCCCCC");
+        let mut merged = adapters.clone();
+        merged.merge(&adapters);
+        assert_eq!(adapters.stats()[0].concatemers, Some(1));
+        assert_eq!(merged.stats()[0].concatemers, Some(2));
+
+        let mut adapters = Adapters::new(vec![adapter("a", "AGATCGGAAG", AdapterKind::ThreePrime)]).unwrap();
+        trimmed(&mut adapters, b"CCCCAGATCGGAAGTTTTAGATCGGAAGTT");
+        assert_eq!(adapters.stats()[0].concatemers, None);
+    }
+
+    #[test]
+    fn mixed_encodings() {
+        // Read `N` bases match only for the second adapter, which must
+        // not be given the read as encoded for the first one.
+        let mut conf = AdapterConf::with_defaults("b", "TTAGGCCAA", AdapterKind::ThreePrime);
+        let (reference_ends, query_ends) = conf.kind.ends();
+        conf.aligner = Some(AlignerConf {
+            max_error_rate: 0.0,
+            reference_ends,
+            query_ends,
+            matching: AlignMatching::QueryWildcard,
+            query_n: None,
+            mismatch_cost: 1,
+            insertion_cost: 1,
+            deletion_cost: 1,
+            gap_open_cost: 0,
+            mismatch_only: false,
+            min_overlap: 3,
+        });
+        let mut adapters = Adapters::new(vec![adapter("a", "ACACACACAC", AdapterKind::ThreePrime),
+                                              conf.adapter().unwrap()]).unwrap();
+        assert_eq!(adapters.shared_encoding, [true, false]);
+        assert_eq!(trimmed(&mut adapters, b"GGGGGTTNGGCCAA"), (b"GGGGG".to_vec(), vec!["b".to_string()]));
+    }
+
+    #[test]
+    fn aligner_conf() {
+        let mut conf: AdapterConf = toml::from_str(concat!(
+            "name = \"a\"\n",
+            "sequence = \"AGATCGGAAGAGC\"\n",
+            "kind = \"three_prime\"\n",
+            "[aligner]\n",
+            "max_error_rate = 0.2\n",
+            "reference_ends = \"local_stop\"\n",
+            "query_ends = \"local\"\n",
+            "matching = \"ref_wildcard\"\n",
+            "gap_open_cost = 1\n",
+        )).unwrap();
+        let aligner = conf.aligner.clone().unwrap();
+        assert_eq!((aligner.reference_ends, aligner.matching), (AlignEnds::LocalStop, AlignMatching::RefWildcard));
+        assert_eq!((aligner.mismatch_cost, aligner.gap_open_cost, aligner.min_overlap), (1, 1, 1));
+        let adapters = Adapters::new(vec![conf.adapter().unwrap()]).unwrap();
+        assert_eq!(adapters.stats()[0].max_error_rate, 0.2);
+        assert_eq!(adapters.stats()[0].allowed_errors, [0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 2, 2, 2, 2]);
+
+        conf.aligner.as_mut().unwrap().min_overlap = 14;
+        assert!(conf.adapter().is_err());
+        conf.aligner.as_mut().unwrap().min_overlap = 3;
+        conf.aligner.as_mut().unwrap().max_error_rate = 1.0;
+        assert!(conf.adapter().is_err());
+        conf.aligner.as_mut().unwrap().max_error_rate = 0.2;
+        conf.aligner.as_mut().unwrap().mismatch_cost = 3;
+        assert!(conf.adapter().is_err());
+        conf.aligner = None;
+        conf.max_error_rate = -0.1;
+        assert!(conf.adapter().is_err());
+    }
+}
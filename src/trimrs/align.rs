@@ -1,6 +1,7 @@
 use std::default::Default;
 
 use anyhow::{bail, ensure, Result};
+use serde::{Serialize, Deserialize};
 
 use crate::encode::*;
 
@@ -93,7 +94,8 @@ impl std::fmt::Display for DPMatrix {
     }
 }
 
-#[derive(PartialEq,Eq,PartialOrd,Ord,Clone,Copy,Hash,Debug)]
+#[derive(PartialEq,Eq,PartialOrd,Ord,Clone,Copy,Hash,Debug,Serialize,Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum AlignEnds {
     Global,
     LocalStart,
@@ -111,26 +113,49 @@ impl AlignEnds {
     }
 }
 
-#[derive(PartialEq,Eq,PartialOrd,Ord,Clone,Copy,Hash,Debug)]
+#[derive(PartialEq,Eq,PartialOrd,Ord,Clone,Copy,Hash,Debug,Serialize,Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum AlignMatching {
     NoWildcard,
     RefWildcard,
-    QueryWildcard
+    QueryWildcard,
+    BothWildcard,
 }
 
 impl AlignMatching {
     pub fn ref_wildcard(self) -> bool {
-        self == AlignMatching::RefWildcard
+        self == AlignMatching::RefWildcard || self == AlignMatching::BothWildcard
     }
 
     pub fn query_wildcard(self) -> bool {
-        self == AlignMatching::QueryWildcard
+        self == AlignMatching::QueryWildcard || self == AlignMatching::BothWildcard
     }
 }
 
+/// How `N` bases in the query count in an alignment
+#[derive(PartialEq,Eq,PartialOrd,Ord,Clone,Copy,Hash,Debug,Serialize,Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryN {
+    /// `N` mismatches every base
+    Mismatch,
+    /// `N` matches every base
+    Match,
+    /// `N` costs nothing and is not a match, and it is left out of
+    /// the length of the alignment for the error rate
+    Ignore,
+}
+
+/// Encoding of a query `N` that matches every base
+const MATCHING_N: u8 = 0x0f;
+
+/// Encoding of an ignored query `N`, which matches no reference base
+const IGNORED_N: u8 = 0x10;
+
 /// Parameters of an [`Aligner`](struct.Aligner.html) match. The
 /// alignment itself is not computed, only the starting and stopping
-/// positions, along with the number of matches and errors. Alignment
+/// positions, along with the number of matches and errors; use
+/// [`Aligner::traceback()`](struct.Aligner.html#method.traceback)
+/// for the alignment. Alignment
 /// positions are reported in Rust coordinate conventions, with
 /// _start_ as the first position in the alignment and _stop_ not
 /// included, i.e., _start_ to (_stop_-1) inclusive.
@@ -142,8 +167,13 @@ pub struct Location {
     querystop: usize,
     matches: usize,
     errors: usize,
+    cost: usize,
 }
 
+/// Units of cost in one error, for
+/// [`Location::cost()`](struct.Location.html#method.cost)
+pub const COST_UNIT: usize = 100;
+
 impl Location {
     /// Starting position on reference sequence
     pub fn refstart(&self) -> usize {
@@ -172,16 +202,117 @@ impl Location {
         self.matches
     }
 
-    /// Number of errors (cost) of the alignment
+    /// Number of errors (cost) of the alignment, rounded up to a
+    /// whole number with quality-weighted mismatches
     pub fn errors(&self) -> usize {
         self.errors
     }
+
+    /// Cost of the alignment in units of `1 / COST_UNIT` of an error,
+    /// which is exact with quality-weighted mismatches
+    pub fn cost(&self) -> usize {
+        self.cost
+    }
+}
+
+/// One column of an alignment.
+#[derive(PartialEq,Eq,PartialOrd,Ord,Clone,Copy,Hash,Debug)]
+pub enum AlignOp {
+    Match,
+    Mismatch,
+    /// Query base missing from the reference
+    Insertion,
+    /// Reference base missing from the query
+    Deletion,
+}
+
+impl AlignOp {
+    /// Extended CIGAR operation, distinguishing matches (`=`) from
+    /// mismatches (`X`)
+    pub fn cigar_op(self) -> char {
+        match self {
+            AlignOp::Match => '=',
+            AlignOp::Mismatch => 'X',
+            AlignOp::Insertion => 'I',
+            AlignOp::Deletion => 'D',
+        }
+    }
+}
+
+/// Alignment of the matching parts of a reference and a query, from
+/// [`Aligner::traceback()`](struct.Aligner.html#method.traceback).
+///
+/// Displaying an alignment gives three lines: the reference, bars
+/// marking matches, and the query, with `-` for gaps.
+#[derive(PartialEq,Eq,Clone,Debug)]
+pub struct Alignment {
+    location: Location,
+    ops: Vec<AlignOp>,
+    reference: Vec<u8>,
+    query: Vec<u8>,
+}
+
+impl Alignment {
+    pub fn location(&self) -> &Location {
+        &self.location
+    }
+
+    /// Operations of the alignment, from its start
+    pub fn ops(&self) -> &[AlignOp] {
+        &self.ops
+    }
+
+    /// Extended CIGAR string of the alignment, with the query
+    /// aligned to the reference, e.g., `5=1X2=1I3=`
+    pub fn cigar(&self) -> String {
+        let mut cigar = String::new();
+        let mut ops = self.ops.iter().peekable();
+        while let Some(&op) = ops.next() {
+            let mut len = 1;
+            while ops.next_if_eq(&&op).is_some() {
+                len += 1;
+            }
+            cigar.push_str(&len.to_string());
+            cigar.push(op.cigar_op());
+        }
+        cigar
+    }
+}
+
+impl std::fmt::Display for Alignment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut reference = String::with_capacity(self.ops.len());
+        let mut bars = String::with_capacity(self.ops.len());
+        let mut query = String::with_capacity(self.ops.len());
+        let mut refs = self.reference.iter().map(|&c| c as char);
+        let mut queries = self.query.iter().map(|&c| c as char);
+        for op in self.ops.iter() {
+            let (r, q) = match op {
+                AlignOp::Match | AlignOp::Mismatch => (refs.next(), queries.next()),
+                AlignOp::Insertion => (None, queries.next()),
+                AlignOp::Deletion => (refs.next(), None),
+            };
+            reference.push(r.unwrap_or('-'));
+            bars.push(if *op == AlignOp::Match { '|' } else { ' ' });
+            query.push(q.unwrap_or('-'));
+        }
+        write!(f, "{}\n{}\n{}", reference, bars, query)
+    }
 }
 
+fn default_matching() -> AlignMatching { AlignMatching::NoWildcard }
+
+fn default_cost() -> usize { 1 }
+
+fn default_min_overlap() -> usize { 1 }
+
 /// Configuration structure for `Aligner`.
 ///
-/// Alignment parameters are named fields in the structure.
-#[derive(Clone,Debug,PartialEq,PartialOrd)]
+/// Alignment parameters are named fields in the structure. When
+/// deserialized, only the error rate and the alignment ends are
+/// required, and the other fields default to exact matching of bases
+/// without wildcards, unit costs, and a minimum overlap of one base.
+#[derive(Clone,Debug,PartialEq,PartialOrd,Serialize,Deserialize)]
 pub struct AlignerConf {
     /// Maximum error rate
     pub max_error_rate: f64,
@@ -192,23 +323,90 @@ pub struct AlignerConf {
     /// Start and/or end gaps in the query sequence
     pub query_ends: AlignEnds,
 
-    /// Use IUPAC wildcards in reference or query
+    /// Use IUPAC wildcards in reference, query, or both
+    #[serde(default = "default_matching")]
     pub matching: AlignMatching,
 
-    /// Cost of insertion or deletion
-    pub indel_cost: usize,
+    /// How `N` bases in the query count, by default matching
+    /// everything when the query has wildcards and nothing otherwise
+    #[serde(default)]
+    pub query_n: Option<QueryN>,
+
+    /// Cost of a mismatch
+    #[serde(default = "default_cost")]
+    pub mismatch_cost: usize,
+
+    /// Cost of each query base missing from the reference
+    #[serde(default = "default_cost")]
+    pub insertion_cost: usize,
+
+    /// Cost of each reference base missing from the query
+    #[serde(default = "default_cost")]
+    pub deletion_cost: usize,
+
+    /// Additional cost of opening each gap, so that a gap of `l`
+    /// bases costs `gap_open_cost + l * insertion_cost` or
+    /// `gap_open_cost + l * deletion_cost`. With zero, gap costs are
+    /// linear.
+    #[serde(default)]
+    pub gap_open_cost: usize,
+
+    /// Align without indels, counting only mismatches, in which case
+    /// the gap costs are not used
+    #[serde(default)]
+    pub mismatch_only: bool,
 
     /// Minimum overlap to report a match
+    #[serde(default = "default_min_overlap")]
     pub min_overlap: usize,
 }
+
+impl AlignerConf {
+    /// Checks the costs, the minimum overlap, and that the maximum
+    /// error rate is below the mismatch cost, so that not every
+    /// overlap is a match. Unless in mismatch-only mode, a mismatch
+    /// must cost no more than an insertion and a deletion, as the
+    /// first column of the DP matrix counts mismatches where the
+    /// sequences overlap.
+    pub fn validate(&self) -> Result<()> {
+        ensure!(self.mismatch_cost >= 1, "Mismatch cost must be at least 1");
+        ensure!(self.insertion_cost >= 1, "Insertion cost must be at least 1");
+        ensure!(self.deletion_cost >= 1, "Deletion cost must be at least 1");
+        ensure!(self.mismatch_only || self.mismatch_cost <= self.insertion_cost + self.deletion_cost,
+                "Mismatch cost {} must be at most the insertion cost {} plus the deletion cost {}",
+                self.mismatch_cost, self.insertion_cost, self.deletion_cost);
+        ensure!(self.min_overlap >= 1, "Min overlap must be at least 1");
+        ensure!(self.max_error_rate >= 0.0 && self.max_error_rate < self.mismatch_cost as f64,
+                "Maximum error rate {} must be at least 0 and below the mismatch cost {}",
+                self.max_error_rate, self.mismatch_cost);
+        Ok(())
+    }
+
+    /// The `N` policy, resolving the default from the matching
+    fn resolved_query_n(&self) -> QueryN {
+        self.query_n.unwrap_or(if self.matching.query_wildcard() { QueryN::Match } else { QueryN::Mismatch })
+    }
+
+    /// Checks the configuration as [`validate()`](#method.validate)
+    /// does, and also that the minimum overlap is no longer than
+    /// `reference`.
+    pub fn validate_reference(&self, reference: &[u8]) -> Result<()> {
+        self.validate()?;
+        ensure!(self.min_overlap <= reference.len(),
+                "Min overlap {} is longer than the reference of {} bases", self.min_overlap, reference.len());
+        Ok(())
+    }
+}
     
 
 ///    Find a full or partial occurrence of a query string in a reference string
 ///    allowing errors (mismatches, insertions, deletions).
 ///
 ///
-///    Mismatches are counted as one error, and insertions and
-///    deletions are counted as one or mor errors.
+///    Mismatches, insertions, and deletions each count as one or
+///    more errors, as given by their costs, and gaps may have an
+///    additional opening cost for affine gap penalties. The number of
+///    errors of an alignment is its total cost.
 ///
 ///    Semi-global alignments allow skipping a suffix and/or prefix of query or
 ///    reference at no cost. Combining semi-global alignment with edit distance is
@@ -267,32 +465,112 @@ pub struct AlignerConf {
 ///    [`Location`](struct.Location.html).
 ///    It is always the case that at least one of `refstart` and `querystart` is zero.
 ///
-///    IUPAC wildcard characters can be allowed in the reference or
-///    the query by setting the appropriate flags. All non-IUPAC
+///    IUPAC wildcard characters can be allowed in the reference, the
+///    query, or both by setting the appropriate flags. All non-IUPAC
 ///    characters compare as non-equal, and when IUPAC wildcards are
 ///    not enabled, anything except `A`, `C`, `G`, `T`, and `U`
-///    compares as non-equal to everything.
+///    compares as non-equal to everything. `N` bases in the query
+///    can instead be set to match everything, nothing, or to be
+///    ignored, which also leaves them out of the error rate.
+///
+///    With unit costs for mismatches and gaps, no gap opening cost,
+///    and a reference of at most 64 bases, the
+///    query is first screened with the bit-parallel algorithm of
+///    Myers, which computes the same edit costs as the DP matrix a
+///    whole column at a time. When no cell that could end an
+///    alignment has a cost within the error rate for its row, there
+///    is no match; otherwise the DP matrix is computed as usual, so
+///    the results are always those of
+///    [`locate_dp()`](#method.locate_dp).
+///
+///    In mismatch-only mode, no DP matrix is computed. Each offset of
+///    the query against the reference that the alignment ends allow
+///    is tried in turn, counting the mismatches over the overlap of
+///    the two sequences, which extends to the end of one of them.
+///    Offsets are tried in the order that the DP matrix would find
+///    their alignments, and the effective length of each is computed
+///    as for the same cell of the matrix, so ties are broken the same
+///    way and the same wildcards are counted.
 #[derive(Clone, Debug)]
 pub struct Aligner {
     column: Vec<Entry>,
     max_error_rate: f64,
     reference_ends: AlignEnds,
     query_ends: AlignEnds,
+    mismatch_cost: usize,
     insertion_cost: usize,
     deletion_cost: usize,
+    gap_open_cost: usize,
     min_overlap: usize,
     matching: AlignMatching,
+    query_n: QueryN,
+    mismatch_only: bool,
     debug: bool,
     dpmatrix: Option<DPMatrix>,
     reference: Vec<u8>,
     breference: Vec<u8>,
     effective_length: usize,
     n_counts: Vec<usize>,
+    /// Buffer for the encoded query
     bquery: Vec<u8>,
+    /// Bit-parallel match masks of the reference for each encoded
+    /// query base, when the bit-parallel screen can be used
+    peq: Option<[u64; 16]>,
+    mismatch_costs: Vec<usize>,
+    /// Number of ignored `N` bases in each prefix of the query
+    query_n_counts: Vec<usize>,
+    /// Costs of the gaps ending in each row of the current column of
+    /// the DP matrix, with affine gap costs
+    insertion_column: Vec<Entry>,
+}
+
+/// Cost of a mismatch against a query base of quality `qual`, encoded
+/// as `quality_base` plus the Phred score, in units of `1 / COST_UNIT` of an error:
+/// the probability that the base was not a sequencing error, so that
+/// a mismatch against a base likely to be wrong costs little. Every
+/// mismatch costs at least one unit.
+pub fn qual_mismatch_cost(qual: u8, quality_base: u8) -> usize {
+    let phred = qual.saturating_sub(quality_base) as f64;
+    let cost = (COST_UNIT as f64 * (1.0 - 10f64.powf(-phred / 10.0))).round() as usize;
+    cost.max(1)
+}
+
+/// Encodes `query` into `bquery` for `matching`, with `N` bases
+/// encoded as `query_n` requires.
+fn encode_query_as(matching: AlignMatching, query_n: QueryN, query: &[u8], bquery: &mut Vec<u8>) {
+    let n_code = match (query_n, matching.query_wildcard()) {
+        (QueryN::Mismatch, false) | (QueryN::Match, true) => None,
+        (QueryN::Mismatch, true) => Some(0),
+        (QueryN::Match, false) => Some(MATCHING_N),
+        (QueryN::Ignore, _) => Some(IGNORED_N),
+    };
+    if matching.query_wildcard() {
+        encode_iupac_vec(query, bquery);
+    } else {
+        encode_acgt_vec(query, bquery);
+    }
+    if let Some(n_code) = n_code {
+        for (code, &nt) in bquery.iter_mut().zip(query.iter()) {
+            if nt == b'N' || nt == b'n' {
+                *code = n_code;
+            }
+        }
+    }
 }
 
 const INIT_QUERY_LEN: usize = 256;
 
+/// Cost of a gap state in the DP matrix that no alignment reaches
+const NO_GAP: usize = usize::MAX / 4;
+
+/// Maximum reference length for the bit-parallel screen
+const MAX_BIT_PARALLEL: usize = 64;
+
+/// Mask of the lowest `n` bits
+fn low_bits(n: usize) -> u64 {
+    if n >= 64 { u64::MAX } else { (1 << n) - 1 }
+}
+
 impl Aligner {
     /// Creates a new aligner with a specified alignment configuration
     /// and reference sequence.
@@ -300,67 +578,144 @@ impl Aligner {
         conf: &AlignerConf,
         reference: &[u8],
     ) -> Result<Self> {
-        let m = reference.len();
-        let mut n_counts = vec![0; m + 1];
-        let mut effective_length = m;
-        let mut breference = reference.to_vec();
+        conf.validate()?;
 
-        let mut n_count = 0;
-        for i in 0..m {
-            n_counts[i] = n_count;
-            if reference[i] == b'n' || reference[i] == b'N' {
-                n_count += 1;
-            }
-        }
-        *n_counts.last_mut().unwrap() = n_count;
-        assert_eq!(
-            n_counts[m],
-            reference
-                .iter()
-                .copied()
-                .filter(|&c| c == b'N' || c == b'n')
-                .count()
-        );
-        match conf.matching {
-            AlignMatching::RefWildcard => {
-                effective_length = m - n_counts[m];
-                if effective_length == 0 {
-                    bail!("Cannot have only N wildcards in the sequence");
-                }
-                encode_iupac_vec(reference, &mut breference);
-            },
-            AlignMatching::NoWildcard |
-            AlignMatching::QueryWildcard => {
-                encode_acgt_vec(reference, &mut breference);
-            },
-        };
-
-        ensure!(conf.indel_cost >= 1, "Indel cost must be at least 1");
-        ensure!(conf.min_overlap >= 1, "Min overlap must be at least 1");
-
-        Ok(Aligner {
-            column: vec![Entry::default(); m + 1],
+        let mut aligner = Aligner {
+            column: Vec::new(),
             max_error_rate: conf.max_error_rate,
             reference_ends: conf.reference_ends,
             query_ends: conf.query_ends,
             matching: conf.matching,
+            query_n: conf.resolved_query_n(),
+            mismatch_only: conf.mismatch_only,
             min_overlap: conf.min_overlap,
             debug: false,
             dpmatrix: None,
-            reference: reference.to_vec(),
-            breference: breference,
-            effective_length: effective_length,
-            n_counts: n_counts,
-            insertion_cost: conf.indel_cost,
-            deletion_cost: conf.indel_cost,
+            reference: Vec::new(),
+            breference: Vec::new(),
+            effective_length: 0,
+            n_counts: Vec::new(),
+            mismatch_cost: conf.mismatch_cost,
+            insertion_cost: conf.insertion_cost,
+            deletion_cost: conf.deletion_cost,
+            gap_open_cost: conf.gap_open_cost,
             bquery: Vec::with_capacity(INIT_QUERY_LEN),
-        })
+            peq: None,
+            mismatch_costs: Vec::with_capacity(INIT_QUERY_LEN),
+            query_n_counts: Vec::with_capacity(INIT_QUERY_LEN),
+            insertion_column: Vec::new(),
+        };
+        aligner.set_reference(reference)?;
+        Ok(aligner)
+    }
+
+    /// Replaces the reference sequence, keeping the configuration.
+    /// Buffers are reused, so that no memory is allocated unless the
+    /// reference is longer than any before it. On error, the
+    /// reference is unchanged.
+    pub fn set_reference(&mut self, reference: &[u8]) -> Result<()> {
+        let m = reference.len();
+        let n_count = reference.iter().filter(|&&c| c == b'N' || c == b'n').count();
+        if self.matching.ref_wildcard() && n_count == m {
+            bail!("Cannot have only N wildcards in the sequence");
+        }
+
+        self.reference.clear();
+        self.reference.extend_from_slice(reference);
+        self.n_counts.clear();
+        let mut n_count = 0;
+        for &nt in reference.iter() {
+            self.n_counts.push(n_count);
+            if nt == b'n' || nt == b'N' {
+                n_count += 1;
+            }
+        }
+        self.n_counts.push(n_count);
+        if self.matching.ref_wildcard() {
+            self.effective_length = m - n_count;
+            encode_iupac_vec(reference, &mut self.breference);
+        } else {
+            self.effective_length = m;
+            encode_acgt_vec(reference, &mut self.breference);
+        }
+        self.column.resize(m + 1, Entry::default());
+        self.insertion_column.resize(m + 1, Entry::default());
+
+        let unit_costs = self.mismatch_cost == 1
+            && self.insertion_cost == 1
+            && self.deletion_cost == 1
+            && self.gap_open_cost == 0;
+        self.peq = if (1..=MAX_BIT_PARALLEL).contains(&m) && unit_costs && !self.mismatch_only {
+            let mut peq = [0u64; 16];
+            for (code, eq) in peq.iter_mut().enumerate() {
+                for (i, &nt) in self.breference.iter().enumerate() {
+                    if nt & (code as u8) != 0 {
+                        *eq |= 1 << i;
+                    }
+                }
+            }
+            Some(peq)
+        } else {
+            None
+        };
+        Ok(())
     }
 
     fn m(&self) -> usize {
         self.reference.len()
     }
 
+    /// Length of the reference, not counting `N` wildcards when the
+    /// reference has wildcards
+    pub fn effective_length(&self) -> usize {
+        self.effective_length
+    }
+
+    /// Bases of `query` aligned to `N` wildcards of the reference in
+    /// a match at `location`, in reference order. This is empty
+    /// unless the reference has wildcards. Reference and query
+    /// positions are paired by the alignment from
+    /// [`traceback()`](#method.traceback), so that an `N` deleted
+    /// from the query has no base, or directly in mismatch-only mode.
+    pub fn wildcards(&self, query: &[u8], location: &Location) -> Vec<u8> {
+        if !self.matching.ref_wildcard() {
+            return Vec::new();
+        }
+        let is_n = |nt: u8| nt == b'N' || nt == b'n';
+        let reference = &self.reference[location.refstart..location.refstop];
+        let aligned = &query[location.querystart..location.querystop];
+        if self.mismatch_only || !reference.iter().any(|&nt| is_n(nt)) {
+            return reference.iter().zip(aligned.iter())
+                .filter(|(&r, _)| is_n(r))
+                .map(|(_, &q)| q)
+                .collect();
+        }
+
+        let mut wildcards = Vec::new();
+        let (mut i, mut j) = (0, 0);
+        for op in self.traceback(query, location).ops() {
+            match op {
+                AlignOp::Match | AlignOp::Mismatch => {
+                    if is_n(reference[i]) {
+                        wildcards.push(aligned[j]);
+                    }
+                    i += 1;
+                    j += 1;
+                },
+                AlignOp::Insertion => j += 1,
+                AlignOp::Deletion => i += 1,
+            }
+        }
+        wildcards
+    }
+
+    /// Maximum number of errors, i.e., cost, in a match of `len`
+    /// reference bases, not counting `N` wildcards when the reference
+    /// has wildcards
+    pub fn max_errors(&self, len: usize) -> usize {
+        (self.max_error_rate * len as f64).floor() as usize
+    }
+
     /// Returns the dynamic programming matrix, which is `None` unless
     /// debugging has been enabled.
     pub fn dpmatrix(&self) -> &Option<DPMatrix> {
@@ -385,33 +740,139 @@ impl Aligner {
     /// each other, with the given number of matches and the given
     /// number of errors. The alignment itself is not returned.
     pub fn locate(&mut self, query: &[u8]) -> Option<Location> {
-        let s1 = &self.breference;
-        let m = self.m();
-        let n = query.len();
-        let column = &mut self.column;
-        let max_error_rate = self.max_error_rate;
+        self.with_encoded(query, |aligner, bquery| aligner.locate_encoded(bquery))
+    }
 
-        match self.matching {
-            AlignMatching::QueryWildcard => {
-                encode_iupac_vec(query, &mut self.bquery);
-            },
-            AlignMatching::NoWildcard |
-            AlignMatching::RefWildcard => {
-                encode_acgt_vec(query, &mut self.bquery);
-            },
-        };
-        let s2 = &self.bquery;
-        //         DP Matrix:
-        //                    query (j)
-        //                  ----------> n
-        //                 |
-        //         ref (i) |
-        //                 |
-        //                 V
-        //                m
+    /// Finds the alignment as [`locate()`](#method.locate) does, for a
+    /// query encoded by [`encode_query()`](#method.encode_query), so
+    /// that a query aligned with several references is only encoded
+    /// once.
+    pub fn locate_encoded(&mut self, bquery: &[u8]) -> Option<Location> {
+        if self.mismatch_only {
+            if self.query_n == QueryN::Ignore {
+                self.fill_mismatch_costs(bquery, None);
+            }
+            return self.locate_mismatches(bquery, 1);
+        }
+        if self.peq.is_some() && !self.debug && self.query_n != QueryN::Ignore && !self.bit_parallel_candidate(bquery) {
+            return None;
+        }
+        self.locate_dp_encoded(bquery)
+    }
+
+    /// Finds the alignment as [`locate()`](#method.locate) does, with
+    /// mismatches weighted by the qualities `quals` of the query bases,
+    /// encoded as `quality_base` plus the Phred score, as given by
+    /// [`qual_mismatch_cost()`](fn.qual_mismatch_cost.html) times the
+    /// mismatch cost. Gaps cost their full costs, and the
+    /// maximum error rate limits the cost of the alignment in the same
+    /// units, so that more mismatches are allowed in low-quality
+    /// sequence.
+    ///
+    /// The DP matrix is always computed, without the bit-parallel
+    /// screen.
+    pub fn locate_weighted(&mut self, query: &[u8], quals: &[u8], quality_base: u8) -> Option<Location> {
+        self.with_encoded(query, |aligner, bquery| aligner.locate_weighted_encoded(bquery, quals, quality_base))
+    }
+
+    /// Finds the alignment as
+    /// [`locate_weighted()`](#method.locate_weighted) does, for a query
+    /// encoded by [`encode_query()`](#method.encode_query).
+    pub fn locate_weighted_encoded(&mut self, bquery: &[u8], quals: &[u8], quality_base: u8) -> Option<Location> {
+        assert_eq!(bquery.len(), quals.len(), "Query and qualities differ in length");
+        self.fill_mismatch_costs(bquery, Some((quals, quality_base)));
+        if self.mismatch_only {
+            self.locate_mismatches(bquery, COST_UNIT)
+        } else {
+            self.locate_costs(bquery, COST_UNIT)
+        }
+    }
+
+    /// Iterates over all non-overlapping matches in `query`, from
+    /// left to right. The best match is found as by
+    /// [`locate()`](#method.locate), and then the parts of the query
+    /// before and after it are searched in turn in the same way.
+    ///
+    /// The alignment ends apply at the ends of each part, so that
+    /// when the reference ends allow partial matches, a match may be
+    /// cut short next to another one as well as at the ends of the
+    /// query.
+    pub fn locate_all<'a>(&'a mut self, query: &[u8]) -> Locations<'a> {
+        let mut bquery = Vec::with_capacity(query.len());
+        self.encode_query(query, &mut bquery);
+        Locations { aligner: self, pending: vec![Pending::Range(0, query.len())], bquery }
+    }
+
+    /// Sets the cost of a mismatch against each base of the encoded
+    /// query, weighted by the qualities `quals` with their quality
+    /// base if given, and counts the ignored `N` bases, which cost
+    /// nothing.
+    fn fill_mismatch_costs(&mut self, bquery: &[u8], quals: Option<(&[u8], u8)>) {
+        let mismatch_cost = self.mismatch_cost;
+        self.mismatch_costs.clear();
+        match quals {
+            Some((quals, quality_base)) => {
+                self.mismatch_costs.extend(quals.iter().map(|&q| qual_mismatch_cost(q, quality_base) * mismatch_cost))
+            }
+            None => self.mismatch_costs.resize(bquery.len(), mismatch_cost),
+        }
+        self.query_n_counts.clear();
+        if self.query_n == QueryN::Ignore {
+            let mut n_count = 0;
+            self.query_n_counts.push(0);
+            for (cost, &nt) in self.mismatch_costs.iter_mut().zip(bquery.iter()) {
+                if nt == IGNORED_N {
+                    *cost = 0;
+                    n_count += 1;
+                }
+                self.query_n_counts.push(n_count);
+            }
+        }
+    }
+
+    /// Number of ignored `N` bases in `query[start..stop]`, after
+    /// [`fill_mismatch_costs()`](#method.fill_mismatch_costs)
+    fn ignored_ns(&self, start: usize, stop: usize) -> usize {
+        if self.query_n == QueryN::Ignore {
+            self.query_n_counts[stop] - self.query_n_counts[start]
+        } else {
+            0
+        }
+    }
+
+    /// Encodes `query` into `bquery` for
+    /// [`locate_encoded()`](#method.locate_encoded), with
+    /// [`encode_iupac_vec()`](../encode/fn.encode_iupac_vec.html) when
+    /// the query has wildcards and
+    /// [`encode_acgt_vec()`](../encode/fn.encode_acgt_vec.html)
+    /// otherwise, and with `N` bases encoded as the `N` policy
+    /// requires.
+    pub fn encode_query(&self, query: &[u8], bquery: &mut Vec<u8>) {
+        encode_query_as(self.matching, self.query_n, query, bquery);
+    }
+
+    /// Whether `other` encodes queries the same way, so that they can
+    /// be shared between the two aligners
+    pub fn same_query_encoding(&self, other: &Aligner) -> bool {
+        self.matching.query_wildcard() == other.matching.query_wildcard() && self.query_n == other.query_n
+    }
+
+    /// Calls `f` with `query` encoded in the query buffer of the
+    /// aligner.
+    fn with_encoded<T>(&mut self, query: &[u8], f: impl FnOnce(&mut Self, &[u8]) -> T) -> T {
+        let mut bquery = std::mem::take(&mut self.bquery);
+        self.encode_query(query, &mut bquery);
+        let result = f(self, &bquery);
+        self.bquery = bquery;
+        result
+    }
 
+    /// Range of DP matrix columns that are computed for a query of
+    /// length `n`, along with the maximum number of errors.
+    fn column_range(&self, n: usize) -> (usize, usize, usize) {
+        let m = self.m();
         // # maximum no. of errors
-        let k = (max_error_rate * m as f64).floor() as usize;
+        let k = (self.max_error_rate * m as f64).floor() as usize;
 
         // # Determine largest and smallest column we need to compute
         let max_n = if !self.query_ends.start_local() {
@@ -425,49 +886,401 @@ impl Aligner {
         } else {
             0
         };
+        (min_n, max_n, k)
+    }
+
+    /// Computes the costs of the DP matrix for the encoded query in
+    /// `bquery` with the bit-parallel algorithm of Myers, returning
+    /// whether any cell where `locate_dp` looks for the end of an
+    /// alignment has a cost within the error rate for an alignment of
+    /// the reference up to its row. The DP matrix only accepts an
+    /// alignment whose cost is no higher than this, as the aligned
+    /// part of the reference is never longer than the row.
+    fn bit_parallel_candidate(&self, bquery: &[u8]) -> bool {
+        let peq = self.peq.as_ref().expect("Bit-parallel screen enabled");
+        let m = self.m();
+        let n = bquery.len();
+        let (min_n, max_n, _) = self.column_range(n);
+        let max_error_rate = self.max_error_rate;
+        let high = 1u64 << (m - 1);
+
+        // Vertical cost differences between rows i and i + 1 are bit i
+        // of `pv` (for +1) and `mv` (for -1), starting from the first
+        // column computed by `locate_dp`.
+        let (mut row0, mut pv) = match (self.reference_ends.start_local(), self.query_ends.start_local()) {
+            (false, false) => (min_n, low_bits(m) & !low_bits(min_n)),
+            (true, false) => (min_n, 0),
+            (false, true) => (0, low_bits(m)),
+            (true, true) => (0, low_bits(m) & low_bits(min_n)),
+        };
+        let mut mv = 0u64;
+        let mut bottom = row0 + pv.count_ones() as usize;
+        let query_start_global = !self.query_ends.start_local();
+        let check_last_row = self.query_ends.stop_local() && m >= self.min_overlap;
+
+        // No columns are computed when a query aligned globally is too
+        // long for the reference.
+        for &nt in bquery.get(min_n..max_n).unwrap_or(&[]) {
+            let eq = peq[(nt & 0x0f) as usize];
+            let xv = eq | mv;
+            let xh = ((eq & pv).wrapping_add(pv) ^ pv) | eq;
+            let mut ph = mv | !(xh | pv);
+            let mut mh = pv & xh;
+            if ph & high != 0 {
+                bottom += 1;
+            } else if mh & high != 0 {
+                bottom -= 1;
+            }
+            ph <<= 1;
+            mh <<= 1;
+            // The first row costs one more in each column unless a
+            // prefix of the query is skipped.
+            if query_start_global {
+                ph |= 1;
+                row0 += 1;
+            }
+            pv = mh | !(xv | ph);
+            mv = ph & xv;
+
+            if check_last_row && (bottom as f64) <= (m as f64) * max_error_rate {
+                return true;
+            }
+        }
+
+        if max_n == n {
+            let first_i = if self.reference_ends.stop_local() { 0 } else { m };
+            let mut cost = row0;
+            for i in 1..(m + 1) {
+                let bit = 1u64 << (i - 1);
+                if pv & bit != 0 {
+                    cost += 1;
+                } else if mv & bit != 0 {
+                    cost -= 1;
+                }
+                if i >= first_i && i >= self.min_overlap && (cost as f64) <= (i as f64) * max_error_rate {
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+
+    /// Computes the alignment of `query` with the reference at
+    /// `location`, found by [`locate()`](#method.locate). The
+    /// alignment is a lowest-cost global alignment of the matching
+    /// parts of the two sequences, with as many matches as possible,
+    /// and so its cost is the number of errors in `location` unless
+    /// mismatches were weighted by quality.
+    pub fn traceback(&self, query: &[u8], location: &Location) -> Alignment {
+        let reference = &self.reference[location.refstart..location.refstop];
+        let query = &query[location.querystart..location.querystop];
+        let bref = &self.breference[location.refstart..location.refstop];
+        let mut bquery = Vec::with_capacity(query.len());
+        self.encode_query(query, &mut bquery);
+
+        let ops = if self.mismatch_only {
+            bref.iter().zip(bquery.iter())
+                .map(|(&r, &q)| if r & q != 0 { AlignOp::Match } else { AlignOp::Mismatch })
+                .collect()
+        } else {
+            self.traceback_ops(bref, &bquery)
+        };
+
+        Alignment { location: location.clone(), ops, reference: reference.to_vec(), query: query.to_vec() }
+    }
+
+    /// Global alignment of encoded sequences, minimizing cost and then
+    /// maximizing matches.
+    fn traceback_ops(&self, bref: &[u8], bquery: &[u8]) -> Vec<AlignOp> {
+        let (a, b) = (bref.len(), bquery.len());
+        let open = self.gap_open_cost;
+        let no_gap = (NO_GAP, 0);
+        // Cost and negated matches, so that the minimum is best, of
+        // the alignments ending anyhow, with an insertion, and with a
+        // deletion
+        let mut scores = vec![vec![(0usize, 0isize); b + 1]; a + 1];
+        let mut insertions = vec![vec![no_gap; b + 1]; a + 1];
+        let mut deletions = vec![vec![no_gap; b + 1]; a + 1];
+        for i in 1..(a + 1) {
+            deletions[i][0] = (open + i * self.deletion_cost, 0);
+            scores[i][0] = deletions[i][0];
+        }
+        for j in 1..(b + 1) {
+            insertions[0][j] = (open + j * self.insertion_cost, 0);
+            scores[0][j] = insertions[0][j];
+        }
+        let diag = |scores: &Vec<Vec<(usize, isize)>>, i: usize, j: usize| {
+            let (cost, matches) = scores[i - 1][j - 1];
+            if bref[i - 1] & bquery[j - 1] != 0 {
+                (cost, matches - 1)
+            } else if bquery[j - 1] == IGNORED_N {
+                (cost, matches)
+            } else {
+                (cost + self.mismatch_cost, matches)
+            }
+        };
+        for i in 1..(a + 1) {
+            for j in 1..(b + 1) {
+                let (left_cost, left_matches) = scores[i][j - 1];
+                let (ins_cost, ins_matches) = insertions[i][j - 1];
+                insertions[i][j] = (left_cost + open + self.insertion_cost, left_matches)
+                    .min((ins_cost + self.insertion_cost, ins_matches));
+                let (up_cost, up_matches) = scores[i - 1][j];
+                let (del_cost, del_matches) = deletions[i - 1][j];
+                deletions[i][j] = (up_cost + open + self.deletion_cost, up_matches)
+                    .min((del_cost + self.deletion_cost, del_matches));
+                scores[i][j] = diag(&scores, i, j).min(deletions[i][j]).min(insertions[i][j]);
+            }
+        }
+
+        let mut ops = Vec::with_capacity(a.max(b));
+        let (mut i, mut j) = (a, b);
+        let mut state = None;
+        while i > 0 || j > 0 {
+            match state {
+                None => {
+                    if i > 0 && j > 0 && diag(&scores, i, j) == scores[i][j] {
+                        ops.push(if bref[i - 1] & bquery[j - 1] != 0 { AlignOp::Match } else { AlignOp::Mismatch });
+                        i -= 1;
+                        j -= 1;
+                    } else if i > 0 && deletions[i][j] == scores[i][j] {
+                        state = Some(AlignOp::Deletion);
+                    } else {
+                        state = Some(AlignOp::Insertion);
+                    }
+                },
+                Some(AlignOp::Deletion) => {
+                    ops.push(AlignOp::Deletion);
+                    let (del_cost, del_matches) = deletions[i - 1][j];
+                    if (del_cost + self.deletion_cost, del_matches) != deletions[i][j] {
+                        state = None;
+                    }
+                    i -= 1;
+                },
+                Some(_) => {
+                    ops.push(AlignOp::Insertion);
+                    let (ins_cost, ins_matches) = insertions[i][j - 1];
+                    if (ins_cost + self.insertion_cost, ins_matches) != insertions[i][j] {
+                        state = None;
+                    }
+                    j -= 1;
+                },
+            }
+        }
+        ops.reverse();
+        ops
+    }
+
+    /// Finds the best alignment without indels, trying each offset of
+    /// the query against the reference. Mismatches cost the mismatch
+    /// cost, or `mismatch_costs` when `unit` is larger or query `N`
+    /// bases are ignored.
+    fn locate_mismatches(&mut self, bquery: &[u8], unit: usize) -> Option<Location> {
+        let m = self.m() as isize;
+        let n = bquery.len() as isize;
+
+        // The query starts at `offset` relative to the reference, and
+        // the start or stop of either sequence can only be skipped
+        // when it is local.
+        let mut min_offset = 1 - m;
+        let mut max_offset = n - 1;
+        if !self.reference_ends.start_local() {
+            min_offset = min_offset.max(0);
+        }
+        if !self.query_ends.start_local() {
+            max_offset = max_offset.min(0);
+        }
+        if !self.reference_ends.stop_local() {
+            max_offset = max_offset.min(n - m);
+        }
+        if !self.query_ends.stop_local() {
+            min_offset = min_offset.max(n - m);
+        }
+        if min_offset > max_offset {
+            return None;
+        }
+
+        // The DP matrix finds alignments reaching the end of the
+        // reference from left to right, when the query stop is local,
+        // followed by those reaching the end of the query from the
+        // shortest reference.
+        let last_reaching_ref_end = if self.query_ends.stop_local() { max_offset.min(n - m) } else { min_offset - 1 };
+        let reaching_ref_end = min_offset..=last_reaching_ref_end;
+        let reaching_query_end = ((n - m).max(min_offset)..=max_offset).rev();
+
+        let mut best: Option<Location> = None;
+        let offsets = reaching_ref_end.map(|offset| (offset, true))
+            .chain(reaching_query_end.map(|offset| (offset, false)));
+        for (offset, in_last_row) in offsets {
+            let refstart = (-offset).max(0) as usize;
+            let querystart = offset.max(0) as usize;
+            let refstop = m.min(n - offset) as usize;
+            if refstop <= refstart {
+                continue;
+            }
+            let length = refstop - refstart;
+            let querystop = querystart + length;
+            let reference = &self.breference[refstart..refstop];
+            let query = &bquery[querystart..querystop];
+            let (matches, cost) = if unit == 1 && self.query_n != QueryN::Ignore {
+                let mismatches = count_mismatches(reference, query);
+                (length - mismatches, mismatches * self.mismatch_cost)
+            } else {
+                let mismatch_costs = &self.mismatch_costs[querystart..querystop];
+                reference.iter().zip(query.iter()).zip(mismatch_costs.iter())
+                    .fold((0, 0), |(matches, cost), ((&r, &q), &mismatch_cost)| {
+                        if r & q != 0 { (matches + 1, cost) } else { (matches, cost + mismatch_cost) }
+                    })
+            };
+            // As in the last row of the DP matrix, a partial match
+            // reaching the end of the reference leaves out as many
+            // wildcards as its prefix of the same length has.
+            let effective_length = if !self.matching.ref_wildcard() {
+                length
+            } else if in_last_row && length < self.m() {
+                length - self.n_counts[length]
+            } else {
+                length - (self.n_counts[refstop] - self.n_counts[refstart])
+            }.saturating_sub(self.ignored_ns(querystart, querystop));
+            let better = match &best {
+                None => true,
+                Some(best) => matches > best.matches || (matches == best.matches && cost * (COST_UNIT / unit) < best.cost),
+            };
+            if length >= self.min_overlap
+                && (cost as f64) <= effective_length as f64 * self.max_error_rate * unit as f64
+                && better
+            {
+                best = Some(Location {
+                    refstart, refstop, querystart, querystop, matches,
+                    errors: cost.div_ceil(unit),
+                    cost: cost * (COST_UNIT / unit),
+                });
+            }
+        }
+        best
+    }
+
+    /// Finds the alignment as [`locate()`](#method.locate) does, by
+    /// always computing the DP matrix.
+    pub fn locate_dp(&mut self, query: &[u8]) -> Option<Location> {
+        self.with_encoded(query, |aligner, bquery| aligner.locate_dp_encoded(bquery))
+    }
+
+    fn locate_dp_encoded(&mut self, bquery: &[u8]) -> Option<Location> {
+        self.fill_mismatch_costs(bquery, None);
+        self.locate_costs(bquery, 1)
+    }
+
+    /// Finds the alignment of `query`, with each cost multiplied by
+    /// `unit` and mismatches against each query base costing
+    /// `mismatch_costs`.
+    fn locate_costs(&mut self, bquery: &[u8], unit: usize) -> Option<Location> {
+        let s1 = &self.breference;
+        let m = self.m();
+        let n = bquery.len();
+        let (min_n, max_n, k) = self.column_range(n);
+        let column = &mut self.column;
+        let max_error_rate = self.max_error_rate * unit as f64;
+        let s2 = bquery;
+        let mismatch_costs = &self.mismatch_costs;
+        let query_n_counts = &self.query_n_counts;
+        let ignore_n = self.query_n == QueryN::Ignore;
+        // # Ignored query Ns aligned from the query start of an origin
+        let ignored_ns = |origin: Origin, query_stop: usize| {
+            if ignore_n {
+                let query_start = match origin {
+                    Origin::QueryStart(s) => s,
+                    Origin::RefStart(_) => 0,
+                };
+                query_n_counts[query_stop] - query_n_counts[query_start]
+            } else {
+                0
+            }
+        };
+        let insertion_column = &mut self.insertion_column;
+        let mismatch_cost = self.mismatch_cost * unit;
+        let insertion_cost = self.insertion_cost * unit;
+        let deletion_cost = self.deletion_cost * unit;
+        let gap_open_cost = self.gap_open_cost * unit;
+        let affine = gap_open_cost > 0;
+        // # With equal linear gap costs, a match on the diagonal is
+        // # never more expensive than a gap.
+        let symmetric_gaps = !affine && insertion_cost == deletion_cost;
+        let insertion_gap = |len: usize| if len == 0 { 0 } else { gap_open_cost + len * insertion_cost };
+        let deletion_gap = |len: usize| if len == 0 { 0 } else { gap_open_cost + len * deletion_cost };
+        // # maximum cost
+        let k_cost = (max_error_rate * m as f64).floor() as usize;
+        //         DP Matrix:
+        //                    query (j)
+        //                  ----------> n
+        //                 |
+        //         ref (i) |
+        //                 |
+        //                 V
+        //                m
 
         // # Fill column min_n.
         // #
-        // # Four cases:
+        // # Four cases, with unit costs:
         // # not startin1, not startin2: c(i,j) = max(i,j); origin(i, j) = 0
         // #     startin1, not startin2: c(i,j) = j       ; origin(i, j) = min(0, j - i)
         // # not startin1,     startin2: c(i,j) = i       ; origin(i, j) =
         // #     startin1,     startin2: c(i,j) = min(i,j)
+        // #
+        // # With other costs, the excess of i over j is a deletion and
+        // # the excess of j over i an insertion. An insertion ending
+        // # the alignment is also an open gap for affine gap costs.
 
         // # TODO (later)
         // # fill out columns only until 'last'
         if !self.reference_ends.start_local() && !self.query_ends.start_local() {
             for i in 0..(m + 1) {
                 column[i].matches = 0;
-                column[i].cost = usize::max(i, min_n) * self.insertion_cost;
+                column[i].cost = usize::min(i, min_n) * mismatch_cost
+                    + if i > min_n { deletion_gap(i - min_n) } else { insertion_gap(min_n - i) };
                 column[i].origin = Origin::RefStart(0);
+                insertion_column[i] = column[i];
+                if min_n <= i {
+                    insertion_column[i].cost = NO_GAP;
+                }
             }
         } else if self.reference_ends.start_local() && !self.query_ends.start_local() {
             for i in 0..(m + 1) {
                 column[i].matches = 0;
-                column[i].cost = min_n * self.insertion_cost;
+                column[i].cost = insertion_gap(min_n);
                 column[i].origin = Origin::RefStart(i - usize::min(i, min_n));
+                insertion_column[i] = column[i];
+                if min_n == 0 {
+                    insertion_column[i].cost = NO_GAP;
+                }
             }
         } else if !self.reference_ends.start_local() && self.query_ends.start_local() {
             for i in 0..(m + 1) {
                 column[i].matches = 0;
-                column[i].cost = i * self.insertion_cost;
+                column[i].cost = deletion_gap(i);
                 column[i].origin = Origin::QueryStart(min_n - usize::min(min_n, i));
+                insertion_column[i] = column[i];
+                insertion_column[i].cost = NO_GAP;
             }
         } else {
             for i in 0..(m + 1) {
                 column[i].matches = 0;
-                column[i].cost = usize::min(i, min_n) * self.insertion_cost;
+                column[i].cost = usize::min(i, min_n) * mismatch_cost;
                 column[i].origin = if min_n > i {
                     Origin::QueryStart(min_n - i)
                 } else {
                     Origin::RefStart(i - min_n)
                 };
+                insertion_column[i] = column[i];
+                insertion_column[i].cost = NO_GAP;
             }
         }
 
         if self.debug {
-            let mut dpmatrix = DPMatrix::new(&self.reference, query);
+            let mut query = Vec::with_capacity(n);
+            decode_iupac_vec(bquery, &mut query);
+            let mut dpmatrix = DPMatrix::new(&self.reference, &query);
             for i in 0..(m + 1) {
                 dpmatrix.set_entry(i, min_n, column[i].cost);
             }
@@ -477,7 +1290,7 @@ impl Aligner {
         let mut best = Match::default();
         best.ref_stop = m;
         best.query_stop = n;
-        best.cost = m + n;
+        best.cost = usize::MAX;
         best.origin = Origin::RefStart(0);
         best.matches = 0;
 
@@ -501,9 +1314,13 @@ impl Aligner {
             if self.query_ends.start_local() {
                 column[0].origin = Origin::QueryStart(j);
             } else {
-                column[0].cost = j * self.insertion_cost;
+                column[0].cost = insertion_gap(j);
+                insertion_column[0] = column[0];
             }
 
+            // # Gap ending in the cell above, with affine gap costs
+            let mut deletion_entry = Entry { cost: NO_GAP, ..column[0] };
+
             for i in 0..(last as usize) {
                 let origin;
                 let cost;
@@ -511,33 +1328,53 @@ impl Aligner {
 
                 let characters_equal = (s1[i] & s2[(j - 1)]) != 0;
 
-                if characters_equal {
+                if characters_equal && symmetric_gaps {
                     // # If the characters match, skip computing costs for
                     // # insertion and deletion as they are at least as high.
                     cost = diag_entry.cost;
                     origin = diag_entry.origin;
                     matches = diag_entry.matches + 1;
                 } else {
-                    // # Characters do not match.
-                    let cost_diag = diag_entry.cost + 1;
-                    let cost_deletion = column[i + 1].cost + self.deletion_cost;
-                    let cost_insertion = column[i].cost + self.insertion_cost;
+                    let (cost_diag, matches_diag) = if characters_equal {
+                        (diag_entry.cost, diag_entry.matches + 1)
+                    } else {
+                        (diag_entry.cost + mismatch_costs[j - 1], diag_entry.matches)
+                    };
+                    // # An insertion extends the cell to the left, and a
+                    // # deletion the cell above, unless an affine gap
+                    // # is cheaper to extend than to open.
+                    let mut insertion = column[i + 1];
+                    insertion.cost += gap_open_cost + insertion_cost;
+                    let mut deletion = column[i];
+                    deletion.cost += gap_open_cost + deletion_cost;
+                    if affine {
+                        if insertion_column[i + 1].cost + insertion_cost <= insertion.cost {
+                            insertion = insertion_column[i + 1];
+                            insertion.cost += insertion_cost;
+                        }
+                        if deletion_entry.cost + deletion_cost <= deletion.cost {
+                            deletion = deletion_entry;
+                            deletion.cost += deletion_cost;
+                        }
+                        insertion_column[i + 1] = insertion;
+                        deletion_entry = deletion;
+                    }
 
-                    if cost_diag <= cost_deletion && cost_diag <= cost_insertion {
-                        // # MISMATCH
+                    if cost_diag <= insertion.cost && cost_diag <= deletion.cost {
+                        // # MATCH or MISMATCH
                         cost = cost_diag;
                         origin = diag_entry.origin;
-                        matches = diag_entry.matches;
-                    } else if cost_insertion < cost_deletion {
-                        // # INSERTION
-                        cost = cost_insertion;
-                        origin = column[i].origin;
-                        matches = column[i].matches;
-                    } else {
+                        matches = matches_diag;
+                    } else if deletion.cost < insertion.cost {
                         // # DELETION
-                        cost = cost_deletion;
-                        origin = column[i + 1].origin;
-                        matches = column[i + 1].matches;
+                        cost = deletion.cost;
+                        origin = deletion.origin;
+                        matches = deletion.matches;
+                    } else {
+                        // # INSERTION
+                        cost = insertion.cost;
+                        origin = insertion.origin;
+                        matches = insertion.matches;
                     }
                 }
 
@@ -554,7 +1391,7 @@ impl Aligner {
                 }
             }
 
-            while last >= 0 && column[last as usize].cost > k {
+            while last >= 0 && column[last as usize].cost > k_cost {
                 last -= 1;
             }
             
@@ -579,7 +1416,7 @@ impl Aligner {
                     }
                 } else {
                     length
-                };
+                }.saturating_sub(ignored_ns(column[m].origin, j));
                 let cost = column[m].cost;
                 let matches = column[m].matches;
                 if length >= self.min_overlap
@@ -627,7 +1464,7 @@ impl Aligner {
                     }
                 } else {
                     length
-                };
+                }.saturating_sub(ignored_ns(column[i].origin, n));
 
                 assert!(cur_effective_length <= length);
                 assert!(cur_effective_length <= self.effective_length);
@@ -646,7 +1483,7 @@ impl Aligner {
             }
         }
 
-        if best.cost == m + n {
+        if best.cost == usize::MAX {
             // # best.cost was initialized with this value.
             // # If it is unchanged, no alignment was found that has
             // # an error rate within the allowed range.
@@ -673,7 +1510,211 @@ impl Aligner {
                 querystart: querystart,
                 querystop: best.query_stop,
                 matches: best.matches,
-                errors: best.cost,
+                errors: best.cost.div_ceil(unit),
+                cost: best.cost * (COST_UNIT / unit),
             });
     }
 }
+
+/// Query range still to search, or match not yet returned, by
+/// [`Locations`](struct.Locations.html)
+#[derive(Debug)]
+enum Pending {
+    Range(usize, usize),
+    Match(Location),
+}
+
+/// Iterator over the non-overlapping matches in a query, from
+/// [`Aligner::locate_all()`](struct.Aligner.html#method.locate_all).
+pub struct Locations<'a> {
+    aligner: &'a mut Aligner,
+    /// Ranges and matches in the query, with the leftmost last
+    pending: Vec<Pending>,
+    bquery: Vec<u8>,
+}
+
+impl Iterator for Locations<'_> {
+    type Item = Location;
+
+    fn next(&mut self) -> Option<Location> {
+        while let Some(pending) = self.pending.pop() {
+            let (start, end) = match pending {
+                Pending::Range(start, end) => (start, end),
+                Pending::Match(location) => return Some(location),
+            };
+            if let Some(mut location) = self.aligner.locate_encoded(&self.bquery[start..end]) {
+                // A match of no query bases would leave the range
+                // to search unchanged.
+                if location.querystop > location.querystart {
+                    location.querystart += start;
+                    location.querystop += start;
+                    let (left, right) = (Pending::Range(start, location.querystart), Pending::Range(location.querystop, end));
+                    self.pending.push(right);
+                    self.pending.push(Pending::Match(location));
+                    self.pending.push(left);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Reference of a [`PrefixComparer`](struct.PrefixComparer.html) or
+/// [`SuffixComparer`](struct.SuffixComparer.html)
+#[derive(Clone, Debug)]
+struct Comparer {
+    max_error_rate: f64,
+    mismatch_cost: usize,
+    min_overlap: usize,
+    matching: AlignMatching,
+    query_n: QueryN,
+    breference: Vec<u8>,
+    n_counts: Vec<usize>,
+    /// Buffer for the encoded query
+    bquery: Vec<u8>,
+}
+
+impl Comparer {
+    fn new(conf: &AlignerConf, reference: &[u8]) -> Result<Self> {
+        conf.validate()?;
+        let mut n_counts = Vec::with_capacity(reference.len() + 1);
+        let mut n_count = 0;
+        for &nt in reference.iter() {
+            n_counts.push(n_count);
+            if nt == b'n' || nt == b'N' {
+                n_count += 1;
+            }
+        }
+        n_counts.push(n_count);
+        let mut breference = Vec::with_capacity(reference.len());
+        if conf.matching.ref_wildcard() {
+            ensure!(n_count < reference.len(), "Cannot have only N wildcards in the sequence");
+            encode_iupac_vec(reference, &mut breference);
+        } else {
+            encode_acgt_vec(reference, &mut breference);
+        }
+        Ok(Comparer {
+            max_error_rate: conf.max_error_rate,
+            mismatch_cost: conf.mismatch_cost,
+            min_overlap: conf.min_overlap,
+            matching: conf.matching,
+            query_n: conf.resolved_query_n(),
+            breference,
+            n_counts,
+            bquery: Vec::with_capacity(INIT_QUERY_LEN),
+        })
+    }
+
+    /// Compares the reference with the start of `bquery`, or with
+    /// its end when `suffix` is set.
+    fn compare(&self, bquery: &[u8], suffix: bool) -> Option<Location> {
+        let (m, n) = (self.breference.len(), bquery.len());
+        let length = m.min(n);
+        if length < self.min_overlap {
+            return None;
+        }
+        let (refstart, querystart) = if suffix { (m - length, n - length) } else { (0, 0) };
+        let (refstop, querystop) = (refstart + length, querystart + length);
+        let query = &bquery[querystart..querystop];
+        let mismatches = count_mismatches(&self.breference[refstart..refstop], query);
+        let ignored = if self.query_n == QueryN::Ignore {
+            query.iter().filter(|&&nt| nt == IGNORED_N).count()
+        } else {
+            0
+        };
+        let cost = (mismatches - ignored) * self.mismatch_cost;
+        let effective_length = if self.matching.ref_wildcard() {
+            length - (self.n_counts[refstop] - self.n_counts[refstart])
+        } else {
+            length
+        }.saturating_sub(ignored);
+        if (cost as f64) > effective_length as f64 * self.max_error_rate {
+            return None;
+        }
+        Some(Location {
+            refstart, refstop, querystart, querystop,
+            matches: length - mismatches,
+            errors: cost,
+            cost: cost * COST_UNIT,
+        })
+    }
+
+    /// Calls `f` with `query` encoded in the query buffer.
+    fn with_encoded<T>(&mut self, query: &[u8], f: impl FnOnce(&Self, &[u8]) -> T) -> T {
+        let mut bquery = std::mem::take(&mut self.bquery);
+        encode_query_as(self.matching, self.query_n, query, &mut bquery);
+        let result = f(self, &bquery);
+        self.bquery = bquery;
+        result
+    }
+}
+
+/// Matches a reference anchored at the start of the query, without
+/// indels and without computing a DP matrix.
+///
+/// The reference is compared base by base with the start of the
+/// query, over the length of the shorter of the two, and the
+/// comparison is a match when it covers at least `min_overlap` bases
+/// and its mismatches are within the error rate. This finds the same
+/// match as an [`Aligner`](struct.Aligner.html) in mismatch-only mode
+/// with the starts of both sequences global and their stops local,
+/// and so with a `min_overlap` of the whole reference it serves for
+/// anchored 5' adapters and barcodes. The alignment ends of the configuration
+/// are not used, and neither are the gap costs. Bases are encoded and
+/// `N` bases counted as by the `Aligner`.
+///
+/// This follows `PrefixComparer` in `Cutadapt`.
+#[derive(Clone, Debug)]
+pub struct PrefixComparer(Comparer);
+
+impl PrefixComparer {
+    /// Creates a new comparer with a specified alignment
+    /// configuration and reference sequence.
+    pub fn new(conf: &AlignerConf, reference: &[u8]) -> Result<Self> {
+        Ok(PrefixComparer(Comparer::new(conf, reference)?))
+    }
+
+    /// Finds the match of the reference at the start of `query`.
+    pub fn locate(&mut self, query: &[u8]) -> Option<Location> {
+        self.0.with_encoded(query, |comparer, bquery| comparer.compare(bquery, false))
+    }
+
+    /// Finds the match as [`locate()`](#method.locate) does, for a
+    /// query encoded by
+    /// [`Aligner::encode_query()`](struct.Aligner.html#method.encode_query)
+    /// with the same configuration.
+    pub fn locate_encoded(&self, bquery: &[u8]) -> Option<Location> {
+        self.0.compare(bquery, false)
+    }
+}
+
+/// Matches a reference anchored at the end of the query, as
+/// [`PrefixComparer`](struct.PrefixComparer.html) does at the start.
+/// This finds the same match as an [`Aligner`](struct.Aligner.html)
+/// in mismatch-only mode with the stops of both sequences global and
+/// their starts local, and serves for anchored 3' adapters.
+///
+/// This follows `SuffixComparer` in `Cutadapt`.
+#[derive(Clone, Debug)]
+pub struct SuffixComparer(Comparer);
+
+impl SuffixComparer {
+    /// Creates a new comparer with a specified alignment
+    /// configuration and reference sequence.
+    pub fn new(conf: &AlignerConf, reference: &[u8]) -> Result<Self> {
+        Ok(SuffixComparer(Comparer::new(conf, reference)?))
+    }
+
+    /// Finds the match of the reference at the end of `query`.
+    pub fn locate(&mut self, query: &[u8]) -> Option<Location> {
+        self.0.with_encoded(query, |comparer, bquery| comparer.compare(bquery, true))
+    }
+
+    /// Finds the match as [`locate()`](#method.locate) does, for a
+    /// query encoded by
+    /// [`Aligner::encode_query()`](struct.Aligner.html#method.encode_query)
+    /// with the same configuration.
+    pub fn locate_encoded(&self, bquery: &[u8]) -> Option<Location> {
+        self.0.compare(bquery, true)
+    }
+}
@@ -0,0 +1,635 @@
+//! Identification and removal of inline barcodes at the start of the
+//! read, or identification of index barcodes in the read header.
+//!
+//! The barcode with the most matches to the start of the read is
+//! chosen, breaking ties by the fewest errors. The matching barcode
+//! is trimmed from the read and its index is recorded as the read
+//! tag, for use in demultiplexing.
+//!
+//! Illumina index reads are not part of the insert. Their sequences
+//! are instead written at the end of the read header by `bcl2fastq`
+//! and BCL Convert, as in `@A00123:8:HXXX:1:1101:1000:1000
+//! 1:N:0:ATTACTCG+TATAGCCT`, with the i7 index first and any i5 index
+//! after the `+`. Barcodes read from the header are matched against
+//! the start of the chosen index in the same way, but the read itself
+//! is left untrimmed.
+//!
+//! When all barcodes are plain `ACGT` sequences, every sequence
+//! within `max_errors` of a barcode is precomputed and reads are
+//! assigned by a hash lookup of each possible barcode length at the
+//! start of the read. Only mismatches are counted as errors unless
+//! indels are allowed, in which case the neighborhood covers all
+//! sequences within the edit distance. A sequence that lies in the
+//! neighborhoods of two barcodes with the same number of matches and
+//! errors cannot be assigned. These collisions are reported when the
+//! barcodes are loaded, and such reads are left unassigned.
+//!
+//! Barcodes with IUPAC wildcards are instead aligned, in their
+//! entirety, against the start of the read, breaking any remaining
+//! ties by the order in which the barcodes were given. Without
+//! indels, each barcode is compared directly with the start of the
+//! read rather than aligned. Their neighborhoods, where a wildcard
+//! matches any of its bases, are still computed to report collisions.
+//!
+//! Neighborhoods grow exponentially with the errors, especially with
+//! indels, so barcodes are rejected when their neighborhoods could
+//! hold more than `MAX_NEIGHBORHOOD_SIZE` sequences in total.
+//!
+//! The neighborhood index follows `MultiAdapter` and
+//! `hamming_environment` in `Cutadapt`.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use anyhow::{bail, ensure, Result};
+use serde::{Serialize, Deserialize};
+
+use crate::align::{AlignEnds, AlignMatching, Aligner, AlignerConf, Location, PrefixComparer};
+use crate::encode::encode_iupac_vec;
+use crate::trimming::Trimming;
+
+const ACGT: &[u8] = b"ACGT";
+
+/// Largest total number of sequences allowed in the neighborhoods of
+/// all barcodes, as estimated by `environment_bound`.
+pub const MAX_NEIGHBORHOOD_SIZE: f64 = 4_000_000.0;
+
+/// Where the barcode is found.
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BarcodeSource {
+    /// Start of the read, from which the barcode is trimmed
+    #[default]
+    Inline,
+    /// First (i7) index in the read header
+    HeaderIndex,
+    /// Second (i5) index in the read header
+    HeaderIndex2,
+}
+
+/// A named barcode sequence, possibly including IUPAC wildcards.
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Serialize, Deserialize)]
+pub struct Barcode {
+    pub name: String,
+    pub sequence: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct Barcodes {
+    names: Vec<String>,
+    source: BarcodeSource,
+    matcher: Matcher,
+    collisions: Vec<BarcodeCollision>,
+    counts: Vec<usize>,
+    no_barcode: usize,
+}
+
+#[derive(Clone, Debug)]
+enum Matcher {
+    Index(BarcodeIndex),
+    Align(Vec<Aligner>),
+    Compare(Vec<PrefixComparer>),
+}
+
+impl Barcodes {
+    /// Creates a barcode matcher allowing up to `max_errors` errors
+    /// in the barcode. Errors are mismatches only, unless `indels`
+    /// is set.
+    pub fn new(barcodes: &[Barcode], max_errors: usize, indels: bool) -> Result<Self> {
+        ensure!(!barcodes.is_empty(), "No barcodes given");
+
+        let mut names: Vec<String> = Vec::with_capacity(barcodes.len());
+        for barcode in barcodes {
+            ensure!(!names.contains(&barcode.name), "Duplicate barcode name {:?}", barcode.name);
+            ensure!(!barcode.sequence.is_empty(), "Empty barcode sequence for {:?}", barcode.name);
+            ensure!(barcode.sequence.bytes().all(|nt| iupac_mask(nt) != 0),
+                    "Barcode {:?} contains characters other than IUPAC nucleotides", barcode.name);
+            // With as many errors as bases every read matches.
+            ensure!(max_errors < barcode.sequence.len(),
+                    "Maximum errors {} must be below the length of barcode {:?}", max_errors, barcode.name);
+            names.push(barcode.name.clone());
+        }
+
+        let size = barcodes.iter().map(|bc| environment_bound(bc.sequence.as_bytes(), max_errors, indels)).sum::<f64>();
+        ensure!(size <= MAX_NEIGHBORHOOD_SIZE,
+                "Barcodes with {} error(s){} have up to {:.0} sequences within their neighborhoods, more than the {:.0} allowed",
+                max_errors, if indels { " including indels" } else { "" }, size, MAX_NEIGHBORHOOD_SIZE);
+
+        let (index, collisions) = BarcodeIndex::new(barcodes, max_errors, indels)?;
+        let plain = barcodes.iter().all(|bc| bc.sequence.bytes().all(|nt| ACGT.contains(&normalize(nt))));
+        let matcher = if plain {
+            Matcher::Index(index)
+        } else {
+            Self::wildcard_matcher(barcodes, max_errors, indels)?
+        };
+
+        let counts = vec![0; names.len()];
+        Ok(Barcodes { names, source: BarcodeSource::Inline, matcher, collisions, counts, no_barcode: 0 })
+    }
+
+    fn wildcard_matcher(barcodes: &[Barcode], max_errors: usize, indels: bool) -> Result<Matcher> {
+        let mut aligners = Vec::new();
+        let mut comparers = Vec::new();
+        for barcode in barcodes {
+            let len = barcode.sequence.len();
+            // Half an error of slack keeps the floor of the error
+            // count at `max_errors` despite rounding.
+            let conf = AlignerConf {
+                max_error_rate: (max_errors as f64 + 0.5) / len as f64,
+                reference_ends: AlignEnds::Global,
+                query_ends: AlignEnds::LocalStop,
+                matching: AlignMatching::RefWildcard,
+                query_n: None,
+                mismatch_cost: 1,
+                insertion_cost: 1,
+                deletion_cost: 1,
+                gap_open_cost: 0,
+                mismatch_only: !indels,
+                min_overlap: len,
+            };
+            if indels {
+                aligners.push(Aligner::new(&conf, barcode.sequence.as_bytes())?);
+            } else {
+                comparers.push(PrefixComparer::new(&conf, barcode.sequence.as_bytes())?);
+            }
+        }
+        Ok(if indels { Matcher::Align(aligners) } else { Matcher::Compare(comparers) })
+    }
+
+    /// Sets where the barcodes are found, at the start of the read by
+    /// default.
+    pub fn set_source(&mut self, source: BarcodeSource) {
+        self.source = source;
+    }
+
+    /// Barcode names, in the order used for read tags.
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// Pairs of barcodes whose neighborhoods collide, found when the
+    /// barcodes were loaded. These are reported as warnings.
+    pub fn collisions(&self) -> &[BarcodeCollision] {
+        &self.collisions
+    }
+
+    /// Finds the barcode at the start of `seq`, returning its index
+    /// along with the length of the read matched by the barcode.
+    pub fn find(&mut self, seq: &[u8]) -> Option<(usize, usize)> {
+        match &mut self.matcher {
+            Matcher::Index(index) => index.find(seq),
+            Matcher::Align(aligners) => best_location(aligners.iter_mut().map(|aligner| aligner.locate(seq))),
+            Matcher::Compare(comparers) => best_location(comparers.iter_mut().map(|comparer| comparer.locate(seq))),
+        }
+    }
+
+    /// Removes the barcode from the start of the read, or finds it in
+    /// the header, and records it as the read tag. Reads with no
+    /// barcode are left untrimmed with no read tag.
+    pub fn trim(&mut self, trimming: &mut Trimming) {
+        let found = match self.source {
+            BarcodeSource::Inline => self.find(trimming.seq_trimmed()),
+            BarcodeSource::HeaderIndex => header_index(trimming.name_raw(), 0).and_then(|index| self.find(index)),
+            BarcodeSource::HeaderIndex2 => header_index(trimming.name_raw(), 1).and_then(|index| self.find(index)),
+        };
+        match found {
+            Some((idx, len)) => {
+                self.counts[idx] += 1;
+                if self.source == BarcodeSource::Inline {
+                    trimming.trim_from_start(len);
+                }
+                trimming.set_read_tag(Some(idx));
+            }
+            None => {
+                self.no_barcode += 1;
+                trimming.set_read_tag(None);
+            }
+        }
+    }
+
+    /// Number of reads with each barcode, in the order of `names`
+    pub fn counts(&self) -> &[usize] {
+        &self.counts
+    }
+
+    /// Number of reads with no barcode
+    pub fn no_barcode(&self) -> usize {
+        self.no_barcode
+    }
+
+    /// Adds the statistics of `other`, a copy of these barcodes.
+    pub fn merge(&mut self, other: &Self) {
+        for (count, other_count) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *count += *other_count;
+        }
+        self.no_barcode += other.no_barcode;
+    }
+}
+
+/// Two barcodes whose neighborhoods share sequences that cannot be
+/// assigned uniquely.
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Serialize, Deserialize)]
+pub struct BarcodeCollision {
+    pub barcode1: String,
+    pub barcode2: String,
+    /// Number of ambiguous sequences
+    pub sequences: usize,
+    /// One of the ambiguous sequences
+    pub example: String,
+}
+
+impl fmt::Display for BarcodeCollision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Barcodes {} and {} are very similar: {} sequence(s), such as {}, cannot be assigned uniquely",
+               self.barcode1, self.barcode2, self.sequences, self.example)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Assignment {
+    Unique(usize),
+    Ambiguous(usize, usize),
+}
+
+#[derive(Clone, Copy, Debug)]
+struct IndexEntry {
+    assignment: Assignment,
+    matches: usize,
+    errors: usize,
+}
+
+#[derive(Clone, Debug)]
+struct BarcodeIndex {
+    entries: HashMap<Vec<u8>, IndexEntry>,
+    // Sequence lengths in the index, longest first
+    lengths: Vec<usize>,
+    prefix: Vec<u8>,
+}
+
+impl BarcodeIndex {
+    fn new(barcodes: &[Barcode], max_errors: usize, indels: bool) -> Result<(Self, Vec<BarcodeCollision>)> {
+        let mut entries: HashMap<Vec<u8>, IndexEntry> = HashMap::new();
+
+        for (idx, barcode) in barcodes.iter().enumerate() {
+            let sequence = barcode.sequence.bytes().map(normalize).collect::<Vec<u8>>();
+            for (seq, (errors, matches)) in environment(&sequence, max_errors, indels) {
+                let entry = IndexEntry { assignment: Assignment::Unique(idx), matches, errors };
+                match entries.get_mut(&seq) {
+                    None => { entries.insert(seq, entry); },
+                    Some(other) => {
+                        if (matches, other.errors) > (other.matches, errors) {
+                            *other = entry;
+                        } else if matches == other.matches && errors == other.errors {
+                            if let Assignment::Unique(other_idx) = other.assignment {
+                                if errors == 0 {
+                                    bail!("Barcodes {:?} and {:?} both match {} without errors",
+                                          barcodes[other_idx].name, barcode.name, String::from_utf8_lossy(&seq));
+                                }
+                                other.assignment = Assignment::Ambiguous(other_idx, idx);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut collision_counts: HashMap<(usize, usize), (usize, &[u8])> = HashMap::new();
+        for (seq, entry) in entries.iter() {
+            if let Assignment::Ambiguous(idx1, idx2) = entry.assignment {
+                let count = collision_counts.entry((idx1, idx2)).or_insert((0, seq));
+                count.0 += 1;
+                count.1 = count.1.min(seq);
+            }
+        }
+        let mut collisions = collision_counts.into_iter()
+            .map(|((idx1, idx2), (sequences, example))| BarcodeCollision {
+                barcode1: barcodes[idx1].name.clone(),
+                barcode2: barcodes[idx2].name.clone(),
+                sequences,
+                example: String::from_utf8_lossy(example).into_owned(),
+            })
+            .collect::<Vec<BarcodeCollision>>();
+        collisions.sort();
+
+        let mut lengths = entries.keys().map(Vec::len).collect::<Vec<usize>>();
+        lengths.sort_unstable_by(|a, b| b.cmp(a));
+        lengths.dedup();
+
+        Ok((BarcodeIndex { entries, lengths, prefix: Vec::new() }, collisions))
+    }
+
+    fn find(&mut self, seq: &[u8]) -> Option<(usize, usize)> {
+        let mut best: Option<(usize, IndexEntry)> = None;
+        for &length in self.lengths.iter() {
+            if length > seq.len() {
+                continue;
+            }
+            if let Some((_, entry)) = best {
+                // No shorter sequence can have as many matches
+                if length < entry.matches {
+                    break;
+                }
+            }
+            self.prefix.clear();
+            self.prefix.extend(seq[..length].iter().copied().map(normalize));
+            if let Some(&entry) = self.entries.get(&self.prefix) {
+                let better = match best {
+                    None => true,
+                    Some((_, best_entry)) => {
+                        entry.matches > best_entry.matches
+                            || (entry.matches == best_entry.matches && entry.errors < best_entry.errors)
+                    }
+                };
+                if better {
+                    best = Some((length, entry));
+                }
+            }
+        }
+        match best {
+            Some((length, IndexEntry { assignment: Assignment::Unique(idx), .. })) => Some((idx, length)),
+            _ => None,
+        }
+    }
+}
+
+/// Index sequence `which`, counting from 0, at the end of an Illumina
+/// read header, e.g., `TATAGCCT` for 1 in `read 1:N:0:ATTACTCG+TATAGCCT`.
+fn header_index(name: &[u8], which: usize) -> Option<&[u8]> {
+    let mut fields = name.split(|c| c.is_ascii_whitespace()).filter(|field| !field.is_empty());
+    fields.next()?;
+    let comment = fields.next_back()?;
+    let indices = comment.rsplit(|&c| c == b':').next()?;
+    indices.split(|&c| c == b'+').nth(which).filter(|index| !index.is_empty())
+}
+
+/// Index and matched read length of the barcode with the most
+/// matches among `locations`, breaking ties by the fewest errors and
+/// then by the first barcode.
+fn best_location(locations: impl Iterator<Item = Option<Location>>) -> Option<(usize, usize)> {
+    let mut best: Option<(usize, usize, usize, usize)> = None;
+    for (idx, loc) in locations.enumerate() {
+        if let Some(loc) = loc {
+            let better = match best {
+                None => true,
+                Some((_, _, matches, errors)) => {
+                    loc.matches() > matches || (loc.matches() == matches && loc.errors() < errors)
+                }
+            };
+            if better {
+                best = Some((idx, loc.querystop(), loc.matches(), loc.errors()));
+            }
+        }
+    }
+    best.map(|(idx, len, _, _)| (idx, len))
+}
+
+#[inline]
+fn normalize(nt: u8) -> u8 {
+    match nt.to_ascii_uppercase() {
+        b'U' => b'T',
+        nt => nt,
+    }
+}
+
+/// Bit mask of the `ACGT` bases that the IUPAC nucleotide `nt`
+/// matches, with `A` as the lowest bit, or 0 for other characters.
+fn iupac_mask(nt: u8) -> u8 {
+    let mut mask = Vec::with_capacity(1);
+    encode_iupac_vec(&[normalize(nt)], &mut mask);
+    mask[0]
+}
+
+/// Upper bound on the number of sequences within `max_errors` of
+/// `sequence`, as found by `environment`, taking each error as any
+/// of 3 mismatches or, with `indels`, also a deletion or any of 4
+/// insertions, and multiplying by the number of sequences matched by
+/// the wildcards.
+pub fn environment_bound(sequence: &[u8], max_errors: usize, indels: bool) -> f64 {
+    let expansions = sequence.iter().map(|&nt| iupac_mask(nt).count_ones() as f64).product::<f64>();
+    let per_error: f64 = if indels { 8.0 } else { 3.0 };
+    let size = (0..=max_errors).map(|errors| {
+        // Positions of the errors among the bases, and the inserted
+        // bases with indels
+        let positions = if indels { sequence.len() + errors } else { sequence.len() };
+        let choose = (0..errors).map(|i| (positions - i) as f64 / (i + 1) as f64).product::<f64>();
+        choose * per_error.powi(errors as i32)
+    }).sum::<f64>();
+    expansions * size
+}
+
+/// Finds every sequence within `max_errors` of `sequence`, counting
+/// mismatches and, when `indels` is set, single-base insertions and
+/// deletions as one error each. Each sequence is reported with the
+/// errors and matches of its best alignment, i.e., the one with the
+/// most matches and then the fewest errors. IUPAC wildcards in
+/// `sequence` match any of their bases.
+///
+/// Insertions after the end of `sequence` are not included, since
+/// the barcode is matched against the start of a longer read.
+pub fn environment(sequence: &[u8], max_errors: usize, indels: bool) -> HashMap<Vec<u8>, (usize, usize)> {
+    let masks = sequence.iter().map(|&nt| iupac_mask(nt)).collect::<Vec<u8>>();
+    let mut env = HashMap::new();
+    let mut current = Vec::with_capacity(sequence.len() + max_errors);
+    extend_environment(&masks, 0, max_errors, indels, &mut current, 0, 0, &mut env);
+    env
+}
+
+#[allow(clippy::too_many_arguments)]
+fn extend_environment(masks: &[u8], pos: usize, errors_left: usize, indels: bool,
+                      current: &mut Vec<u8>, errors: usize, matches: usize,
+                      env: &mut HashMap<Vec<u8>, (usize, usize)>) {
+    if pos == masks.len() {
+        let best = env.entry(current.clone()).or_insert((errors, matches));
+        if (matches, best.0) > (best.1, errors) {
+            *best = (errors, matches);
+        }
+        return;
+    }
+
+    for (bit, &nt) in ACGT.iter().enumerate() {
+        if masks[pos] & (1 << bit) != 0 {
+            current.push(nt);
+            extend_environment(masks, pos + 1, errors_left, indels, current, errors, matches + 1, env);
+            current.pop();
+        }
+    }
+
+    if errors_left == 0 {
+        return;
+    }
+
+    for (bit, &nt) in ACGT.iter().enumerate() {
+        if masks[pos] & (1 << bit) == 0 {
+            current.push(nt);
+            extend_environment(masks, pos + 1, errors_left - 1, indels, current, errors + 1, matches, env);
+            current.pop();
+        }
+    }
+
+    if indels {
+        // Deletion of the barcode base from the read
+        extend_environment(masks, pos + 1, errors_left - 1, indels, current, errors + 1, matches, env);
+
+        // Insertion of a read base before the barcode base
+        for &nt in ACGT.iter() {
+            current.push(nt);
+            extend_environment(masks, pos, errors_left - 1, indels, current, errors + 1, matches, env);
+            current.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn barcode(name: &str, sequence: &str) -> Barcode {
+        Barcode { name: name.to_string(), sequence: sequence.to_string() }
+    }
+
+    #[test]
+    fn hamming_environment() {
+        let env = environment(b"ACGT", 0, false);
+        assert_eq!(env.len(), 1);
+        assert_eq!(env.get(b"ACGT".as_ref()), Some(&(0, 4)));
+
+        let env = environment(b"ACGT", 1, false);
+        assert_eq!(env.len(), 1 + 4 * 3);
+        assert_eq!(env.get(b"ACTT".as_ref()), Some(&(1, 3)));
+
+        let env = environment(b"ACGTAC", 2, false);
+        assert_eq!(env.len(), 1 + 6 * 3 + 15 * 9);
+        assert!(env.keys().all(|s| s.len() == 6));
+    }
+
+    #[test]
+    fn edit_environment() {
+        let env = environment(b"ACGT", 1, true);
+        assert_eq!(env.get(b"ACGT".as_ref()), Some(&(0, 4)));
+        assert_eq!(env.get(b"AGT".as_ref()), Some(&(1, 3)));
+        assert_eq!(env.get(b"ACGGT".as_ref()), Some(&(1, 4)));
+        assert_eq!(env.get(b"TACGT".as_ref()), Some(&(1, 4)));
+        assert!(env.keys().all(|s| s.len() >= 3 && s.len() <= 5));
+    }
+
+    #[test]
+    fn wildcard_environment() {
+        let env = environment(b"ANT", 0, false);
+        assert_eq!(env.len(), 4);
+        assert_eq!(env.get(b"AGT".as_ref()), Some(&(0, 3)));
+
+        let env = environment(b"ART", 1, false);
+        assert_eq!(env.get(b"AGT".as_ref()), Some(&(0, 3)));
+        assert_eq!(env.get(b"ACT".as_ref()), Some(&(1, 2)));
+        assert_eq!(env.len(), 2 + 3 * 2 + 2 + 2 * 3);
+    }
+
+    #[test]
+    fn neighborhood_size() {
+        assert_eq!(environment_bound(b"ACGTAC", 2, false), (1 + 6 * 3 + 15 * 9) as f64);
+        assert_eq!(environment_bound(b"ANGTAC", 0, false), 4.0);
+        assert!(environment_bound(b"ACGT", 1, true) >= environment(b"ACGT", 1, true).len() as f64);
+
+        let barcodes = [barcode("bc1", "ACGTACGTACGT")];
+        assert!(Barcodes::new(&barcodes, 3, true).is_ok());
+        assert!(Barcodes::new(&barcodes, 4, true).is_err());
+        assert!(Barcodes::new(&barcodes, 4, false).is_ok());
+    }
+
+    #[test]
+    fn find_barcodes() {
+        for &indels in [false, true].iter() {
+            let mut barcodes = Barcodes::new(&[barcode("bc1", "ACGTAC"), barcode("bc2", "TTGCAG")], 1, indels).unwrap();
+            assert!(barcodes.collisions().is_empty());
+
+            assert_eq!(barcodes.find(b"ACGTACGGGGGGGG"), Some((0, 6)));
+            assert_eq!(barcodes.find(b"TTGCAGGGGGGGGG"), Some((1, 6)));
+            assert_eq!(barcodes.find(b"ttgcagGGGGGGGG"), Some((1, 6)));
+            assert_eq!(barcodes.find(b"ACCTACGGGGGGGG"), Some((0, 6)));
+            assert_eq!(barcodes.find(b"ACCTTCGGGGGGGG"), None);
+            assert_eq!(barcodes.find(b"GGACGTACGGGGGG"), None);
+            assert_eq!(barcodes.find(b"ACG"), None);
+        }
+
+        let mut barcodes = Barcodes::new(&[barcode("bc1", "ACGTAC"), barcode("bc2", "TTGCAG")], 1, true).unwrap();
+        assert_eq!(barcodes.find(b"ACTACGGGGGGG"), Some((0, 5)));
+        assert_eq!(barcodes.find(b"TTGCCAGGGGGG"), Some((1, 7)));
+    }
+
+    #[test]
+    fn find_wildcard_barcodes() {
+        let mut barcodes = Barcodes::new(&[barcode("bc1", "ACNTAC"), barcode("bc2", "TTGCAG")], 1, false).unwrap();
+
+        assert_eq!(barcodes.find(b"ACTTACGGGGGGGG"), Some((0, 6)));
+        assert_eq!(barcodes.find(b"ACTTTCGGGGGGGG"), Some((0, 6)));
+        assert_eq!(barcodes.find(b"TTGCAGGGGGGGGG"), Some((1, 6)));
+        assert_eq!(barcodes.find(b"TGCAGGGGGGGGGG"), None);
+    }
+
+    #[test]
+    fn barcode_collisions() {
+        let mut barcodes = Barcodes::new(&[barcode("bc1", "AAAA"), barcode("bc2", "AATT")], 1, false).unwrap();
+        assert_eq!(barcodes.collisions(),
+                   &[BarcodeCollision { barcode1: "bc1".to_string(), barcode2: "bc2".to_string(),
+                                        sequences: 2, example: "AAAT".to_string() }]);
+        assert_eq!(barcodes.find(b"AAATGG"), None);
+        assert_eq!(barcodes.find(b"AAACGG"), Some((0, 4)));
+        assert_eq!(barcodes.find(b"AATTGG"), Some((1, 4)));
+
+        assert!(Barcodes::new(&[barcode("bc1", "AATT"), barcode("bc2", "AATT")], 1, false).is_err());
+    }
+
+    #[test]
+    fn wildcard_collisions() {
+        let mut barcodes = Barcodes::new(&[barcode("bc1", "ACNT"), barcode("bc2", "ACGA")], 1, false).unwrap();
+        assert_eq!(barcodes.collisions(),
+                   &[BarcodeCollision { barcode1: "bc1".to_string(), barcode2: "bc2".to_string(),
+                                        sequences: 5, example: "ACAA".to_string() }]);
+        assert_eq!(barcodes.find(b"ACGTGG"), Some((0, 4)));
+
+        assert!(Barcodes::new(&[barcode("bc1", "ACNT"), barcode("bc2", "ACGT")], 1, false).is_err());
+    }
+
+    #[test]
+    fn trim_barcode() {
+        let mut barcodes = Barcodes::new(&[barcode("bc1", "ACGTAC"), barcode("bc2", "TTGCAG")], 0, false).unwrap();
+
+        let mut trimming = Trimming::new(b"read", b"TTGCAGCCCC", None);
+        barcodes.trim(&mut trimming);
+        assert_eq!(trimming.seq_trimmed(), b"CCCC");
+        assert_eq!(trimming.read_tag(), Some(1));
+
+        let mut trimming = Trimming::new(b"read", b"TTGAAGCCCC", None);
+        barcodes.trim(&mut trimming);
+        assert_eq!(trimming.seq_trimmed(), b"TTGAAGCCCC");
+        assert_eq!(trimming.read_tag(), None);
+
+        assert_eq!(barcodes.counts(), &[0, 1]);
+        assert_eq!(barcodes.no_barcode(), 1);
+    }
+
+    #[test]
+    fn header_barcodes() {
+        assert_eq!(header_index(b"r1 1:N:0:ACGTAC+TTGCAG", 0), Some(b"ACGTAC".as_ref()));
+        assert_eq!(header_index(b"r1 1:N:0:ACGTAC+TTGCAG", 1), Some(b"TTGCAG".as_ref()));
+        assert_eq!(header_index(b"r1 1:N:0:ACGTAC", 1), None);
+        assert_eq!(header_index(b"r1:N:0:ACGTAC", 0), None);
+
+        let mut barcodes = Barcodes::new(&[barcode("bc1", "ACGTAC"), barcode("bc2", "TTGCAG")], 1, false).unwrap();
+        barcodes.set_source(BarcodeSource::HeaderIndex2);
+        let mut trimming = Trimming::new(b"r1 1:N:0:ACGTAC+TTGCTG", b"ACGTACCCCC", None);
+        barcodes.trim(&mut trimming);
+        assert_eq!(trimming.seq_trimmed(), b"ACGTACCCCC");
+        assert_eq!(trimming.read_tag(), Some(1));
+    }
+
+    #[test]
+    fn bad_barcodes() {
+        assert!(Barcodes::new(&[], 0, false).is_err());
+        assert!(Barcodes::new(&[barcode("bc1", "ACGT"), barcode("bc1", "TTTT")], 0, false).is_err());
+        assert!(Barcodes::new(&[barcode("bc1", "")], 0, false).is_err());
+        assert!(Barcodes::new(&[barcode("bc1", "ACGT"), barcode("bc2", "TTG")], 3, false).is_err());
+        assert!(Barcodes::new(&[barcode("bc1", "ACNT")], 4, true).is_err());
+        assert!(Barcodes::new(&[barcode("bc1", "ACXT")], 0, false).is_err());
+    }
+}
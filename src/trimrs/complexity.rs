@@ -0,0 +1,237 @@
+//! Low-complexity read filtering and masking.
+//!
+//! Sequence complexity is measured from the counts of overlapping
+//! k-mers, ignoring any k-mer with a base other than `A`, `C`, `G`,
+//! or `T`, in one of two ways:
+//!
+//! * The DUST score is `sum c * (c - 1) / 2` over the k-mer counts
+//!   `c`, divided by one less than the number of k-mers. Homopolymers
+//!   and short repeats have high scores, and a sequence is low
+//!   complexity when its score exceeds the threshold. With k = 3 this
+//!   is the original triplet DUST score.
+//! * The Shannon entropy of the k-mer frequencies, normalized by its
+//!   largest possible value so that it ranges from 0 to 1. A
+//!   sequence is low complexity when its entropy falls below the
+//!   threshold.
+//!
+//! Low-complexity reads are either assigned the `LowComplexity` fate
+//! or, when masking, left in the output with each low-complexity
+//! window replaced by `N`. Windows of `window` bases overlap by half
+//! their length, and the final window is aligned with the end of the
+//! read.
+
+use anyhow::{ensure, Result};
+use serde::{Serialize, Deserialize};
+
+use crate::output::Fate;
+use crate::trimming::Trimming;
+
+const MAX_KMER: usize = 8;
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ComplexityMethod {
+    Dust,
+    Entropy,
+}
+
+#[derive(Clone, Debug)]
+pub struct LowComplexityFilter {
+    method: ComplexityMethod,
+    threshold: f64,
+    kmer: usize,
+    mask_window: Option<usize>,
+    counts: Vec<usize>,
+    filtered: usize,
+    masked: usize,
+}
+
+impl LowComplexityFilter {
+    /// Creates a low-complexity filter, which masks windows of
+    /// `mask_window` bases rather than assigning a fate when
+    /// `mask_window` is given.
+    pub fn new(method: ComplexityMethod, threshold: f64, kmer: usize, mask_window: Option<usize>) -> Result<Self> {
+        ensure!((1..=MAX_KMER).contains(&kmer), "K-mer length must be between 1 and {}", MAX_KMER);
+        if let Some(window) = mask_window {
+            ensure!(window > kmer, "Masking window must be longer than the k-mer length");
+        }
+        Ok(LowComplexityFilter {
+            method,
+            threshold,
+            kmer,
+            mask_window,
+            counts: vec![0; 1 << (2 * kmer)],
+            filtered: 0,
+            masked: 0,
+        })
+    }
+
+    /// Complexity score of `seq`, or `None` when it has no k-mers.
+    pub fn score(&mut self, seq: &[u8]) -> Option<f64> {
+        self.counts.iter_mut().for_each(|c| *c = 0);
+
+        let mask = (1usize << (2 * self.kmer)) - 1;
+        let mut kmer = 0;
+        let mut valid = 0;
+        let mut total = 0;
+        for &nt in seq {
+            let code = match nt {
+                b'A' | b'a' => 0,
+                b'C' | b'c' => 1,
+                b'G' | b'g' => 2,
+                b'T' | b't' => 3,
+                _ => {
+                    valid = 0;
+                    continue;
+                }
+            };
+            kmer = ((kmer << 2) | code) & mask;
+            valid += 1;
+            if valid >= self.kmer {
+                self.counts[kmer] += 1;
+                total += 1;
+            }
+        }
+
+        if total == 0 {
+            return None;
+        }
+
+        Some(match self.method {
+            ComplexityMethod::Dust => {
+                if total == 1 {
+                    0.0
+                } else {
+                    let pairs: usize = self.counts.iter().map(|&c| c * c.saturating_sub(1) / 2).sum();
+                    pairs as f64 / (total - 1) as f64
+                }
+            }
+            ComplexityMethod::Entropy => {
+                let max_entropy = (self.counts.len().min(total) as f64).log2();
+                if max_entropy == 0.0 {
+                    0.0
+                } else {
+                    let entropy: f64 = self.counts.iter()
+                        .filter(|&&c| c > 0)
+                        .map(|&c| { let p = c as f64 / total as f64; -p * p.log2() })
+                        .sum();
+                    entropy / max_entropy
+                }
+            }
+        })
+    }
+
+    /// Whether `seq` is low complexity.
+    pub fn is_low_complexity(&mut self, seq: &[u8]) -> bool {
+        match (self.score(seq), self.method) {
+            (None, _) => false,
+            (Some(score), ComplexityMethod::Dust) => score > self.threshold,
+            (Some(score), ComplexityMethod::Entropy) => score < self.threshold,
+        }
+    }
+
+    pub fn filter(&mut self, trimming: &mut Trimming) {
+        if !trimming.fate().is_output() {
+            return;
+        }
+
+        let seq = trimming.seq_trimmed();
+        match self.mask_window {
+            None => {
+                if self.is_low_complexity(seq) {
+                    self.filtered += 1;
+                    trimming.set_fate(Fate::LowComplexity);
+                }
+            }
+            Some(window) => {
+                let mut any_masked = false;
+                for (start, stop) in windows(seq.len(), window) {
+                    if self.is_low_complexity(&seq[start..stop]) {
+                        trimming.mask_trimmed(start, stop);
+                        any_masked = true;
+                    }
+                }
+                if any_masked {
+                    self.masked += 1;
+                }
+            }
+        }
+    }
+
+    /// Number of reads assigned a fate by the filter
+    pub fn filtered(&self) -> usize {
+        self.filtered
+    }
+
+    /// Number of reads with masked windows
+    pub fn masked(&self) -> usize {
+        self.masked
+    }
+
+    /// Adds the statistics of `other`, a copy of this filter.
+    pub fn merge(&mut self, other: &Self) {
+        self.filtered += other.filtered;
+        self.masked += other.masked;
+    }
+}
+
+/// Windows of length `window` in a sequence of length `len`,
+/// overlapping by half their length, with a final window ending at
+/// the end of the sequence.
+fn windows(len: usize, window: usize) -> impl Iterator<Item = (usize, usize)> {
+    let step = (window / 2).max(1);
+    let last_start = len.saturating_sub(window);
+    let regular = (0..last_start).step_by(step).map(move |start| (start, start + window));
+    regular.chain(std::iter::once((last_start, len)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dust_score() {
+        let mut filter = LowComplexityFilter::new(ComplexityMethod::Dust, 2.0, 3, None).unwrap();
+        assert_eq!(filter.score(b"AAAAAA"), Some(6.0 / 3.0));
+        assert_eq!(filter.score(b"ACGTTG"), Some(0.0));
+        assert_eq!(filter.score(b"AC"), None);
+        assert!(filter.is_low_complexity(b"AAAAAAAAAAAAAAAAAAAA"));
+        assert!(filter.is_low_complexity(b"CACACACACACACACACACA"));
+        assert!(!filter.is_low_complexity(b"ACGTTGCAAGCTTAGCGATC"));
+    }
+
+    #[test]
+    fn entropy_score() {
+        let mut filter = LowComplexityFilter::new(ComplexityMethod::Entropy, 0.5, 1, None).unwrap();
+        assert_eq!(filter.score(b"AAAA"), Some(0.0));
+        assert_eq!(filter.score(b"ACGT"), Some(1.0));
+        assert_eq!(filter.score(b"AACC"), Some(0.5));
+        assert!(filter.is_low_complexity(b"AAAAAAAAAAAAAAAAAAAC"));
+        assert!(!filter.is_low_complexity(b"ACGTTGCAAGCTTAGCGATC"));
+    }
+
+    #[test]
+    fn filter_and_mask() {
+        let mut filter = LowComplexityFilter::new(ComplexityMethod::Dust, 2.0, 3, None).unwrap();
+        let mut trimming = Trimming::new(b"read", b"AAAAAAAAAAAAAAAAAAAA", None);
+        filter.filter(&mut trimming);
+        assert_eq!(trimming.fate(), &Fate::LowComplexity);
+        assert_eq!(filter.filtered(), 1);
+
+        let mut filter = LowComplexityFilter::new(ComplexityMethod::Dust, 1.5, 3, Some(8)).unwrap();
+        let mut trimming = Trimming::new(b"read", b"ACGTTGCAAGCTTAAAAAAAAAAAA", None);
+        filter.filter(&mut trimming);
+        assert_eq!(trimming.fate(), &Fate::Output);
+        let mut masked = Vec::new();
+        trimming.seq_trimmed_masked(&mut masked);
+        assert_eq!(masked, b"ACGTTGCAAGCTNNNNNNNNNNNNN".to_vec());
+        assert_eq!(filter.masked(), 1);
+    }
+
+    #[test]
+    fn sequence_windows() {
+        assert_eq!(windows(10, 4).collect::<Vec<_>>(), vec![(0, 4), (2, 6), (4, 8), (6, 10)]);
+        assert_eq!(windows(9, 4).collect::<Vec<_>>(), vec![(0, 4), (2, 6), (4, 8), (5, 9)]);
+        assert_eq!(windows(3, 4).collect::<Vec<_>>(), vec![(0, 3)]);
+    }
+}
@@ -0,0 +1,263 @@
+//! Screening of reads against contaminant sequences, such as rRNA
+//! and tRNA, by shared k-mers.
+//!
+//! All k-mers of the contaminant sequences are collected into a set
+//! when the filter is created. A read is a contaminant when enough of
+//! the k-mers in its trimmed sequence are in the set, and the hit is
+//! attributed to the contaminant sharing the most k-mers with the
+//! read. K-mers found in more than one contaminant count towards a
+//! hit but not towards its attribution, so a read sharing only such
+//! k-mers is an ambiguous hit. K-mers with a base other than `A`, `C`, `G`, or `T` are
+//! ignored. Unless restricted to the forward strand, k-mers are
+//! compared in canonical form, so that reads from either strand of a
+//! contaminant are found.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::{ensure, Result};
+use serde::{Serialize, Deserialize};
+
+use crate::output::Fate;
+use crate::trimming::Trimming;
+
+const MAX_KMER: usize = 32;
+
+/// Marks a k-mer shared by more than one contaminant
+const SHARED: usize = usize::MAX;
+
+#[derive(Clone, Debug)]
+pub struct ContaminantFilter {
+    kmer: usize,
+    both_strands: bool,
+    min_kmers: usize,
+    min_fraction: Option<f64>,
+    /// Contaminant index of each k-mer, shared between clones
+    kmers: Arc<HashMap<u64, usize>>,
+    hits: Vec<usize>,
+    stats: ContaminantStats,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ContaminantStats {
+    /// Contaminant names, in the order of `reads`
+    pub names: Vec<String>,
+    /// Number of reads attributed to each contaminant
+    pub reads: Vec<usize>,
+    /// Number of contaminant reads sharing k-mers only with more than
+    /// one contaminant
+    pub ambiguous: usize,
+}
+
+/// Contaminant found in a read
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ContaminantHit {
+    /// Index of the contaminant sharing the most k-mers with the read
+    Contaminant(usize),
+    /// Every k-mer found is shared by more than one contaminant
+    Ambiguous,
+}
+
+impl ContaminantStats {
+    pub fn merge(&mut self, other: &ContaminantStats) {
+        for (reads, other_reads) in self.reads.iter_mut().zip(other.reads.iter()) {
+            *reads += *other_reads;
+        }
+        self.ambiguous += other.ambiguous;
+    }
+
+    /// Total number of contaminant reads, including ambiguous ones
+    pub fn total(&self) -> usize {
+        self.reads.iter().sum::<usize>() + self.ambiguous
+    }
+}
+
+impl ContaminantFilter {
+    /// Creates a filter from named contaminant sequences. A read is a
+    /// contaminant when at least `min_kmers` of its k-mers, and at
+    /// least `min_fraction` of them if given, are contaminant k-mers.
+    pub fn new(contaminants: &[(String, Vec<u8>)], kmer: usize, both_strands: bool,
+               min_kmers: usize, min_fraction: Option<f64>) -> Result<Self> {
+        ensure!((1..=MAX_KMER).contains(&kmer), "K-mer length must be between 1 and {}", MAX_KMER);
+        ensure!(min_kmers >= 1, "Minimum k-mer count must be at least 1");
+        ensure!(!contaminants.is_empty(), "No contaminant sequences");
+
+        let mut kmers = HashMap::new();
+        for (idx, (_, seq)) in contaminants.iter().enumerate() {
+            for_each_kmer(seq, kmer, both_strands, |code| {
+                let owner = kmers.entry(code).or_insert(idx);
+                if *owner != idx {
+                    *owner = SHARED;
+                }
+            });
+        }
+
+        let names = contaminants.iter().map(|(name, _)| name.clone()).collect::<Vec<String>>();
+        Ok(ContaminantFilter {
+            kmer,
+            both_strands,
+            min_kmers,
+            min_fraction,
+            kmers: Arc::new(kmers),
+            hits: vec![0; names.len()],
+            stats: ContaminantStats { reads: vec![0; names.len()], names, ambiguous: 0 },
+        })
+    }
+
+    /// Number of distinct contaminant k-mers
+    pub fn kmer_count(&self) -> usize {
+        self.kmers.len()
+    }
+
+    /// Finds the contaminant matching `seq`, if any.
+    pub fn find(&mut self, seq: &[u8]) -> Option<ContaminantHit> {
+        self.hits.iter_mut().for_each(|h| *h = 0);
+        let mut total = 0;
+        let mut shared = 0;
+        let kmers = &self.kmers;
+        let hits = &mut self.hits;
+        for_each_kmer(seq, self.kmer, self.both_strands, |code| {
+            total += 1;
+            match kmers.get(&code) {
+                Some(&SHARED) => shared += 1,
+                Some(&idx) => hits[idx] += 1,
+                None => (),
+            }
+        });
+
+        let found = shared + self.hits.iter().sum::<usize>();
+        if found < self.min_kmers || self.min_fraction.is_some_and(|min| (found as f64) < min * total as f64) {
+            return None;
+        }
+
+        // K-mers shared between contaminants do not attribute the hit.
+        let mut best = 0;
+        for (idx, &hits) in self.hits.iter().enumerate() {
+            if hits > self.hits[best] {
+                best = idx;
+            }
+        }
+        if self.hits[best] == 0 {
+            Some(ContaminantHit::Ambiguous)
+        } else {
+            Some(ContaminantHit::Contaminant(best))
+        }
+    }
+
+    pub fn filter(&mut self, trimming: &mut Trimming) {
+        if !trimming.fate().is_output() {
+            return;
+        }
+
+        if let Some(hit) = self.find(trimming.seq_trimmed()) {
+            match hit {
+                ContaminantHit::Contaminant(idx) => self.stats.reads[idx] += 1,
+                ContaminantHit::Ambiguous => self.stats.ambiguous += 1,
+            }
+            trimming.set_fate(Fate::Contaminant);
+        }
+    }
+
+    pub fn stats(&self) -> &ContaminantStats {
+        &self.stats
+    }
+
+    /// Adds the statistics of `other`, a copy of this filter.
+    pub fn merge(&mut self, other: &Self) {
+        self.stats.merge(&other.stats);
+    }
+}
+
+/// Calls `f` with the 2-bit encoding of each k-mer in `seq` having
+/// only `ACGT` bases, in canonical form when `both_strands` is set.
+fn for_each_kmer<F: FnMut(u64)>(seq: &[u8], kmer: usize, both_strands: bool, mut f: F) {
+    let mask = if kmer == MAX_KMER { u64::MAX } else { (1u64 << (2 * kmer)) - 1 };
+    let rc_shift = 2 * (kmer as u64 - 1);
+    let mut fwd = 0u64;
+    let mut rev = 0u64;
+    let mut valid = 0;
+    for &nt in seq {
+        let code = match nt {
+            b'A' | b'a' => 0,
+            b'C' | b'c' => 1,
+            b'G' | b'g' => 2,
+            b'T' | b't' | b'U' | b'u' => 3,
+            _ => {
+                valid = 0;
+                continue;
+            }
+        };
+        fwd = ((fwd << 2) | code) & mask;
+        rev = (rev >> 2) | ((3 - code) << rc_shift);
+        valid += 1;
+        if valid >= kmer {
+            f(if both_strands { fwd.min(rev) } else { fwd });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contaminants() -> Vec<(String, Vec<u8>)> {
+        vec![("rRNA".to_string(), b"GTACCTGGTTGATCCTGCCAGTAGCATATGCTTGTCTCAAAGATTAAGCCATGCATG".to_vec()),
+             ("tRNA".to_string(), b"GCATTGGTGGTTCAGTGGTAGAATTCTCGCCTGCCACGCGGGAGGCCCGGGTTCGATT".to_vec())]
+    }
+
+    #[test]
+    fn kmer_encoding() {
+        let mut fwd = Vec::new();
+        for_each_kmer(b"ACGTNACG", 3, false, |code| fwd.push(code));
+        assert_eq!(fwd, vec![0b000110, 0b011011, 0b000110]);
+
+        let mut canonical = Vec::new();
+        for_each_kmer(b"AAC", 3, true, |code| canonical.push(code));
+        for_each_kmer(b"GTT", 3, true, |code| canonical.push(code));
+        assert_eq!(canonical, vec![0b000001, 0b000001]);
+    }
+
+    #[test]
+    fn find_contaminants() {
+        let mut filter = ContaminantFilter::new(&contaminants(), 12, true, 2, None).unwrap();
+        assert_eq!(filter.find(b"TTGATCCTGCCAGTAGCATA"), Some(ContaminantHit::Contaminant(0)));
+        assert_eq!(filter.find(b"TATGCTACTGGCAGGATCAA"), Some(ContaminantHit::Contaminant(0)));
+        assert_eq!(filter.find(b"GTAGAATTCTCGCCTGCCAC"), Some(ContaminantHit::Contaminant(1)));
+        assert_eq!(filter.find(b"ACGTACGTACGTACGTACGT"), None);
+        assert_eq!(filter.find(b"AAAAAAAAGTACCTGGTTGA"), None);
+
+        let mut forward = ContaminantFilter::new(&contaminants(), 12, false, 2, None).unwrap();
+        assert_eq!(forward.find(b"TATGCTACTGGCAGGATCAA"), None);
+
+        let mut fraction = ContaminantFilter::new(&contaminants(), 12, true, 1, Some(0.5)).unwrap();
+        assert_eq!(fraction.find(b"TTGATCCTGCCAGTAGCATA"), Some(ContaminantHit::Contaminant(0)));
+        assert_eq!(fraction.find(b"CCCCCCCCCTTGATCCTGCCAG"), None);
+    }
+
+    #[test]
+    fn filter_contaminants() {
+        let mut filter = ContaminantFilter::new(&contaminants(), 12, true, 2, None).unwrap();
+        for seq in [&b"GTAGAATTCTCGCCTGCCAC"[..], &b"ACGTACGTACGTACGTACGT"[..]].iter() {
+            let mut trimming = Trimming::new(b"read", seq, None);
+            filter.filter(&mut trimming);
+        }
+        assert_eq!(filter.stats().reads, vec![0, 1]);
+        assert_eq!(filter.stats().total(), 1);
+    }
+
+    #[test]
+    fn shared_kmers() {
+        let shared = b"TTGATCCTGCCAGTAGCATA";
+        let contaminants = vec![("rRNA1".to_string(), [&b"GGGGGGGGGGGG"[..], shared].concat()),
+                                ("rRNA2".to_string(), [shared, &b"CCACCACCACCA"[..]].concat())];
+        let mut filter = ContaminantFilter::new(&contaminants, 12, true, 2, None).unwrap();
+        assert_eq!(filter.find(shared), Some(ContaminantHit::Ambiguous));
+        assert_eq!(filter.find(b"TGCCAGTAGCATACCACCACC"), Some(ContaminantHit::Contaminant(1)));
+
+        let mut trimming = Trimming::new(b"read", shared, None);
+        filter.filter(&mut trimming);
+        assert_eq!(trimming.fate(), &Fate::Contaminant);
+        assert_eq!((filter.stats().reads.clone(), filter.stats().ambiguous), (vec![0, 0], 1));
+        assert_eq!(filter.stats().total(), 1);
+    }
+}
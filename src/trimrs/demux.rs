@@ -0,0 +1,452 @@
+//! Combinatorial demultiplexing of read pairs by the barcodes found
+//! on read 1 and read 2.
+//!
+//! Each combination of a read 1 barcode and a read 2 barcode is
+//! written to its own pair of output files. The output paths are
+//! formed from templates by replacing `{name1}` and `{name2}` with
+//! the barcode names. Files are created when the first read pair is
+//! written to them. Each combination must have its own paths, except
+//! that unknown pairs may share theirs, and the unknown name cannot
+//! also be a barcode name.
+//!
+//! Pairs where either read lacks a barcode, or whose combination is
+//! not among the selected combinations, are _unknown_. They are
+//! written using the unknown name in place of the missing barcode
+//! name, or discarded when no unknown name is given.
+//!
+//! Selected combinations may also be given a sample name, which
+//! replaces `{sample}` in the templates. The sample name of any other
+//! combination is its two barcode names joined by `-`, and the
+//! sample name of an unknown pair is the unknown name.
+//!
+//! Runs demultiplex read pairs with the `demultiplex` section of the
+//! run configuration, using the barcodes of the read 1 and read 2
+//! pipelines or those of a sample sheet.
+//!
+//! This follows `CombinatorialDemultiplexer` in `Cutadapt`.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use anyhow::{bail, ensure, Result};
+use serde::{Serialize, Deserialize};
+
+use crate::output::write_trimmed;
+use crate::samplesheet::SampleSheetConf;
+use crate::trimming::Trimming;
+
+const NAME1: &str = "{name1}";
+const NAME2: &str = "{name2}";
+const SAMPLE: &str = "{sample}";
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Serialize, Deserialize)]
+pub struct CombinatorialDemuxConf {
+    /// Output path template for read 1
+    pub output: String,
+
+    /// Output path template for read 2
+    pub paired_output: String,
+
+    /// Name substituted for a missing barcode, or `None` to discard
+    /// unknown pairs
+    #[serde(default)]
+    pub unknown_name: Option<String>,
+
+    /// Combinations of read 1 and read 2 barcode names to write, or
+    /// `None` to write all combinations
+    #[serde(default)]
+    pub combinations: Option<Vec<Combination>>,
+
+    /// Sample sheet giving the barcodes, found in the read headers,
+    /// and the combinations, in place of barcodes in the pipelines
+    #[serde(default)]
+    pub sample_sheet: Option<SampleSheetConf>,
+}
+
+/// A combination of a read 1 and a read 2 barcode, with an optional
+/// sample name.
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Serialize, Deserialize)]
+pub struct Combination {
+    pub name1: String,
+    pub name2: String,
+    #[serde(default)]
+    pub sample: Option<String>,
+}
+
+impl CombinatorialDemuxConf {
+    pub fn demultiplexer(&self, names1: &[String], names2: &[String]) -> Result<CombinatorialDemultiplexer> {
+        CombinatorialDemultiplexer::new(self, names1, names2)
+    }
+}
+
+type Key = (Option<usize>, Option<usize>);
+
+pub struct CombinatorialDemultiplexer {
+    template1: String,
+    template2: String,
+    unknown_name: Option<String>,
+    selected: Option<HashSet<(usize, usize)>>,
+    samples: HashMap<(usize, usize), String>,
+    /// Index into `outputs` for each combination written so far
+    writers: HashMap<Key, usize>,
+    /// Writers for each pair of output paths, which unknown
+    /// combinations may share
+    outputs: Vec<(BufWriter<File>, BufWriter<File>)>,
+    output_paths: HashMap<(String, String), usize>,
+    counts: CombinationCounts,
+}
+
+impl CombinatorialDemultiplexer {
+    /// Creates a demultiplexer for read tags that index `names1` on
+    /// read 1 and `names2` on read 2.
+    pub fn new(conf: &CombinatorialDemuxConf, names1: &[String], names2: &[String]) -> Result<Self> {
+        for template in [&conf.output, &conf.paired_output].iter() {
+            ensure!((template.contains(NAME1) && template.contains(NAME2)) || template.contains(SAMPLE),
+                    "Output template {:?} must contain both {} and {}, or {}", template, NAME1, NAME2, SAMPLE);
+        }
+
+        let mut samples = HashMap::new();
+        let selected = match &conf.combinations {
+            None => None,
+            Some(combinations) => {
+                let mut selected = HashSet::new();
+                for combination in combinations.iter() {
+                    let idx1 = match names1.iter().position(|n| n == &combination.name1) {
+                        Some(idx1) => idx1,
+                        None => bail!("Unknown read 1 barcode {:?} in combination", combination.name1),
+                    };
+                    let idx2 = match names2.iter().position(|n| n == &combination.name2) {
+                        Some(idx2) => idx2,
+                        None => bail!("Unknown read 2 barcode {:?} in combination", combination.name2),
+                    };
+                    ensure!(selected.insert((idx1, idx2)),
+                            "Duplicate combination {:?} and {:?}", combination.name1, combination.name2);
+                    if let Some(sample) = &combination.sample {
+                        samples.insert((idx1, idx2), sample.clone());
+                    }
+                }
+                Some(selected)
+            }
+        };
+
+        if let Some(unknown_name) = &conf.unknown_name {
+            ensure!(!names1.contains(unknown_name) && !names2.contains(unknown_name),
+                    "Unknown name {:?} is also a barcode name", unknown_name);
+        }
+
+        let demux = CombinatorialDemultiplexer {
+            template1: conf.output.clone(),
+            template2: conf.paired_output.clone(),
+            unknown_name: conf.unknown_name.clone(),
+            selected,
+            samples,
+            writers: HashMap::new(),
+            outputs: Vec::new(),
+            output_paths: HashMap::new(),
+            counts: CombinationCounts::new(names1, names2),
+        };
+        demux.check_paths()?;
+        Ok(demux)
+    }
+
+    /// Checks that every combination that can be written has its own
+    /// output paths, except that unknown combinations may share
+    /// theirs, e.g., when the paths are formed from the sample name
+    /// alone.
+    fn check_paths(&self) -> Result<()> {
+        let idxs1 = (0..self.counts.names1.len()).map(Some).chain(std::iter::once(None));
+        let keys: Vec<Key> = idxs1
+            .flat_map(|idx1| (0..self.counts.names2.len()).map(Some).chain(std::iter::once(None))
+                      .map(move |idx2| (idx1, idx2)))
+            .filter(|&key| self.key(key.0, key.1) == key)
+            .collect();
+        let unknown = |key: Key| key.0.is_none() || key.1.is_none();
+        let mut keys_by_path: HashMap<String, (Key, usize)> = HashMap::new();
+        for key in keys {
+            if let Some((path1, path2)) = self.paths(key) {
+                ensure!(path1 != path2, "Output path {:?} is used for both reads", path1);
+                for (read, path) in [path1, path2].iter().enumerate() {
+                    if let Some((other, other_read)) = keys_by_path.insert(path.clone(), (key, read)) {
+                        ensure!(unknown(key) && unknown(other) && read == other_read,
+                                "Output path {:?} is used by more than one combination", path);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Combination for a pair of read tags, with `None` marking an
+    /// unknown barcode.
+    pub fn key(&self, tag1: Option<usize>, tag2: Option<usize>) -> Key {
+        match (tag1, tag2, &self.selected) {
+            (Some(idx1), Some(idx2), Some(selected)) if !selected.contains(&(idx1, idx2)) => (None, None),
+            _ => (tag1, tag2),
+        }
+    }
+
+    /// Output paths for a combination, or `None` when the pair is
+    /// discarded.
+    pub fn paths(&self, key: Key) -> Option<(String, String)> {
+        let name1 = match key.0 {
+            Some(idx1) => &self.counts.names1[idx1],
+            None => self.unknown_name.as_ref()?,
+        };
+        let name2 = match key.1 {
+            Some(idx2) => &self.counts.names2[idx2],
+            None => self.unknown_name.as_ref()?,
+        };
+        let sample = match key {
+            (Some(idx1), Some(idx2)) => match self.samples.get(&(idx1, idx2)) {
+                Some(sample) => sample.clone(),
+                None => format!("{}-{}", name1, name2),
+            },
+            _ => self.unknown_name.clone()?,
+        };
+        let make_path = |template: &str| template.replace(NAME1, name1).replace(NAME2, name2).replace(SAMPLE, &sample);
+        Some((make_path(&self.template1), make_path(&self.template2)))
+    }
+
+    /// Writes a read pair according to the read tags of the two
+    /// reads. Pairs are skipped unless both reads have an `Output`
+    /// fate.
+    ///
+    /// Returns `true` when the pair was written.
+    pub fn write(&mut self, trimming1: &Trimming, trimming2: &Trimming) -> io::Result<bool> {
+        if !trimming1.fate().is_output() || !trimming2.fate().is_output() {
+            return Ok(false);
+        }
+
+        let key = self.key(trimming1.read_tag(), trimming2.read_tag());
+        self.counts.add(key, 1);
+        let (writer1, writer2) = match self.output(key)? {
+            Some(output) => output,
+            None => return Ok(false),
+        };
+        write_trimmed(writer1, trimming1)?;
+        write_trimmed(writer2, trimming2)?;
+        Ok(true)
+    }
+
+    /// Writes `pairs` read pairs with `Output` fate that were already
+    /// formatted into `records1` and `records2`, all with the read
+    /// tags `tags`, as when the pairs are processed on other threads.
+    pub fn write_raw(&mut self, tags: Key, records1: &[u8], records2: &[u8], pairs: usize) -> io::Result<()> {
+        let key = self.key(tags.0, tags.1);
+        self.counts.add(key, pairs);
+        if let Some((writer1, writer2)) = self.output(key)? {
+            writer1.write_all(records1)?;
+            writer2.write_all(records2)?;
+        }
+        Ok(())
+    }
+
+    /// Writers for a combination, creating its files when first
+    /// needed, or `None` when the pair is discarded.
+    fn output(&mut self, key: Key) -> io::Result<Option<&mut (BufWriter<File>, BufWriter<File>)>> {
+        let output = match self.writers.get(&key) {
+            Some(&output) => output,
+            None => {
+                let paths = match self.paths(key) {
+                    Some(paths) => paths,
+                    None => return Ok(None),
+                };
+                let output = match self.output_paths.get(&paths) {
+                    Some(&output) => output,
+                    None => {
+                        let writer1 = BufWriter::new(File::create(&paths.0)?);
+                        let writer2 = BufWriter::new(File::create(&paths.1)?);
+                        self.outputs.push((writer1, writer2));
+                        self.output_paths.insert(paths, self.outputs.len() - 1);
+                        self.outputs.len() - 1
+                    }
+                };
+                self.writers.insert(key, output);
+                output
+            }
+        };
+        Ok(Some(&mut self.outputs[output]))
+    }
+
+    pub fn counts(&self) -> &CombinationCounts {
+        &self.counts
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        for (writer1, writer2) in self.outputs.iter_mut() {
+            writer1.flush()?;
+            writer2.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Table of read pair counts for each combination of read 1 and
+/// read 2 barcodes.
+///
+/// Counts are indexed by read 1 barcode and then read 2 barcode,
+/// with one extra final row and column for unknown barcodes.
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct CombinationCounts {
+    names1: Vec<String>,
+    names2: Vec<String>,
+    counts: Vec<Vec<usize>>,
+}
+
+impl CombinationCounts {
+    pub fn new(names1: &[String], names2: &[String]) -> Self {
+        CombinationCounts {
+            names1: names1.to_vec(),
+            names2: names2.to_vec(),
+            counts: vec![vec![0; names2.len() + 1]; names1.len() + 1],
+        }
+    }
+
+    fn add(&mut self, key: Key, pairs: usize) {
+        let idx1 = key.0.unwrap_or(self.names1.len());
+        let idx2 = key.1.unwrap_or(self.names2.len());
+        self.counts[idx1][idx2] += pairs;
+    }
+
+    /// Number of read pairs with a combination of barcodes, with
+    /// `None` for an unknown barcode.
+    pub fn count(&self, idx1: Option<usize>, idx2: Option<usize>) -> usize {
+        self.counts[idx1.unwrap_or(self.names1.len())][idx2.unwrap_or(self.names2.len())]
+    }
+
+    pub fn total(&self) -> usize {
+        self.counts.iter().map(|row| row.iter().sum::<usize>()).sum()
+    }
+}
+
+/// Writes the table with one row per read 1 barcode and one column
+/// per read 2 barcode, separated by tabs.
+impl fmt::Display for CombinationCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "R1\\R2")?;
+        for name2 in self.names2.iter() {
+            write!(f, "\t{}", name2)?;
+        }
+        writeln!(f, "\tunknown")?;
+
+        let row_names = self.names1.iter().map(String::as_str).chain(std::iter::once("unknown"));
+        for (name1, row) in row_names.zip(self.counts.iter()) {
+            write!(f, "{}", name1)?;
+            for count in row.iter() {
+                write!(f, "\t{}", count)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    fn combination(name1: &str, name2: &str, sample: Option<&str>) -> Combination {
+        Combination { name1: name1.to_string(), name2: name2.to_string(), sample: sample.map(str::to_string) }
+    }
+
+    fn conf(unknown_name: Option<&str>, combinations: Option<&[Combination]>) -> CombinatorialDemuxConf {
+        CombinatorialDemuxConf {
+            output: "out-{name1}-{name2}.1.fastq".to_string(),
+            paired_output: "out-{name1}-{name2}.2.fastq".to_string(),
+            unknown_name: unknown_name.map(str::to_string),
+            combinations: combinations.map(<[Combination]>::to_vec),
+            sample_sheet: None,
+        }
+    }
+
+    #[test]
+    fn combination_paths() {
+        let demux = CombinatorialDemultiplexer::new(&conf(Some("unknown"), None),
+                                                    &names(&["A1", "A2"]), &names(&["B1"])).unwrap();
+        assert_eq!(demux.paths(demux.key(Some(1), Some(0))),
+                   Some(("out-A2-B1.1.fastq".to_string(), "out-A2-B1.2.fastq".to_string())));
+        assert_eq!(demux.paths(demux.key(None, Some(0))),
+                   Some(("out-unknown-B1.1.fastq".to_string(), "out-unknown-B1.2.fastq".to_string())));
+
+        let demux = CombinatorialDemultiplexer::new(&conf(None, None),
+                                                    &names(&["A1", "A2"]), &names(&["B1"])).unwrap();
+        assert_eq!(demux.paths(demux.key(Some(0), None)), None);
+    }
+
+    #[test]
+    fn selected_combinations() {
+        let demux = CombinatorialDemultiplexer::new(&conf(None, Some(&[combination("A2", "B1", None)])),
+                                                    &names(&["A1", "A2"]), &names(&["B1", "B2"])).unwrap();
+        assert_eq!(demux.key(Some(1), Some(0)), (Some(1), Some(0)));
+        assert_eq!(demux.key(Some(1), Some(1)), (None, None));
+        assert_eq!(demux.paths(demux.key(Some(1), Some(1))), None);
+
+        assert!(CombinatorialDemultiplexer::new(&conf(None, Some(&[combination("A3", "B1", None)])),
+                                                &names(&["A1", "A2"]), &names(&["B1", "B2"])).is_err());
+    }
+
+    #[test]
+    fn sample_paths() {
+        let mut sample_conf = conf(Some("unknown"), Some(&[combination("A1", "B2", Some("S1")),
+                                                          combination("A2", "B1", None)]));
+        sample_conf.output = "{sample}_R1.fastq".to_string();
+        sample_conf.paired_output = "{sample}_R2.fastq".to_string();
+        let demux = CombinatorialDemultiplexer::new(&sample_conf, &names(&["A1", "A2"]), &names(&["B1", "B2"])).unwrap();
+        assert_eq!(demux.paths(demux.key(Some(0), Some(1))),
+                   Some(("S1_R1.fastq".to_string(), "S1_R2.fastq".to_string())));
+        assert_eq!(demux.paths(demux.key(Some(1), Some(0))),
+                   Some(("A2-B1_R1.fastq".to_string(), "A2-B1_R2.fastq".to_string())));
+        assert_eq!(demux.paths(demux.key(Some(0), Some(0))),
+                   Some(("unknown_R1.fastq".to_string(), "unknown_R2.fastq".to_string())));
+    }
+
+    #[test]
+    fn colliding_paths() {
+        assert!(CombinatorialDemultiplexer::new(&conf(Some("A1"), None), &names(&["A1", "A2"]), &names(&["B1"])).is_err());
+        assert!(CombinatorialDemultiplexer::new(&conf(Some("B1"), None), &names(&["A1", "A2"]), &names(&["B1"])).is_err());
+
+        // Barcode names that only differ where the template joins
+        // them give the same paths.
+        let joined = |unknown_name| CombinatorialDemuxConf {
+            output: "out-{name1}{name2}.1.fastq".to_string(),
+            paired_output: "out-{name1}{name2}.2.fastq".to_string(),
+            ..conf(unknown_name, None)
+        };
+        assert!(CombinatorialDemultiplexer::new(&joined(None), &names(&["A", "AB"]), &names(&["BC", "C"])).is_err());
+        assert!(CombinatorialDemultiplexer::new(&joined(None), &names(&["A", "AB"]), &names(&["B", "C"])).is_ok());
+
+        let mut same_sample = conf(None, Some(&[combination("A1", "B1", Some("S1")), combination("A2", "B1", Some("S1"))]));
+        same_sample.output = "{sample}_R1.fastq".to_string();
+        same_sample.paired_output = "{sample}_R2.fastq".to_string();
+        assert!(CombinatorialDemultiplexer::new(&same_sample, &names(&["A1", "A2"]), &names(&["B1"])).is_err());
+
+        let mut same_file = conf(None, None);
+        same_file.paired_output = same_file.output.clone();
+        assert!(CombinatorialDemultiplexer::new(&same_file, &names(&["A1"]), &names(&["B1"])).is_err());
+    }
+
+    #[test]
+    fn bad_template() {
+        let mut bad = conf(None, None);
+        bad.paired_output = "out-{name1}.2.fastq".to_string();
+        assert!(CombinatorialDemultiplexer::new(&bad, &names(&["A1"]), &names(&["B1"])).is_err());
+    }
+
+    #[test]
+    fn count_table() {
+        let mut counts = CombinationCounts::new(&names(&["A1", "A2"]), &names(&["B1"]));
+        counts.add((Some(0), Some(0)), 2);
+        counts.add((Some(1), None), 1);
+        assert_eq!(counts.count(Some(0), Some(0)), 2);
+        assert_eq!(counts.count(Some(1), None), 1);
+        assert_eq!(counts.total(), 3);
+        assert_eq!(counts.to_string(),
+                   "R1\\R2\tB1\tunknown\nA1\t2\t0\nA2\t0\t1\nunknown\t0\t0\n");
+    }
+}
@@ -40,6 +40,71 @@ pub fn decode_iupac_vec(mask: &[u8], nts: &mut Vec<u8>) {
     }
 }
 
+/// Counts the positions where encoded masks `a` and `b` do not match,
+/// i.e., where their bitwise and is zero, over the length of the
+/// shorter one.
+///
+/// Uses AVX2 or SSE2 instructions when available, and scalar code
+/// otherwise.
+pub fn count_mismatches(a: &[u8], b: &[u8]) -> usize {
+    let len = a.len().min(b.len());
+    let (a, b) = (&a[..len], &b[..len]);
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") {
+            // SAFETY: AVX2 support was just checked.
+            return unsafe { x86::count_mismatches_avx2(a, b) };
+        }
+        // SAFETY: SSE2 is part of the x86_64 baseline.
+        unsafe { x86::count_mismatches_sse2(a, b) }
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    count_mismatches_scalar(a, b)
+}
+
+fn count_mismatches_scalar(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b.iter()).filter(|(&x, &y)| x & y == 0).count()
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    use super::count_mismatches_scalar;
+
+    /// Requires AVX2, with `a` and `b` of equal length.
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn count_mismatches_avx2(a: &[u8], b: &[u8]) -> usize {
+        let zero = _mm256_setzero_si256();
+        let mut mismatches = 0;
+        let chunks = a.len() / 32;
+        for chunk in 0..chunks {
+            let x = _mm256_loadu_si256(a.as_ptr().add(32 * chunk) as *const __m256i);
+            let y = _mm256_loadu_si256(b.as_ptr().add(32 * chunk) as *const __m256i);
+            let unmatched = _mm256_cmpeq_epi8(_mm256_and_si256(x, y), zero);
+            mismatches += (_mm256_movemask_epi8(unmatched) as u32).count_ones() as usize;
+        }
+        mismatches + count_mismatches_sse2(&a[32 * chunks..], &b[32 * chunks..])
+    }
+
+    /// Requires `a` and `b` of equal length.
+    #[target_feature(enable = "sse2")]
+    pub(super) unsafe fn count_mismatches_sse2(a: &[u8], b: &[u8]) -> usize {
+        let zero = _mm_setzero_si128();
+        let mut mismatches = 0;
+        let chunks = a.len() / 16;
+        for chunk in 0..chunks {
+            let x = _mm_loadu_si128(a.as_ptr().add(16 * chunk) as *const __m128i);
+            let y = _mm_loadu_si128(b.as_ptr().add(16 * chunk) as *const __m128i);
+            let unmatched = _mm_cmpeq_epi8(_mm_and_si128(x, y), zero);
+            mismatches += (_mm_movemask_epi8(unmatched) as u32).count_ones() as usize;
+        }
+        mismatches + count_mismatches_scalar(&a[16 * chunks..], &b[16 * chunks..])
+    }
+}
+
 #[inline]
 fn encode_acgt(nt: u8) -> u8 {
     NT_ACGT[nt as usize]
@@ -217,3 +282,24 @@ static NT_DECODE: [u8; DECODE_SIZE] =
       b'B', // TGC.
       b'N', // TGCA
     ];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mismatches() {
+        let mut reference = Vec::new();
+        let mut query = Vec::new();
+        for len in 0..100 {
+            let nts = (0..len).map(|i| b"ACGTN"[(i * 7) % 5]).collect::<Vec<u8>>();
+            let other = (0..len).map(|i| b"ACGTNRY"[(i * 3) % 7]).collect::<Vec<u8>>();
+            encode_iupac_vec(&nts, &mut reference);
+            encode_acgt_vec(&other, &mut query);
+            assert_eq!(count_mismatches(&reference, &query), count_mismatches_scalar(&reference, &query));
+        }
+        encode_iupac_vec(b"ACGTNRA", &mut reference);
+        encode_acgt_vec(b"ACCTGANNN", &mut query);
+        assert_eq!(count_mismatches(&reference, &query), 2);
+    }
+}
@@ -0,0 +1,316 @@
+//! Filters that assign a fate to reads after trimming.
+//!
+//! A filter only examines reads whose fate is still `Output`, so
+//! each read is assigned the fate of the first filter that rejects
+//! it.
+
+use serde::{Serialize, Deserialize};
+
+use crate::output::Fate;
+use crate::stats::LengthHistogram;
+use crate::trimming::Trimming;
+
+/// Assigns `TooShort` to reads shorter than `min_length` and
+/// `TooLong` to reads longer than `max_length`, after trimming.
+#[derive(Clone, Debug)]
+pub struct LengthFilter {
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+    stats: LengthFilterStats,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct LengthFilterStats {
+    /// Reads found too short. For read pairs, this only counts reads
+    /// whose pair was given the `TooShort` fate, after the fates of
+    /// the two reads were harmonized, so that it agrees with the
+    /// fate counts.
+    pub too_short: usize,
+    /// Reads found too long, counted as for `too_short`
+    pub too_long: usize,
+    /// Trimmed lengths of all reads reaching the filter
+    pub lengths: LengthHistogram,
+}
+
+impl LengthFilterStats {
+    pub fn merge(&mut self, other: &LengthFilterStats) {
+        self.too_short += other.too_short;
+        self.too_long += other.too_long;
+        self.lengths.merge(&other.lengths);
+    }
+}
+
+impl LengthFilter {
+    pub fn new(min_length: Option<usize>, max_length: Option<usize>) -> Self {
+        LengthFilter { min_length, max_length, stats: LengthFilterStats::default() }
+    }
+
+    pub fn filter(&mut self, trimming: &mut Trimming) {
+        if !trimming.fate().is_output() {
+            return;
+        }
+
+        let len = trimming.len_trimmed();
+        self.stats.lengths.add(len);
+        if self.min_length.is_some_and(|min| len < min) {
+            self.stats.too_short += 1;
+            trimming.set_fate(Fate::TooShort);
+        } else if self.max_length.is_some_and(|max| len > max) {
+            self.stats.too_long += 1;
+            trimming.set_fate(Fate::TooLong);
+        }
+    }
+
+    /// Takes back the count of a read given `fate` by this filter,
+    /// when its pair was given another fate.
+    pub fn overrule(&mut self, fate: &Fate) {
+        match fate {
+            Fate::TooShort => self.stats.too_short -= 1,
+            Fate::TooLong => self.stats.too_long -= 1,
+            _ => (),
+        }
+    }
+
+    pub fn stats(&self) -> &LengthFilterStats {
+        &self.stats
+    }
+
+    /// Adds the statistics of `other`, a copy of this filter.
+    pub fn merge(&mut self, other: &Self) {
+        self.stats.merge(&other.stats);
+    }
+}
+
+/// Reads assigned a fate by an [`AdapterFilter`](struct.AdapterFilter.html).
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdapterFilterMode {
+    /// Assign `NoAdapter` to reads where no adapter was found
+    NoAdapter,
+    /// Assign `HasAdapter` to reads where an adapter was found
+    HasAdapter,
+}
+
+/// Assigns a fate to reads according to whether any adapter was
+/// found, e.g., to discard reads lacking an adapter.
+#[derive(Clone, Debug)]
+pub struct AdapterFilter {
+    mode: AdapterFilterMode,
+    filtered: usize,
+}
+
+impl AdapterFilter {
+    pub fn new(mode: AdapterFilterMode) -> Self {
+        AdapterFilter { mode, filtered: 0 }
+    }
+
+    pub fn filter(&mut self, trimming: &mut Trimming) {
+        if !trimming.fate().is_output() {
+            return;
+        }
+
+        let has_adapter = !trimming.adapter_matches().is_empty();
+        match (self.mode, has_adapter) {
+            (AdapterFilterMode::NoAdapter, false) => trimming.set_fate(Fate::NoAdapter),
+            (AdapterFilterMode::HasAdapter, true) => trimming.set_fate(Fate::HasAdapter),
+            _ => return,
+        }
+        self.filtered += 1;
+    }
+
+    /// Number of reads assigned a fate by the filter
+    pub fn filtered(&self) -> usize {
+        self.filtered
+    }
+
+    /// Adds the statistics of `other`, a copy of this filter.
+    pub fn merge(&mut self, other: &Self) {
+        self.filtered += other.filtered;
+    }
+}
+
+/// Assigns `TooManyExpectedErrors` to reads whose expected number of
+/// sequencing errors, computed from their qualities after trimming,
+/// exceeds `max_expected_errors`. Reads without qualities are not
+/// filtered.
+///
+/// The expected number of errors is the sum of the error
+/// probabilities, `10^(-Q/10)` for Phred quality `Q`, of all bases.
+#[derive(Clone, Debug)]
+pub struct ExpectedErrorsFilter {
+    max_expected_errors: f64,
+    error_probs: Vec<f64>,
+    filtered: usize,
+}
+
+impl ExpectedErrorsFilter {
+    /// Creates a filter for qualities encoded as `quality_base` plus
+    /// the Phred score.
+    pub fn new(max_expected_errors: f64, quality_base: u8) -> Self {
+        let error_probs = (0..=u8::MAX)
+            .map(|q| 10.0_f64.powf(-(q.saturating_sub(quality_base) as f64) / 10.0))
+            .collect();
+        ExpectedErrorsFilter { max_expected_errors, error_probs, filtered: 0 }
+    }
+
+    pub fn expected_errors(&self, quals: &[u8]) -> f64 {
+        quals.iter().map(|&q| self.error_probs[q as usize]).sum()
+    }
+
+    pub fn filter(&mut self, trimming: &mut Trimming) {
+        if !trimming.fate().is_output() {
+            return;
+        }
+
+        if let Some(quals) = trimming.qual_trimmed() {
+            if self.expected_errors(quals) > self.max_expected_errors {
+                self.filtered += 1;
+                trimming.set_fate(Fate::TooManyExpectedErrors);
+            }
+        }
+    }
+
+    /// Number of reads assigned a fate by the filter
+    pub fn filtered(&self) -> usize {
+        self.filtered
+    }
+
+    /// Adds the statistics of `other`, a copy of this filter.
+    pub fn merge(&mut self, other: &Self) {
+        self.filtered += other.filtered;
+    }
+}
+
+/// Assigns `TooManyN` to reads with more than `max_count` `N` bases,
+/// or a fraction of `N` bases greater than `max_fraction`, after
+/// trimming.
+#[derive(Clone, Debug)]
+pub struct NFilter {
+    max_count: Option<usize>,
+    max_fraction: Option<f64>,
+    filtered: usize,
+}
+
+impl NFilter {
+    pub fn new(max_count: Option<usize>, max_fraction: Option<f64>) -> Self {
+        NFilter { max_count, max_fraction, filtered: 0 }
+    }
+
+    pub fn filter(&mut self, trimming: &mut Trimming) {
+        if !trimming.fate().is_output() {
+            return;
+        }
+
+        let seq = trimming.seq_trimmed();
+        let n_count = seq.iter().filter(|&&nt| nt == b'N' || nt == b'n').count();
+        let too_many = self.max_count.is_some_and(|max| n_count > max)
+            || self.max_fraction.is_some_and(|max| !seq.is_empty() && n_count as f64 > max * seq.len() as f64);
+        if too_many {
+            self.filtered += 1;
+            trimming.set_fate(Fate::TooManyN);
+        }
+    }
+
+    /// Number of reads assigned a fate by the filter
+    pub fn filtered(&self) -> usize {
+        self.filtered
+    }
+
+    /// Adds the statistics of `other`, a copy of this filter.
+    pub fn merge(&mut self, other: &Self) {
+        self.filtered += other.filtered;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn length_filter() {
+        let mut filter = LengthFilter::new(Some(4), Some(6));
+        let fate = |filter: &mut LengthFilter, seq: &[u8]| {
+            let mut trimming = Trimming::new(b"read", seq, None);
+            filter.filter(&mut trimming);
+            trimming.fate().clone()
+        };
+
+        assert_eq!(fate(&mut filter, b"ACG"), Fate::TooShort);
+        assert_eq!(fate(&mut filter, b"ACGT"), Fate::Output);
+        assert_eq!(fate(&mut filter, b"ACGTAC"), Fate::Output);
+        assert_eq!(fate(&mut filter, b"ACGTACG"), Fate::TooLong);
+        assert_eq!(filter.stats().too_short, 1);
+        assert_eq!(filter.stats().too_long, 1);
+        assert_eq!(filter.stats().lengths.total(), 4);
+
+        let mut trimming = Trimming::new(b"read", b"A", None);
+        trimming.set_fate(Fate::NoAdapter);
+        filter.filter(&mut trimming);
+        assert_eq!(trimming.fate(), &Fate::NoAdapter);
+        assert_eq!(filter.stats().lengths.total(), 4);
+    }
+
+    #[test]
+    fn expected_errors_filter() {
+        let mut filter = ExpectedErrorsFilter::new(0.5, 33);
+        assert!((filter.expected_errors(b"!!+") - 2.1).abs() < 1e-9);
+        assert!((filter.expected_errors(b"55555") - 0.05).abs() < 1e-9);
+
+        let mut trimming = Trimming::new(b"read", b"ACGTA", Some(b"II+II"));
+        filter.filter(&mut trimming);
+        assert_eq!(trimming.fate(), &Fate::Output);
+
+        let mut trimming = Trimming::new(b"read", b"ACGTA", Some(b"II!II"));
+        filter.filter(&mut trimming);
+        assert_eq!(trimming.fate(), &Fate::TooManyExpectedErrors);
+
+        let mut trimming = Trimming::new(b"read", b"ACGTA", Some(b"II!II"));
+        trimming.trim_from_end(3);
+        filter.filter(&mut trimming);
+        assert_eq!(trimming.fate(), &Fate::Output);
+
+        let mut trimming = Trimming::new(b"read", b"ACGTA", None);
+        filter.filter(&mut trimming);
+        assert_eq!(trimming.fate(), &Fate::Output);
+        assert_eq!(filter.filtered(), 1);
+    }
+
+    #[test]
+    fn n_filter() {
+        let fate = |filter: &mut NFilter, seq: &[u8]| {
+            let mut trimming = Trimming::new(b"read", seq, None);
+            filter.filter(&mut trimming);
+            trimming.fate().clone()
+        };
+
+        let mut filter = NFilter::new(Some(1), None);
+        assert_eq!(fate(&mut filter, b"ACNGT"), Fate::Output);
+        assert_eq!(fate(&mut filter, b"ACNGn"), Fate::TooManyN);
+
+        let mut filter = NFilter::new(None, Some(0.25));
+        assert_eq!(fate(&mut filter, b"ACNGT"), Fate::Output);
+        assert_eq!(fate(&mut filter, b"ACNN"), Fate::TooManyN);
+        assert_eq!(fate(&mut filter, b""), Fate::Output);
+        assert_eq!(filter.filtered(), 1);
+    }
+
+    #[test]
+    fn adapter_filter() {
+        use crate::adapter::{AdapterConf, AdapterKind, Adapters};
+
+        let adapter = AdapterConf::with_defaults("a", "AGATCGGAAG", AdapterKind::ThreePrime).adapter().unwrap();
+        let mut adapters = Adapters::new(vec![adapter]).unwrap();
+
+        for (mode, fates) in [(AdapterFilterMode::NoAdapter, [Fate::Output, Fate::NoAdapter]),
+                               (AdapterFilterMode::HasAdapter, [Fate::HasAdapter, Fate::Output])].iter() {
+            let mut filter = AdapterFilter::new(*mode);
+            for (seq, fate) in [&b"CCCCCCAGATCGGAAG"[..], &b"CCCCCCCCCCCCCCCC"[..]].iter().zip(fates.iter()) {
+                let mut trimming = Trimming::new(b"read", seq, None);
+                adapters.trim(&mut trimming);
+                filter.filter(&mut trimming);
+                assert_eq!(trimming.fate(), fate);
+            }
+            assert_eq!(filter.filtered(), 1);
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! Reading of sequence files.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use crate::trimming::Trimming;
+
+/// Reads all records of a FASTA file, returning the name (the header
+/// line up to the first whitespace) and the sequence of each record.
+pub fn read_fasta<P: AsRef<Path>>(path: P) -> Result<Vec<(String, Vec<u8>)>> {
+    let path = path.as_ref();
+    let file = File::open(path).with_context(|| format!("Opening FASTA file {:?}", path))?;
+    parse_fasta(BufReader::new(file)).with_context(|| format!("Reading FASTA file {:?}", path))
+}
+
+pub fn parse_fasta<R: BufRead>(reader: R) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut records: Vec<(String, Vec<u8>)> = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim_end();
+        if let Some(header) = line.strip_prefix('>') {
+            let name = header.split_whitespace().next().unwrap_or("");
+            records.push((name.to_string(), Vec::new()));
+        } else if !line.is_empty() {
+            match records.last_mut() {
+                Some((_, seq)) => seq.extend_from_slice(line.as_bytes()),
+                None => bail!("Sequence before first FASTA header"),
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// A sequencing read owning its name, sequence, and qualities.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Record {
+    name: Vec<u8>,
+    seq: Vec<u8>,
+    qual: Option<Vec<u8>>,
+}
+
+impl Record {
+    pub fn new(name: &[u8], seq: &[u8], qual: Option<&[u8]>) -> Self {
+        Record { name: name.to_vec(), seq: seq.to_vec(), qual: qual.map(<[u8]>::to_vec) }
+    }
+
+    /// Full header line, without the leading `@`
+    pub fn name(&self) -> &[u8] { &self.name }
+
+    pub fn seq(&self) -> &[u8] { &self.seq }
+
+    pub fn qual(&self) -> Option<&[u8]> { self.qual.as_deref() }
+
+    /// Read identifier, the name up to the first whitespace and
+    /// without any `/1` or `/2` suffix.
+    pub fn id(&self) -> &[u8] {
+        let id = self.name.split(|c| c.is_ascii_whitespace()).next().unwrap_or(&[]);
+        match id {
+            [rest @ .., b'/', b'1'] | [rest @ .., b'/', b'2'] => rest,
+            _ => id,
+        }
+    }
+
+    pub fn trimming(&self) -> Trimming<'_> {
+        Trimming::new(&self.name, &self.seq, self.qual.as_deref())
+    }
+}
+
+/// Reader for FASTQ files.
+pub struct FastqReader<R> {
+    reader: R,
+    line: Vec<u8>,
+    records: usize,
+}
+
+impl FastqReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).with_context(|| format!("Opening FASTQ file {:?}", path))?;
+        Ok(FastqReader::new(BufReader::new(file)))
+    }
+}
+
+impl<R: BufRead> FastqReader<R> {
+    pub fn new(reader: R) -> Self {
+        FastqReader { reader, line: Vec::new(), records: 0 }
+    }
+
+    fn read_line(&mut self) -> Result<bool> {
+        self.line.clear();
+        if self.reader.read_until(b'\n', &mut self.line)? == 0 {
+            return Ok(false);
+        }
+        while self.line.last().is_some_and(|&c| c == b'\n' || c == b'\r') {
+            self.line.pop();
+        }
+        Ok(true)
+    }
+
+    fn read_required_line(&mut self) -> Result<()> {
+        if !self.read_line()? {
+            bail!("Truncated FASTQ record {}", self.records + 1);
+        }
+        Ok(())
+    }
+
+    /// Reads the next record into `record`, returning `false` at the
+    /// end of the file.
+    pub fn read_record(&mut self, record: &mut Record) -> Result<bool> {
+        if !self.read_line()? {
+            return Ok(false);
+        }
+        match self.line.split_first() {
+            Some((b'@', name)) => {
+                record.name.clear();
+                record.name.extend_from_slice(name);
+            }
+            _ => bail!("FASTQ record {} does not start with '@'", self.records + 1),
+        }
+
+        self.read_required_line()?;
+        record.seq.clear();
+        record.seq.extend_from_slice(&self.line);
+
+        self.read_required_line()?;
+        if self.line.first() != Some(&b'+') {
+            bail!("FASTQ record {} has no '+' separator line", self.records + 1);
+        }
+
+        self.read_required_line()?;
+        if self.line.len() != record.seq.len() {
+            bail!("FASTQ record {} has {} bases but {} qualities",
+                  self.records + 1, record.seq.len(), self.line.len());
+        }
+        let qual = record.qual.get_or_insert_with(Vec::new);
+        qual.clear();
+        qual.extend_from_slice(&self.line);
+
+        self.records += 1;
+        Ok(true)
+    }
+
+    /// Reads up to `size` records, returning an empty chunk at the end
+    /// of the file.
+    pub fn read_chunk(&mut self, size: usize) -> Result<Vec<Record>> {
+        let mut chunk = Vec::with_capacity(size);
+        let mut record = Record::default();
+        while chunk.len() < size && self.read_record(&mut record)? {
+            chunk.push(std::mem::take(&mut record));
+        }
+        Ok(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fasta() {
+        let records = parse_fasta(&b">one first\nACGT\nTT\n\n>two\nGG\n"[..]).unwrap();
+        assert_eq!(records, vec![("one".to_string(), b"ACGTTT".to_vec()), ("two".to_string(), b"GG".to_vec())]);
+        assert!(parse_fasta(&b"ACGT\n>one\n"[..]).is_err());
+    }
+
+    #[test]
+    fn fastq() {
+        let mut reader = FastqReader::new(&b"@r1/1 x\nACGT\n+\nIIII\n@r2\r\nGG\r\n+r2\r\n##\r\n"[..]);
+        let chunk = reader.read_chunk(5).unwrap();
+        assert_eq!(chunk, vec![Record::new(b"r1/1 x", b"ACGT", Some(b"IIII")),
+                               Record::new(b"r2", b"GG", Some(b"##"))]);
+        assert_eq!(chunk[0].id(), b"r1");
+        assert!(reader.read_chunk(5).unwrap().is_empty());
+
+        assert!(FastqReader::new(&b"@r1\nACGT\n+\nIII\n"[..]).read_chunk(5).is_err());
+        assert!(FastqReader::new(&b"@r1\nACGT\n"[..]).read_chunk(5).is_err());
+        assert!(FastqReader::new(&b">r1\nACGT\n"[..]).read_chunk(5).is_err());
+    }
+}
@@ -1,6 +1,18 @@
+pub mod adapter;
 pub mod align;
+pub mod barcode;
+pub mod complexity;
+pub mod contaminant;
+pub mod demux;
 pub mod encode;
+pub mod filter;
+pub mod input;
 pub mod output;
 pub mod pipeline;
+pub mod qc;
 pub mod qual_trim;
+pub mod report;
+pub mod runner;
+pub mod samplesheet;
+pub mod stats;
 pub mod trimming;
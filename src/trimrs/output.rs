@@ -1,5 +1,514 @@
-#[derive(Clone, Debug, Eq, PartialEq)]
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use anyhow::{bail, Context, Result};
+use serde::{Serialize, Deserialize};
+
+use crate::trimming::Trimming;
+
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Fate {
     Output,
-    Discard,
+    TooShort,
+    TooLong,
+    NoAdapter,
+    HasAdapter,
+    TooManyExpectedErrors,
+    TooManyN,
+    LowComplexity,
+    Contaminant,
+}
+
+/// Policy for combining filtering decisions on read 1 and read 2 of
+/// a pair, following `Cutadapt`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PairFilterMode {
+    /// Filter the pair when either read is filtered
+    #[default]
+    Any,
+    /// Filter the pair only when both reads are filtered
+    Both,
+    /// Filter the pair according to read 1 alone
+    First,
+}
+
+impl Fate {
+    pub fn is_output(&self) -> bool {
+        *self == Fate::Output
+    }
+
+    /// Harmonizes the fates of read 1 and read 2 of a pair, so that
+    /// both reads share a single destination.
+    ///
+    /// When the pair is filtered, the fate of read 1 is used if it
+    /// was filtered, and otherwise the fate of read 2.
+    pub fn harmonize(fate1: &Fate, fate2: &Fate, mode: PairFilterMode) -> Fate {
+        match mode {
+            PairFilterMode::Any if !fate1.is_output() => fate1.clone(),
+            PairFilterMode::Any => fate2.clone(),
+            PairFilterMode::Both if !fate2.is_output() => fate1.clone(),
+            PairFilterMode::Both => Fate::Output,
+            PairFilterMode::First => fate1.clone(),
+        }
+    }
+}
+
+/// Writes the trimmed read as a FASTQ record, or a FASTA record if
+/// the read has no qualities. Masked regions are written as `N`.
+pub fn write_trimmed<W: Write>(out: &mut W, trimming: &Trimming) -> io::Result<()> {
+    let qual = trimming.qual_trimmed();
+    out.write_all(if qual.is_some() { b"@" } else { b">" })?;
+    out.write_all(trimming.name_raw())?;
+    out.write_all(b"\n")?;
+    if trimming.n_masks().is_empty() {
+        out.write_all(trimming.seq_trimmed())?;
+    } else {
+        let mut seq = Vec::new();
+        trimming.seq_trimmed_masked(&mut seq);
+        out.write_all(&seq)?;
+    }
+    out.write_all(b"\n")?;
+    if let Some(qual) = qual {
+        out.write_all(b"+\n")?;
+        out.write_all(qual)?;
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+fn write_fields<W: Write>(out: &mut W, fields: &[&[u8]]) -> io::Result<()> {
+    for (idx, field) in fields.iter().enumerate() {
+        if idx > 0 {
+            out.write_all(b"\t")?;
+        }
+        out.write_all(field)?;
+    }
+    out.write_all(b"\n")
+}
+
+/// Writes a line for each adapter match in the read, as in the info
+/// file of `Cutadapt`, with tab-separated fields
+///
+/// * read name
+/// * number of errors
+/// * start and stop of the match
+/// * sequence before, within, and after the match
+/// * adapter name
+/// * qualities before, within, and after the match, empty when the
+///   read has no qualities
+/// * extended CIGAR string of the alignment of the read with the
+///   adapter, only when the adapters compute alignments, which
+///   `Cutadapt` does not write
+///
+/// Sequence positions are relative to the read sequence that was
+/// aligned to the adapter. A read with no adapter matches is written
+/// on a single line with its name, `-1`, its sequence, and its
+/// qualities, which are an empty field when the read has none, as in
+/// `Cutadapt`.
+pub fn write_info<W: Write>(out: &mut W, trimming: &Trimming) -> io::Result<()> {
+    let name = trimming.name_raw();
+    if trimming.adapter_matches().is_empty() {
+        return write_fields(out, &[name, b"-1", trimming.seq_raw(), trimming.qual_raw().unwrap_or(b"")]);
+    }
+
+    for adapter_match in trimming.adapter_matches() {
+        let aligned = adapter_match.offset()..(adapter_match.offset() + adapter_match.length());
+        let location = adapter_match.location();
+        let (start, stop) = (location.querystart(), location.querystop());
+        let errors = location.errors().to_string();
+        let start_field = start.to_string();
+        let stop_field = stop.to_string();
+        let seq = &trimming.seq_raw()[aligned.clone()];
+        let qual = trimming.qual_raw().map_or(&b""[..], |qual| &qual[aligned]);
+        let (qual_before, qual_match, qual_after) = if qual.is_empty() {
+            (&b""[..], &b""[..], &b""[..])
+        } else {
+            (&qual[..start], &qual[start..stop], &qual[stop..])
+        };
+        let mut fields: Vec<&[u8]> = vec![name, errors.as_bytes(), start_field.as_bytes(), stop_field.as_bytes(),
+                                          &seq[..start], &seq[start..stop], &seq[stop..],
+                                          adapter_match.adapter().as_bytes(), qual_before, qual_match, qual_after];
+        if let Some(cigar) = adapter_match.cigar() {
+            fields.push(cigar.as_bytes());
+        }
+        write_fields(out, &fields)?;
+    }
+    Ok(())
+}
+
+/// Writes the sequence trimmed along with the last adapter match but
+/// outside of it, followed by the read name, as in the rest file of
+/// `Cutadapt`. Nothing is written when there is no such sequence.
+pub fn write_rest<W: Write>(out: &mut W, trimming: &Trimming) -> io::Result<()> {
+    if let Some(adapter_match) = trimming.adapter_matches().last() {
+        let rest = adapter_match.rest(trimming.seq_raw());
+        if !rest.is_empty() {
+            out.write_all(rest)?;
+            out.write_all(b" ")?;
+            out.write_all(trimming.name_raw())?;
+            out.write_all(b"\n")?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes the read bases matching `N` wildcards in the last adapter
+/// match, followed by the read name, as in the wildcard file of
+/// `Cutadapt`. Nothing is written for reads without adapter matches.
+pub fn write_wildcards<W: Write>(out: &mut W, trimming: &Trimming) -> io::Result<()> {
+    if let Some(adapter_match) = trimming.adapter_matches().last() {
+        out.write_all(adapter_match.wildcards())?;
+        out.write_all(b" ")?;
+        out.write_all(trimming.name_raw())?;
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Per-read report files, with a record for every read regardless of
+/// its fate.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum ReadReport {
+    Info,
+    Rest,
+    Wildcard,
+}
+
+impl ReadReport {
+    pub fn write<W: Write>(self, out: &mut W, trimming: &Trimming) -> io::Result<()> {
+        match self {
+            ReadReport::Info => write_info(out, trimming),
+            ReadReport::Rest => write_rest(out, trimming),
+            ReadReport::Wildcard => write_wildcards(out, trimming),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Default, Serialize, Deserialize)]
+pub struct ReadReportConf {
+    #[serde(default)]
+    info_file: Option<String>,
+    #[serde(default)]
+    rest_file: Option<String>,
+    #[serde(default)]
+    wildcard_file: Option<String>,
+    /// Add the CIGAR string of each adapter alignment to the info
+    /// file, which needs the alignments to be computed
+    #[serde(default)]
+    info_cigar: bool,
+}
+
+impl ReadReportConf {
+    pub fn info_cigar(&self) -> bool {
+        self.info_cigar
+    }
+
+    pub fn read_reports(&self) -> Result<ReadReports> {
+        let mut writers = BTreeMap::new();
+        let paths = [(ReadReport::Info, &self.info_file),
+                     (ReadReport::Rest, &self.rest_file),
+                     (ReadReport::Wildcard, &self.wildcard_file)];
+        for (report, path) in paths.iter() {
+            if let Some(path) = path {
+                writers.insert(*report, create(path)?);
+            }
+        }
+        Ok(ReadReports { writers })
+    }
+}
+
+pub struct ReadReports {
+    writers: BTreeMap<ReadReport, BufWriter<File>>,
+}
+
+impl ReadReports {
+    /// Reports with an output file
+    pub fn reports(&self) -> Vec<ReadReport> {
+        self.writers.keys().copied().collect()
+    }
+
+    /// Writes already formatted records to the file for `report`, if
+    /// there is one.
+    pub fn write_raw(&mut self, report: ReadReport, records: &[u8]) -> io::Result<()> {
+        match self.writers.get_mut(&report) {
+            Some(writer) => writer.write_all(records),
+            None => Ok(()),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writers.values_mut().try_for_each(Write::flush)
+    }
+}
+
+pub(crate) fn create(path: &str) -> Result<BufWriter<File>> {
+    let file = File::create(path).with_context(|| format!("Creating output file {:?}", path))?;
+    Ok(BufWriter::new(file))
+}
+
+/// Output files for reads with fates other than `Output`. Reads
+/// whose fate has no output file are dropped.
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Default, Serialize, Deserialize)]
+pub struct FateOutputConf {
+    #[serde(default)]
+    too_short_output: Option<String>,
+    #[serde(default)]
+    too_long_output: Option<String>,
+    #[serde(default)]
+    no_adapter_output: Option<String>,
+    #[serde(default)]
+    has_adapter_output: Option<String>,
+    #[serde(default)]
+    too_many_expected_errors_output: Option<String>,
+    #[serde(default)]
+    too_many_n_output: Option<String>,
+    #[serde(default)]
+    low_complexity_output: Option<String>,
+    #[serde(default)]
+    contaminant_output: Option<String>,
+}
+
+impl FateOutputConf {
+    fn paths(&self) -> Vec<(Fate, &String)> {
+        [(Fate::TooShort, &self.too_short_output),
+         (Fate::TooLong, &self.too_long_output),
+         (Fate::NoAdapter, &self.no_adapter_output),
+         (Fate::HasAdapter, &self.has_adapter_output),
+         (Fate::TooManyExpectedErrors, &self.too_many_expected_errors_output),
+         (Fate::TooManyN, &self.too_many_n_output),
+         (Fate::LowComplexity, &self.low_complexity_output),
+         (Fate::Contaminant, &self.contaminant_output)]
+            .iter()
+            .filter_map(|(fate, path)| path.as_ref().map(|path| (fate.clone(), path)))
+            .collect()
+    }
+
+    pub fn fate_outputs(&self) -> Result<FateOutputs> {
+        let paths = self.paths();
+        check_distinct(paths.iter().map(|(fate, path)| (format!("{:?}", fate), *path)))?;
+        let mut writers = HashMap::new();
+        for (fate, path) in paths {
+            writers.insert(fate, create(path)?);
+        }
+        Ok(FateOutputs { writers })
+    }
+
+    /// Creates paired outputs, with read 2 written to the read 1 path
+    /// from `paired_conf`. Each fate needs both paths or neither.
+    pub fn paired_fate_outputs(&self, paired_conf: &FateOutputConf) -> Result<PairedFateOutputs> {
+        let paths = self.paths();
+        let paired_paths = paired_conf.paths();
+        for (fate, _) in paired_paths.iter() {
+            if !paths.iter().any(|(f, _)| f == fate) {
+                bail!("No read 1 output file for {:?} reads", fate);
+            }
+        }
+        check_distinct(paths.iter().map(|(fate, path)| (format!("read 1 {:?}", fate), *path))
+                       .chain(paired_paths.iter().map(|(fate, path)| (format!("read 2 {:?}", fate), *path))))?;
+        let mut writers = HashMap::new();
+        for (fate, path) in paths {
+            let paired_path = match paired_paths.iter().find(|(f, _)| *f == fate) {
+                Some((_, paired_path)) => paired_path,
+                None => bail!("No read 2 output file for {:?} reads", fate),
+            };
+            writers.insert(fate, (create(path)?, create(paired_path)?));
+        }
+        Ok(PairedFateOutputs { writers })
+    }
+}
+
+/// Checks that no two outputs share a path, which would truncate one
+/// of them.
+fn check_distinct<'a>(paths: impl Iterator<Item = (String, &'a String)>) -> Result<()> {
+    let mut used: HashMap<&String, String> = HashMap::new();
+    for (reads, path) in paths {
+        if let Some(other) = used.get(path) {
+            bail!("Output file {:?} is used for both {} and {} reads", path, other, reads);
+        }
+        used.insert(path, reads);
+    }
+    Ok(())
+}
+
+pub struct FateOutputs {
+    writers: HashMap<Fate, BufWriter<File>>,
+}
+
+impl FateOutputs {
+    /// Writes the read to the output file for its fate, returning
+    /// `true` when there is such a file.
+    pub fn write(&mut self, trimming: &Trimming) -> io::Result<bool> {
+        match self.writers.get_mut(trimming.fate()) {
+            Some(writer) => write_trimmed(writer, trimming).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Fates with an output file
+    pub fn fates(&self) -> Vec<Fate> {
+        self.writers.keys().cloned().collect()
+    }
+
+    /// Writes already formatted records to the output file for
+    /// `fate`, if there is one.
+    pub fn write_raw(&mut self, fate: &Fate, records: &[u8]) -> io::Result<()> {
+        match self.writers.get_mut(fate) {
+            Some(writer) => writer.write_all(records),
+            None => Ok(()),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writers.values_mut().try_for_each(Write::flush)
+    }
+}
+
+pub struct PairedFateOutputs {
+    writers: HashMap<Fate, (BufWriter<File>, BufWriter<File>)>,
+}
+
+impl PairedFateOutputs {
+    /// Writes the pair to the output files for its harmonized fate,
+    /// returning `true` when there are such files.
+    pub fn write(&mut self, trimming1: &Trimming, trimming2: &Trimming) -> io::Result<bool> {
+        match self.writers.get_mut(trimming1.fate()) {
+            Some((writer1, writer2)) => {
+                write_trimmed(writer1, trimming1)?;
+                write_trimmed(writer2, trimming2)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Fates with output files
+    pub fn fates(&self) -> Vec<Fate> {
+        self.writers.keys().cloned().collect()
+    }
+
+    /// Writes already formatted read 1 and read 2 records to the
+    /// output files for `fate`, if there are any.
+    pub fn write_raw(&mut self, fate: &Fate, records1: &[u8], records2: &[u8]) -> io::Result<()> {
+        match self.writers.get_mut(fate) {
+            Some((writer1, writer2)) => {
+                writer1.write_all(records1)?;
+                writer2.write_all(records2)
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        for (writer1, writer2) in self.writers.values_mut() {
+            writer1.flush()?;
+            writer2.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::adapter::{AdapterConf, AdapterKind, Adapters};
+
+    #[test]
+    fn harmonize_fates() {
+        let out = Fate::Output;
+        let short = Fate::TooShort;
+        let long = Fate::TooLong;
+
+        assert_eq!(Fate::harmonize(&out, &out, PairFilterMode::Any), out);
+        assert_eq!(Fate::harmonize(&short, &out, PairFilterMode::Any), short);
+        assert_eq!(Fate::harmonize(&out, &long, PairFilterMode::Any), long);
+        assert_eq!(Fate::harmonize(&short, &long, PairFilterMode::Any), short);
+
+        assert_eq!(Fate::harmonize(&short, &out, PairFilterMode::Both), out);
+        assert_eq!(Fate::harmonize(&out, &long, PairFilterMode::Both), out);
+        assert_eq!(Fate::harmonize(&short, &long, PairFilterMode::Both), short);
+
+        assert_eq!(Fate::harmonize(&short, &out, PairFilterMode::First), short);
+        assert_eq!(Fate::harmonize(&out, &long, PairFilterMode::First), out);
+    }
+
+    #[test]
+    fn fate_output_paths() {
+        let conf: FateOutputConf = toml::from_str("too_short_output = \"a.fastq\"\ntoo_long_output = \"a.fastq\"\n").unwrap();
+        assert!(conf.fate_outputs().is_err());
+
+        let conf1: FateOutputConf = toml::from_str("too_short_output = \"short.1.fastq\"\n").unwrap();
+        let same: FateOutputConf = toml::from_str("too_short_output = \"short.1.fastq\"\n").unwrap();
+        assert!(conf1.paired_fate_outputs(&same).is_err());
+
+        let orphan: FateOutputConf = toml::from_str("too_short_output = \"short.2.fastq\"\ntoo_long_output = \"long.2.fastq\"\n").unwrap();
+        let err = conf1.paired_fate_outputs(&orphan).err().unwrap();
+        assert!(err.to_string().contains("No read 1 output file"));
+    }
+
+    #[test]
+    fn write_records() {
+        let mut out = Vec::new();
+        let mut trimming = Trimming::new(b"read1", b"ACGTACGT", Some(b"IIIIII##"));
+        trimming.trim_from_end(2);
+        write_trimmed(&mut out, &trimming).unwrap();
+        write_trimmed(&mut out, &Trimming::new(b"read2", b"GGCC", None)).unwrap();
+        let mut trimming = Trimming::new(b"read3", b"ACGTACGT", None);
+        trimming.trim_from_start(2);
+        trimming.mask_trimmed(1, 3);
+        trimming.mask_trimmed(5, 9);
+        write_trimmed(&mut out, &trimming).unwrap();
+        assert_eq!(std::str::from_utf8(&out).unwrap(),
+                   "@read1\nACGTAC\n+\nIIIIII\n>read2\nGGCC\n>read3\nGNNCGN\n");
+    }
+
+    #[test]
+    fn write_info_lines() {
+        let adapter = AdapterConf::with_defaults("a", "AGATCGGAAG", AdapterKind::ThreePrime).adapter().unwrap();
+        let mut adapters = Adapters::new(vec![adapter]).unwrap();
+
+        let mut out = Vec::new();
+        let mut trimming = Trimming::new(b"read1 x", b"GGCCCCAGATCGGAAGTT", Some(b"ABCDEFGHIJKLMNOPQR"));
+        trimming.trim_from_start(2);
+        adapters.trim(&mut trimming);
+        write_info(&mut out, &trimming).unwrap();
+        write_info(&mut out, &Trimming::new(b"read2", b"ACGT", None)).unwrap();
+        write_info(&mut out, &Trimming::new(b"read3", b"ACGT", Some(b"IIII"))).unwrap();
+        assert_eq!(std::str::from_utf8(&out).unwrap(),
+                   "read1 x\t0\t4\t14\tCCCC\tAGATCGGAAG\tTT\ta\tCDEF\tGHIJKLMNOP\tQR\nread2\t-1\tACGT\t\nread3\t-1\tACGT\tIIII\n");
+    }
+
+    #[test]
+    fn write_info_cigar() {
+        let adapter = AdapterConf::with_defaults("a", "AGATCGGAAG", AdapterKind::ThreePrime).adapter().unwrap();
+        let mut adapters = Adapters::new(vec![adapter]).unwrap();
+        adapters.set_traceback(true);
+
+        let mut out = Vec::new();
+        let mut trimming = Trimming::new(b"read1", b"CCCCAGATCGCAAGTT", None);
+        adapters.trim(&mut trimming);
+        write_info(&mut out, &trimming).unwrap();
+        assert_eq!(std::str::from_utf8(&out).unwrap(), "read1\t1\t4\t14\tCCCC\tAGATCGCAAG\tTT\ta\t\t\t\t6=1X3=\n");
+    }
+
+    #[test]
+    fn write_rest_and_wildcards() {
+        let adapter = AdapterConf::with_defaults("a", "AGANNGGAAG", AdapterKind::ThreePrime).adapter().unwrap();
+        let mut adapters = Adapters::new(vec![adapter]).unwrap();
+
+        let mut trimming = Trimming::new(b"read1", b"CCCCAGATCGGAAGTT", None);
+        adapters.trim(&mut trimming);
+        let untrimmed = Trimming::new(b"read2", b"ACGT", None);
+        let mut rest = Vec::new();
+        let mut wildcards = Vec::new();
+        for t in [&trimming, &untrimmed].iter() {
+            ReadReport::Rest.write(&mut rest, t).unwrap();
+            ReadReport::Wildcard.write(&mut wildcards, t).unwrap();
+        }
+        assert_eq!(rest, b"TT read1\n");
+        assert_eq!(wildcards, b"TC read1\n");
+    }
 }
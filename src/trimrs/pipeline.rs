@@ -1,50 +1,486 @@
+use anyhow::{ensure, Context, Result};
 use serde::{Serialize, Deserialize};
+
+use crate::adapter::{AdapterConf, AdapterStats, Adapters};
+use crate::barcode::{Barcode, BarcodeSource, Barcodes};
+use crate::complexity::{ComplexityMethod, LowComplexityFilter};
+use crate::contaminant::{ContaminantFilter, ContaminantStats};
+use crate::filter::{AdapterFilter, AdapterFilterMode, ExpectedErrorsFilter, LengthFilter, LengthFilterStats, NFilter};
+use crate::input::read_fasta;
+use crate::output::{Fate, PairFilterMode};
 use crate::qual_trim::{QualTrimEnd, QualTrimStart};
+use crate::stats::ReadCount;
 use crate::trimming::*;
 
-#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Serialize, Deserialize)]
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
 pub struct PipelineConf {
-    qual_trim_start: QualTrimStartConf,
-    qual_trim_end: QualTrimEndConf,
+    #[serde(default)]
+    qual_trim_start: Option<QualTrimStartConf>,
+    #[serde(default)]
+    qual_trim_end: Option<QualTrimEndConf>,
+    #[serde(default)]
+    barcodes: Option<BarcodesConf>,
+    #[serde(default)]
+    adapters: Option<Vec<AdapterConf>>,
+    #[serde(default)]
+    length_filter: Option<LengthFilterConf>,
+    #[serde(default)]
+    adapter_filter: Option<AdapterFilterConf>,
+    #[serde(default)]
+    expected_errors_filter: Option<ExpectedErrorsFilterConf>,
+    #[serde(default)]
+    n_filter: Option<NFilterConf>,
+    #[serde(default)]
+    low_complexity_filter: Option<LowComplexityFilterConf>,
+    #[serde(default)]
+    contaminant_filter: Option<ContaminantFilterConf>,
+}
+
+impl PipelineConf {
+    pub fn pipeline(&self) -> Result<Pipeline> {
+        Ok(Pipeline {
+            qual_trim_start: self.qual_trim_start.as_ref().map(QualTrimStartConf::qual_trim_start),
+            qual_trim_end: self.qual_trim_end.as_ref().map(QualTrimEndConf::qual_trim_end),
+            barcodes: self.barcodes.as_ref().map(BarcodesConf::barcodes).transpose()?,
+            adapters: match &self.adapters {
+                Some(adapters) => Some(Adapters::new(adapters.iter().map(AdapterConf::adapter).collect::<Result<_>>()?)?),
+                None => None,
+            },
+            length_filter: self.length_filter.as_ref().map(LengthFilterConf::length_filter),
+            adapter_filter: self.adapter_filter.as_ref().map(AdapterFilterConf::adapter_filter),
+            expected_errors_filter: self.expected_errors_filter.as_ref().map(ExpectedErrorsFilterConf::expected_errors_filter),
+            n_filter: self.n_filter.as_ref().map(NFilterConf::n_filter),
+            low_complexity_filter: self.low_complexity_filter.as_ref()
+                .map(LowComplexityFilterConf::low_complexity_filter).transpose()?,
+            contaminant_filter: self.contaminant_filter.as_ref()
+                .map(ContaminantFilterConf::contaminant_filter).transpose()?,
+        })
+    }
 }
 
+fn default_quality_base() -> u8 { 33 }
+
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Serialize, Deserialize)]
 pub struct QualTrimEndConf {
     threshold: u8,
+    #[serde(default = "default_quality_base")]
+    quality_base: u8,
 }
 
 impl QualTrimEndConf {
     pub fn qual_trim_end(&self) -> QualTrimEnd {
-        QualTrimEnd::new(self.threshold)
+        QualTrimEnd::new(self.threshold, self.quality_base)
     }
 }
 
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Serialize, Deserialize)]
 pub struct QualTrimStartConf {
     threshold: u8,
+    #[serde(default = "default_quality_base")]
+    quality_base: u8,
 }
 
 impl QualTrimStartConf {
     pub fn qual_trim_start(&self) -> QualTrimStart {
-        QualTrimStart::new(self.threshold)
+        QualTrimStart::new(self.threshold, self.quality_base)
     }
 }
 
-#[derive(Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Serialize, Deserialize)]
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Serialize, Deserialize)]
+pub struct BarcodesConf {
+    barcodes: Vec<Barcode>,
+    #[serde(default)]
+    max_errors: usize,
+    #[serde(default)]
+    indels: bool,
+    #[serde(default)]
+    source: BarcodeSource,
+}
+
+impl BarcodesConf {
+    pub fn new(barcodes: Vec<Barcode>, max_errors: usize, indels: bool, source: BarcodeSource) -> Self {
+        BarcodesConf { barcodes, max_errors, indels, source }
+    }
+
+    /// Loads the barcodes. Any ambiguous neighborhoods are listed by
+    /// `Barcodes::collisions` for the caller to report.
+    pub fn barcodes(&self) -> Result<Barcodes> {
+        let mut barcodes = Barcodes::new(&self.barcodes, self.max_errors, self.indels)?;
+        barcodes.set_source(self.source);
+        Ok(barcodes)
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Serialize, Deserialize)]
+pub struct LengthFilterConf {
+    #[serde(default)]
+    min_length: Option<usize>,
+    #[serde(default)]
+    max_length: Option<usize>,
+}
+
+impl LengthFilterConf {
+    pub fn length_filter(&self) -> LengthFilter {
+        LengthFilter::new(self.min_length, self.max_length)
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Serialize, Deserialize)]
+pub struct AdapterFilterConf {
+    reads: AdapterFilterMode,
+}
+
+impl AdapterFilterConf {
+    pub fn adapter_filter(&self) -> AdapterFilter {
+        AdapterFilter::new(self.reads)
+    }
+}
+
+#[derive(Debug, PartialEq, PartialOrd, Clone, Serialize, Deserialize)]
+pub struct ExpectedErrorsFilterConf {
+    max_expected_errors: f64,
+    #[serde(default = "default_quality_base")]
+    quality_base: u8,
+}
+
+impl ExpectedErrorsFilterConf {
+    pub fn expected_errors_filter(&self) -> ExpectedErrorsFilter {
+        ExpectedErrorsFilter::new(self.max_expected_errors, self.quality_base)
+    }
+}
+
+#[derive(Debug, PartialEq, PartialOrd, Clone, Serialize, Deserialize)]
+pub struct NFilterConf {
+    #[serde(default)]
+    max_count: Option<usize>,
+    #[serde(default)]
+    max_fraction: Option<f64>,
+}
+
+impl NFilterConf {
+    pub fn n_filter(&self) -> NFilter {
+        NFilter::new(self.max_count, self.max_fraction)
+    }
+}
+
+fn default_kmer() -> usize { 3 }
+
+#[derive(Debug, PartialEq, PartialOrd, Clone, Serialize, Deserialize)]
+pub struct LowComplexityFilterConf {
+    method: ComplexityMethod,
+    threshold: f64,
+    #[serde(default = "default_kmer")]
+    kmer: usize,
+    /// Mask low-complexity windows of this length with `N` instead
+    /// of filtering reads
+    #[serde(default)]
+    mask_window: Option<usize>,
+}
+
+impl LowComplexityFilterConf {
+    pub fn low_complexity_filter(&self) -> Result<LowComplexityFilter> {
+        LowComplexityFilter::new(self.method, self.threshold, self.kmer, self.mask_window)
+    }
+}
+
+fn default_contaminant_kmer() -> usize { 25 }
+
+fn default_both_strands() -> bool { true }
+
+fn default_min_kmers() -> usize { 1 }
+
+#[derive(Debug, PartialEq, PartialOrd, Clone, Serialize, Deserialize)]
+pub struct ContaminantFilterConf {
+    /// FASTA file of contaminant sequences
+    path: String,
+    #[serde(default = "default_contaminant_kmer")]
+    kmer: usize,
+    #[serde(default = "default_both_strands")]
+    both_strands: bool,
+    #[serde(default = "default_min_kmers")]
+    min_kmers: usize,
+    #[serde(default)]
+    min_fraction: Option<f64>,
+}
+
+impl ContaminantFilterConf {
+    pub fn contaminant_filter(&self) -> Result<ContaminantFilter> {
+        let contaminants = read_fasta(&self.path)?;
+        ContaminantFilter::new(&contaminants, self.kmer, self.both_strands, self.min_kmers, self.min_fraction)
+            .with_context(|| format!("Building contaminant k-mers from {:?}", self.path))
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct Pipeline {
     qual_trim_start: Option<QualTrimStart>,
     qual_trim_end: Option<QualTrimEnd>,
+    barcodes: Option<Barcodes>,
+    adapters: Option<Adapters>,
+    length_filter: Option<LengthFilter>,
+    adapter_filter: Option<AdapterFilter>,
+    expected_errors_filter: Option<ExpectedErrorsFilter>,
+    n_filter: Option<NFilter>,
+    low_complexity_filter: Option<LowComplexityFilter>,
+    contaminant_filter: Option<ContaminantFilter>,
 }
 
 impl Pipeline {
-    pub fn process(&self, trimming: &mut Trimming) {
-        if let Some(qts) = &self.qual_trim_start {
+    pub fn process(&mut self, trimming: &mut Trimming) {
+        if let Some(qts) = &mut self.qual_trim_start {
             qts.trim(trimming);
         }
 
-        if let Some(qte) = &self.qual_trim_end {
+        if let Some(qte) = &mut self.qual_trim_end {
             qte.trim(trimming);
         }
+
+        if let Some(bcs) = &mut self.barcodes {
+            bcs.trim(trimming);
+        }
+
+        if let Some(ads) = &mut self.adapters {
+            ads.trim(trimming);
+        }
+
+        if let Some(lf) = &mut self.length_filter {
+            lf.filter(trimming);
+        }
+
+        if let Some(af) = &mut self.adapter_filter {
+            af.filter(trimming);
+        }
+
+        if let Some(eef) = &mut self.expected_errors_filter {
+            eef.filter(trimming);
+        }
+
+        if let Some(nf) = &mut self.n_filter {
+            nf.filter(trimming);
+        }
+
+        if let Some(lcf) = &mut self.low_complexity_filter {
+            lcf.filter(trimming);
+        }
+
+        if let Some(cf) = &mut self.contaminant_filter {
+            cf.filter(trimming);
+        }
+    }
+
+    pub fn qual_trim_start(&self) -> Option<&QualTrimStart> {
+        self.qual_trim_start.as_ref()
+    }
+
+    pub fn qual_trim_end(&self) -> Option<&QualTrimEnd> {
+        self.qual_trim_end.as_ref()
+    }
+
+    pub fn barcodes(&self) -> Option<&Barcodes> {
+        self.barcodes.as_ref()
+    }
+
+    /// Barcode names, in the order used for read tags, when the
+    /// pipeline identifies barcodes.
+    pub fn barcode_names(&self) -> Option<&[String]> {
+        self.barcodes.as_ref().map(Barcodes::names)
+    }
+
+    /// Sets whether adapter alignments are computed, as with
+    /// `Adapters::set_traceback`.
+    pub fn set_traceback(&mut self, traceback: bool) {
+        if let Some(adapters) = &mut self.adapters {
+            adapters.set_traceback(traceback);
+        }
+    }
+
+    /// Identifies `barcodes` in the pipeline, which must not have
+    /// barcodes of its own.
+    pub fn set_barcodes(&mut self, barcodes: Barcodes) -> Result<()> {
+        ensure!(self.barcodes.is_none(), "Pipeline already has barcodes");
+        self.barcodes = Some(barcodes);
+        Ok(())
+    }
+
+    pub fn adapters(&self) -> Option<&Adapters> {
+        self.adapters.as_ref()
+    }
+
+    pub fn length_filter(&self) -> Option<&LengthFilter> {
+        self.length_filter.as_ref()
+    }
+
+    pub fn adapter_filter(&self) -> Option<&AdapterFilter> {
+        self.adapter_filter.as_ref()
+    }
+
+    pub fn expected_errors_filter(&self) -> Option<&ExpectedErrorsFilter> {
+        self.expected_errors_filter.as_ref()
+    }
+
+    pub fn n_filter(&self) -> Option<&NFilter> {
+        self.n_filter.as_ref()
+    }
+
+    pub fn low_complexity_filter(&self) -> Option<&LowComplexityFilter> {
+        self.low_complexity_filter.as_ref()
+    }
+
+    pub fn contaminant_filter(&self) -> Option<&ContaminantFilter> {
+        self.contaminant_filter.as_ref()
+    }
+
+    pub fn stats(&self) -> PipelineStats {
+        PipelineStats {
+            qual_trim_start: self.qual_trim_start.as_ref().map(QualTrimStart::trimmed),
+            qual_trim_end: self.qual_trim_end.as_ref().map(QualTrimEnd::trimmed),
+            barcodes: self.barcodes.as_ref().map(|bcs| BarcodeStats {
+                names: bcs.names().to_vec(),
+                reads: bcs.counts().to_vec(),
+                no_barcode: bcs.no_barcode(),
+            }),
+            adapters: self.adapters.as_ref().map(|ads| ads.stats().to_vec()),
+            length_filter: self.length_filter.as_ref().map(|lf| lf.stats().clone()),
+            adapter_filter: self.adapter_filter.as_ref().map(|af| FilterStats { filtered: af.filtered() }),
+            expected_errors_filter: self.expected_errors_filter.as_ref()
+                .map(|eef| FilterStats { filtered: eef.filtered() }),
+            n_filter: self.n_filter.as_ref().map(|nf| FilterStats { filtered: nf.filtered() }),
+            low_complexity_filter: self.low_complexity_filter.as_ref()
+                .map(|lcf| LowComplexityStats { filtered: lcf.filtered(), masked: lcf.masked() }),
+            contaminant_filter: self.contaminant_filter.as_ref().map(|cf| cf.stats().clone()),
+        }
+    }
+
+    /// Adds the statistics accumulated by `other`, a copy of this
+    /// pipeline that processed different reads.
+    pub fn merge(&mut self, other: &Pipeline) {
+        fn merge_stage<T>(stage: &mut Option<T>, other: &Option<T>, merge: fn(&mut T, &T)) {
+            if let (Some(stage), Some(other)) = (stage, other) {
+                merge(stage, other);
+            }
+        }
+
+        merge_stage(&mut self.qual_trim_start, &other.qual_trim_start, QualTrimStart::merge);
+        merge_stage(&mut self.qual_trim_end, &other.qual_trim_end, QualTrimEnd::merge);
+        merge_stage(&mut self.barcodes, &other.barcodes, Barcodes::merge);
+        merge_stage(&mut self.adapters, &other.adapters, Adapters::merge);
+        merge_stage(&mut self.length_filter, &other.length_filter, LengthFilter::merge);
+        merge_stage(&mut self.adapter_filter, &other.adapter_filter, AdapterFilter::merge);
+        merge_stage(&mut self.expected_errors_filter, &other.expected_errors_filter, ExpectedErrorsFilter::merge);
+        merge_stage(&mut self.n_filter, &other.n_filter, NFilter::merge);
+        merge_stage(&mut self.low_complexity_filter, &other.low_complexity_filter, LowComplexityFilter::merge);
+        merge_stage(&mut self.contaminant_filter, &other.contaminant_filter, ContaminantFilter::merge);
+    }
+}
+
+/// Statistics of each stage of a pipeline, with `None` for stages
+/// that are not in the pipeline.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PipelineStats {
+    /// Reads trimmed and bases removed
+    pub qual_trim_start: Option<ReadCount>,
+    /// Reads trimmed and bases removed
+    pub qual_trim_end: Option<ReadCount>,
+    pub barcodes: Option<BarcodeStats>,
+    pub adapters: Option<Vec<AdapterStats>>,
+    pub length_filter: Option<LengthFilterStats>,
+    pub adapter_filter: Option<FilterStats>,
+    pub expected_errors_filter: Option<FilterStats>,
+    pub n_filter: Option<FilterStats>,
+    pub low_complexity_filter: Option<LowComplexityStats>,
+    pub contaminant_filter: Option<ContaminantStats>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct BarcodeStats {
+    pub names: Vec<String>,
+    /// Number of reads with each barcode, in the order of `names`
+    pub reads: Vec<usize>,
+    pub no_barcode: usize,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct FilterStats {
+    /// Number of reads assigned a fate by the filter
+    pub filtered: usize,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct LowComplexityStats {
+    pub filtered: usize,
+    /// Number of reads with masked windows
+    pub masked: usize,
+}
+
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
+pub struct PairedPipelineConf {
+    read1: PipelineConf,
+    read2: PipelineConf,
+    #[serde(default)]
+    pair_filter: PairFilterMode,
+}
+
+impl PairedPipelineConf {
+    pub fn paired_pipeline(&self) -> Result<PairedPipeline> {
+        Ok(PairedPipeline {
+            read1: self.read1.pipeline()?,
+            read2: self.read2.pipeline()?,
+            pair_filter: self.pair_filter,
+        })
     }
 }
 
+/// Separate processing pipelines for read 1 and read 2 of a pair,
+/// after which both reads share a single fate, harmonized according
+/// to the pair filter mode.
+#[derive(Clone, Debug)]
+pub struct PairedPipeline {
+    read1: Pipeline,
+    read2: Pipeline,
+    pair_filter: PairFilterMode,
+}
+
+impl PairedPipeline {
+    pub fn process(&mut self, trimming1: &mut Trimming, trimming2: &mut Trimming) {
+        self.read1.process(trimming1);
+        self.read2.process(trimming2);
+
+        let fate = Fate::harmonize(trimming1.fate(), trimming2.fate(), self.pair_filter);
+        for (pipeline, trimming) in [(&mut self.read1, &*trimming1), (&mut self.read2, &*trimming2)].iter_mut() {
+            if trimming.fate() != &fate {
+                if let Some(lf) = &mut pipeline.length_filter {
+                    lf.overrule(trimming.fate());
+                }
+            }
+        }
+        trimming1.set_fate(fate.clone());
+        trimming2.set_fate(fate);
+    }
+
+    pub fn read1(&self) -> &Pipeline {
+        &self.read1
+    }
+
+    pub fn read2(&self) -> &Pipeline {
+        &self.read2
+    }
+
+    /// Sets whether adapter alignments are computed for read 1 and for
+    /// read 2.
+    pub fn set_traceback(&mut self, traceback1: bool, traceback2: bool) {
+        self.read1.set_traceback(traceback1);
+        self.read2.set_traceback(traceback2);
+    }
+
+    /// Identifies `barcodes1` in read 1 and `barcodes2` in read 2, as
+    /// with `Pipeline::set_barcodes`.
+    pub fn set_barcodes(&mut self, barcodes1: Barcodes, barcodes2: Barcodes) -> Result<()> {
+        self.read1.set_barcodes(barcodes1)?;
+        self.read2.set_barcodes(barcodes2)
+    }
+
+    pub fn merge(&mut self, other: &PairedPipeline) {
+        self.read1.merge(&other.read1);
+        self.read2.merge(&other.read2);
+    }
+}
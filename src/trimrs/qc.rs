@@ -0,0 +1,241 @@
+//! Quality control statistics on reads, as in the basic modules of
+//! `FastQC`: mean quality and base composition at each position, and
+//! the distributions of read length and GC content.
+//!
+//! Statistics are collected on the raw reads and again on the trimmed
+//! reads written to the output, so that the effect of trimming can be
+//! seen. Qualities are Phred scores, by default with an offset of 33.
+
+use serde::{Serialize, Deserialize};
+
+use crate::stats::LengthHistogram;
+use crate::trimming::Trimming;
+
+fn default_quality_base() -> u8 { 33 }
+
+/// Base composition and qualities at one read position.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PositionStats {
+    /// Counts of `A`, `C`, `G`, `T`, and any other base
+    pub bases: [usize; 5],
+    /// Sum of the quality scores
+    pub qual_sum: u64,
+    /// Number of reads with a quality score
+    pub quals: usize,
+}
+
+impl PositionStats {
+    pub fn merge(&mut self, other: &PositionStats) {
+        for (count, other_count) in self.bases.iter_mut().zip(other.bases.iter()) {
+            *count += *other_count;
+        }
+        self.qual_sum += other.qual_sum;
+        self.quals += other.quals;
+    }
+
+    /// Number of reads covering the position
+    pub fn reads(&self) -> usize {
+        self.bases.iter().sum()
+    }
+
+    pub fn mean_quality(&self) -> Option<f64> {
+        if self.quals == 0 {
+            None
+        } else {
+            Some(self.qual_sum as f64 / self.quals as f64)
+        }
+    }
+}
+
+/// Statistics on a set of read sequences.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SeqQc {
+    /// Statistics at each position, starting from the first base
+    pub positions: Vec<PositionStats>,
+    pub lengths: LengthHistogram,
+    /// Number of reads by the percentage of GC among their `A`, `C`,
+    /// `G`, and `T` bases, rounded, from 0 to 100. Reads without any
+    /// of these bases are not counted.
+    pub gc: Vec<usize>,
+}
+
+impl Default for SeqQc {
+    fn default() -> Self {
+        SeqQc { positions: Vec::new(), lengths: LengthHistogram::new(), gc: vec![0; 101] }
+    }
+}
+
+impl SeqQc {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a read with qualities encoded as `quality_base` plus the
+    /// Phred score.
+    pub fn add(&mut self, seq: &[u8], qual: Option<&[u8]>, quality_base: u8) {
+        if seq.len() > self.positions.len() {
+            self.positions.resize(seq.len(), PositionStats::default());
+        }
+        self.lengths.add(seq.len());
+
+        let mut gc = 0usize;
+        let mut acgt = 0;
+        for (position, &nt) in self.positions.iter_mut().zip(seq.iter()) {
+            let base = match nt {
+                b'A' | b'a' => 0,
+                b'C' | b'c' => 1,
+                b'G' | b'g' => 2,
+                b'T' | b't' => 3,
+                _ => 4,
+            };
+            position.bases[base] += 1;
+            if base < 4 {
+                acgt += 1;
+                if base == 1 || base == 2 {
+                    gc += 1;
+                }
+            }
+        }
+        if let Some(percent) = (100 * gc + acgt / 2).checked_div(acgt) {
+            self.gc[percent] += 1;
+        }
+
+        if let Some(qual) = qual {
+            for (position, &q) in self.positions.iter_mut().zip(qual.iter()) {
+                position.qual_sum += u64::from(q.saturating_sub(quality_base));
+                position.quals += 1;
+            }
+        }
+    }
+
+    pub fn merge(&mut self, other: &SeqQc) {
+        if other.positions.len() > self.positions.len() {
+            self.positions.resize(other.positions.len(), PositionStats::default());
+        }
+        for (position, other_position) in self.positions.iter_mut().zip(other.positions.iter()) {
+            position.merge(other_position);
+        }
+        self.lengths.merge(&other.lengths);
+        for (count, other_count) in self.gc.iter_mut().zip(other.gc.iter()) {
+            *count += *other_count;
+        }
+    }
+
+    /// Number of reads
+    pub fn reads(&self) -> usize {
+        self.lengths.total()
+    }
+}
+
+/// Statistics on single-end reads, or on one read of read pairs,
+/// before and after trimming.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReadQc {
+    /// Offset of the quality scores
+    #[serde(default = "default_quality_base")]
+    pub quality_base: u8,
+    /// All raw reads
+    pub raw: SeqQc,
+    /// Trimmed reads with `Output` fate, with masked bases as `N`
+    pub trimmed: SeqQc,
+    /// Buffer for the sequences of masked reads
+    #[serde(skip)]
+    masked: Vec<u8>,
+}
+
+impl Default for ReadQc {
+    fn default() -> Self {
+        ReadQc::new(default_quality_base())
+    }
+}
+
+impl PartialEq for ReadQc {
+    fn eq(&self, other: &ReadQc) -> bool {
+        self.quality_base == other.quality_base && self.raw == other.raw && self.trimmed == other.trimmed
+    }
+}
+
+impl Eq for ReadQc {}
+
+impl ReadQc {
+    /// Creates empty statistics for qualities encoded as
+    /// `quality_base` plus the Phred score.
+    pub fn new(quality_base: u8) -> Self {
+        ReadQc { quality_base, raw: SeqQc::new(), trimmed: SeqQc::new(), masked: Vec::new() }
+    }
+
+    pub fn add(&mut self, trimming: &Trimming) {
+        self.raw.add(trimming.seq_raw(), trimming.qual_raw(), self.quality_base);
+        if trimming.fate().is_output() {
+            if trimming.n_masks().is_empty() {
+                self.trimmed.add(trimming.seq_trimmed(), trimming.qual_trimmed(), self.quality_base);
+            } else {
+                trimming.seq_trimmed_masked(&mut self.masked);
+                self.trimmed.add(&self.masked, trimming.qual_trimmed(), self.quality_base);
+            }
+        }
+    }
+
+    pub fn merge(&mut self, other: &ReadQc) {
+        self.raw.merge(&other.raw);
+        self.trimmed.merge(&other.trimmed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::output::Fate;
+
+    #[test]
+    fn seq_qc() {
+        let mut qc = SeqQc::new();
+        qc.add(b"ACGT", Some(b"+5?I"), 33);
+        qc.add(b"GGN", None, 33);
+        let mut other = SeqQc::new();
+        other.add(b"NNNNNN", Some(b"!!!!!!"), 33);
+        qc.merge(&other);
+
+        assert_eq!(qc.reads(), 3);
+        assert_eq!(qc.positions.len(), 6);
+        assert_eq!(qc.positions[0].bases, [1, 0, 1, 0, 1]);
+        assert_eq!(qc.positions[3].mean_quality(), Some(20.0));
+        assert_eq!(qc.positions[4].reads(), 1);
+        assert_eq!(qc.lengths.count(3), 1);
+        assert_eq!(qc.gc[50], 1);
+        assert_eq!(qc.gc[100], 1);
+        assert_eq!(qc.gc.iter().sum::<usize>(), 2);
+    }
+
+    #[test]
+    fn read_qc() {
+        let mut qc = ReadQc::default();
+        let mut trimming = Trimming::new(b"r1", b"ACGTACGT", Some(b"IIIIIIII"));
+        trimming.trim_from_end(4);
+        trimming.mask_trimmed(0, 1);
+        qc.add(&trimming);
+        trimming.set_fate(Fate::TooShort);
+        qc.add(&trimming);
+
+        assert_eq!(qc.raw.reads(), 2);
+        assert_eq!(qc.raw.lengths.count(8), 2);
+        assert_eq!(qc.trimmed.reads(), 1);
+        assert_eq!(qc.trimmed.lengths.count(4), 1);
+        assert_eq!(qc.trimmed.positions[0].bases, [0, 0, 0, 0, 1]);
+        assert_eq!(qc.trimmed.positions[0].mean_quality(), Some(40.0));
+    }
+
+    #[test]
+    fn quality_base() {
+        let mut qc = ReadQc::new(64);
+        let mut trimming = Trimming::new(b"r1", b"ACGT", Some(b"hhhh"));
+        trimming.mask_trimmed(0, 2);
+        qc.add(&trimming);
+        qc.add(&Trimming::new(b"r2", b"ACGT", Some(b"TTTT")));
+
+        assert_eq!(qc.raw.positions[0].mean_quality(), Some(30.0));
+        assert_eq!(qc.trimmed.positions[0].bases, [1, 0, 0, 0, 1]);
+        assert_eq!(qc.trimmed.positions[2].bases, [0, 0, 2, 0, 0]);
+    }
+}
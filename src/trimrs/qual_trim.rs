@@ -16,21 +16,27 @@
 //! position is high-quality and no trimming is performed, or the
 //! low-quality region encompasses the entire read.
 //!
+//! Qualities are compared with the `threshold` after subtracting the
+//! quality `base`, usually 33 for FASTQ files, as in the
+//! `quality_trim_index` function of `Cutadapt`.
+//!
 //! The algorithm is taken from `Cutadapt`, which in turn took it from
 //! `BWA`.
 
 use serde::{Serialize, Deserialize};
 
+use crate::stats::ReadCount;
 use crate::trimming::Trimming;
 
 #[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash, Serialize, Deserialize)]
 pub struct QualTrimEnd {
     threshold_i: isize,
+    trimmed: ReadCount,
 }
 
 impl QualTrimEnd {
-    pub fn new(threshold: u8) -> Self {
-        QualTrimEnd { threshold_i: threshold as isize }
+    pub fn new(threshold: u8, base: u8) -> Self {
+        QualTrimEnd { threshold_i: threshold as isize + base as isize, trimmed: ReadCount::default() }
     }
     
     /// Computes read length remaining after dropping low-quality bases
@@ -61,21 +67,38 @@ impl QualTrimEnd {
         return lowest_offset;
     }
 
-    pub fn trim(&self, trimming: &mut Trimming) {
+    pub fn trim(&mut self, trimming: &mut Trimming) {
         if let Some(quals) = trimming.qual_trimmed() {
-            trimming.trim_from_end(self.remaining(quals));
+            // `remaining` is the length kept, unlike `trim_from_end`,
+            // which takes the length removed.
+            let removed = quals.len() - self.remaining(quals);
+            if removed > 0 {
+                self.trimmed.add(removed);
+                trimming.trim_from_end(removed);
+            }
         }
     }
+
+    /// Number of reads trimmed and of bases removed
+    pub fn trimmed(&self) -> ReadCount {
+        self.trimmed
+    }
+
+    /// Adds the statistics of `other`, a copy of this trimmer.
+    pub fn merge(&mut self, other: &Self) {
+        self.trimmed.merge(&other.trimmed);
+    }
 }
 
 #[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash, Serialize, Deserialize)]
 pub struct QualTrimStart {
     threshold_i: isize,
+    trimmed: ReadCount,
 }
 
 impl QualTrimStart {
-    pub fn new(threshold: u8) -> Self {
-        QualTrimStart { threshold_i: threshold as isize }
+    pub fn new(threshold: u8, base: u8) -> Self {
+        QualTrimStart { threshold_i: threshold as isize + base as isize, trimmed: ReadCount::default() }
     }
 
     pub fn remaining(&self, quals: &[u8]) -> usize {
@@ -97,11 +120,26 @@ impl QualTrimStart {
         return lowest_offset;
     }
 
-    pub fn trim(&self, trimming: &mut Trimming) {
+    pub fn trim(&mut self, trimming: &mut Trimming) {
         if let Some(quals) = trimming.qual_trimmed() {
-            trimming.trim_from_start(self.remaining(quals));
+            // Here `remaining` is the length of the low-quality prefix.
+            let removed = self.remaining(quals);
+            if removed > 0 {
+                self.trimmed.add(removed);
+                trimming.trim_from_start(removed);
+            }
         }
     }
+
+    /// Number of reads trimmed and of bases removed
+    pub fn trimmed(&self) -> ReadCount {
+        self.trimmed
+    }
+
+    /// Adds the statistics of `other`, a copy of this trimmer.
+    pub fn merge(&mut self, other: &Self) {
+        self.trimmed.merge(&other.trimmed);
+    }
 }
 
 // Cutadapt example for end trimming
@@ -118,7 +156,7 @@ mod tests {
 
     #[test]
     fn quality_trim_end() {
-        let qual_trim = QualTrimEnd::new(10);
+        let qual_trim = QualTrimEnd::new(10, 0);
         
         assert_eq!(qual_trim.remaining(&vec![]), 0);
         
@@ -143,11 +181,14 @@ mod tests {
         assert_eq!(qual_trim.remaining(&vec![20, 20, 9, 12, 9, 10]), 4);
 
         assert_eq!(qual_trim.remaining(&vec![42, 40, 26, 27, 8, 7, 11, 4, 2, 3]), 4);
+
+        let qual_trim = QualTrimEnd::new(10, 33);
+        assert_eq!(qual_trim.remaining(b"KI;<)(,%#$"), 4);
     }
 
     #[test]
     fn quality_trim_start() {
-        let qual_trim = QualTrimStart::new(10);
+        let qual_trim = QualTrimStart::new(10, 0);
         
         assert_eq!(qual_trim.remaining(&vec![]), 0);
         
@@ -173,6 +214,43 @@ mod tests {
 
         
         assert_eq!(qual_trim.remaining(&vec![3, 2, 4, 11, 7, 8, 27, 26, 40, 42]), 6);
+
+        let qual_trim = QualTrimStart::new(10, 33);
+        assert_eq!(qual_trim.remaining(b"$#%,()<;IK"), 6);
+    }
+
+    #[test]
+    fn trim_end() {
+        let mut qual_trim = QualTrimEnd::new(10, 33);
+        let mut trimming = Trimming::new(b"read", b"ACGTACGTAC", Some(b"KI;<)(,%#$"));
+        qual_trim.trim(&mut trimming);
+        assert_eq!(trimming.seq_trimmed(), b"ACGT");
+        assert_eq!(trimming.len_trimmed(), 4);
+
+        let mut trimming = Trimming::new(b"read", b"ACGT", Some(b"KKKK"));
+        qual_trim.trim(&mut trimming);
+        assert_eq!(trimming.len_trimmed(), 4);
+
+        let mut trimming = Trimming::new(b"read", b"ACG", Some(b"###"));
+        qual_trim.trim(&mut trimming);
+        assert_eq!(trimming.len_trimmed(), 0);
+
+        assert_eq!(qual_trim.trimmed(), ReadCount { reads: 2, bases: 9 });
+    }
+
+    #[test]
+    fn trim_start() {
+        let mut qual_trim = QualTrimStart::new(10, 33);
+        let mut trimming = Trimming::new(b"read", b"ACGTACGTAC", Some(b"$#%,()<;IK"));
+        qual_trim.trim(&mut trimming);
+        assert_eq!(trimming.seq_trimmed(), b"GTAC");
+        assert_eq!(trimming.len_trimmed(), 4);
+
+        let mut trimming = Trimming::new(b"read", b"ACGT", Some(b"KKKK"));
+        qual_trim.trim(&mut trimming);
+        assert_eq!(trimming.len_trimmed(), 4);
+
+        assert_eq!(qual_trim.trimmed(), ReadCount { reads: 1, bases: 6 });
     }
 
 }
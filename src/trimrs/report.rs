@@ -0,0 +1,586 @@
+//! Reports of the statistics from a run: a human-readable report
+//! following the full report of `Cutadapt`, and a machine-readable
+//! JSON report.
+//!
+//! The number of reads expected to have adapter sequence removed by
+//! chance assumes that read bases are independent, with the given GC
+//! content.
+//!
+//! The MultiQC report is a custom-content section, a bar graph of
+//! read fates for each sample, which MultiQC picks up from files named
+//! `*_mqc.json`.
+//!
+//! The quality control TSV has one value per line, in columns `read`
+//! (1 or 2), `stage` (`raw` or `trimmed`), `metric`, `bin`, and
+//! `value`. For the per-position metrics `mean_quality` and the base
+//! counts `A`, `C`, `G`, `T`, and `N`, the bin is the 1-based cycle.
+//! For the `length` and `gc` distributions, it is the read length or
+//! the GC percentage, and only non-empty bins are listed.
+//!
+//! The JSON report is the serialization of `JsonReport`, and so its
+//! schema follows the configuration and statistics structs. Once a
+//! version is released, any change to those structs that alters the
+//! JSON report must increment `JSON_SCHEMA_VERSION`.
+
+use std::fmt::{self, Write};
+use std::time::Duration;
+
+use serde::{Serialize, Serializer, Deserialize};
+
+use crate::adapter::{AdapterKind, AdapterStats};
+use crate::demux::CombinationCounts;
+use crate::output::Fate;
+use crate::pipeline::{Pipeline, PipelineStats};
+use crate::qc::{ReadQc, SeqQc};
+use crate::runner::{RunConf, RunOutcome};
+use crate::stats::FateCounts;
+
+pub const JSON_SCHEMA_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct JsonReport {
+    pub schema_version: u32,
+    pub program_version: String,
+    /// Configuration of the run, including input and output paths
+    pub conf: RunConf,
+    pub elapsed_seconds: f64,
+    pub paired: bool,
+    /// Counts of input reads and of reads with each fate, or of read
+    /// pairs for paired-end runs
+    pub counts: FateCounts,
+    pub read1: PipelineStats,
+    /// Read 2 statistics for paired-end runs
+    pub read2: Option<PipelineStats>,
+    /// Quality control statistics on single-end reads, or on read 1
+    /// and read 2, empty when not collected
+    pub qc: Vec<ReadQc>,
+    /// Read pairs written for each combination of barcodes, when
+    /// demultiplexing
+    pub combinations: Option<CombinationCounts>,
+}
+
+impl JsonReport {
+    pub fn new(conf: &RunConf, outcome: &RunOutcome, elapsed: Duration) -> Self {
+        let (read1, read2) = match outcome {
+            RunOutcome::Single(summary) => (summary.pipeline().stats(), None),
+            RunOutcome::Paired(summary) => {
+                (summary.pipeline().read1().stats(), Some(summary.pipeline().read2().stats()))
+            }
+        };
+        JsonReport {
+            schema_version: JSON_SCHEMA_VERSION,
+            program_version: env!("CARGO_PKG_VERSION").to_string(),
+            conf: conf.clone(),
+            elapsed_seconds: elapsed.as_secs_f64(),
+            paired: read2.is_some(),
+            counts: outcome.counts().clone(),
+            read1,
+            read2,
+            qc: outcome.qc().to_vec(),
+            combinations: outcome.combinations().cloned(),
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+}
+
+/// Renders the full report for a run that took `elapsed`.
+pub fn full_report(outcome: &RunOutcome, elapsed: Duration, gc_content: f64) -> String {
+    let mut report = String::new();
+    match outcome {
+        RunOutcome::Single(summary) => {
+            write_report(&mut report, summary.counts(), &[("", summary.pipeline())], None, elapsed, gc_content)
+        }
+        RunOutcome::Paired(summary) => {
+            let pipeline = summary.pipeline();
+            let pipelines = [("First read: ", pipeline.read1()), ("Second read: ", pipeline.read2())];
+            write_report(&mut report, summary.counts(), &pipelines, summary.combinations(), elapsed, gc_content)
+        }
+    }.expect("Writing report to a string");
+    report.truncate(report.trim_end().len());
+    report
+}
+
+fn write_report(out: &mut String, counts: &FateCounts, pipelines: &[(&str, &Pipeline)],
+                combinations: Option<&CombinationCounts>, elapsed: Duration, gc_content: f64) -> fmt::Result {
+    let n = counts.input().reads;
+    if n == 0 {
+        return write!(out, "No reads processed!");
+    }
+
+    let secs = elapsed.as_secs_f64().max(f64::MIN_POSITIVE);
+    writeln!(out, "Finished in {:.2} s ({:.0} us/read; {:.2} M reads/minute).",
+             secs, 1e6 * secs / n as f64, n as f64 / secs * 60.0 / 1e6)?;
+
+    let paired = pipelines.len() > 1;
+    let reads = if paired { "Pairs" } else { "Reads" };
+    writeln!(out)?;
+    writeln!(out, "=== Summary ===")?;
+    writeln!(out)?;
+    summary_line(out, if paired { "Total read pairs processed:" } else { "Total reads processed:" }, n, None)?;
+
+    for (idx, (_, pipeline)) in pipelines.iter().enumerate() {
+        if let Some(adapters) = pipeline.adapters() {
+            let trimmed = adapters.stats().iter().map(|s| s.trimmed.reads).sum();
+            let label = if paired { format!("  Read {} with adapter:", idx + 1) } else { "Reads with adapters:".to_string() };
+            summary_line(out, &label, trimmed, Some(n))?;
+        }
+    }
+
+    for (idx, (_, pipeline)) in pipelines.iter().enumerate() {
+        if let Some(masked) = pipeline.low_complexity_filter().map(|f| f.masked()).filter(|&m| m > 0) {
+            let label = if paired { format!("  Read {} masked:", idx + 1) } else { "Reads masked:".to_string() };
+            summary_line(out, &label, masked, Some(n))?;
+        }
+    }
+
+    for (fate, count) in counts.iter().filter(|(fate, _)| !fate.is_output()) {
+        summary_line(out, &format!("{} {}:", reads, fate_description(fate)), count.reads, Some(n))?;
+    }
+    summary_line(out, &format!("{} written (passing filters):", reads), counts.fate(&Fate::Output).reads, Some(n))?;
+
+    let total_bp = counts.input().bases;
+    writeln!(out)?;
+    writeln!(out, "Total basepairs processed: {:>13} bp", thousands(total_bp))?;
+    let qual_trims = pipelines.iter()
+        .flat_map(|(_, p)| p.qual_trim_start().map(|q| q.trimmed()).into_iter()
+                  .chain(p.qual_trim_end().map(|q| q.trimmed())))
+        .collect::<Vec<_>>();
+    if !qual_trims.is_empty() {
+        let qual_trimmed = qual_trims.iter().map(|count| count.bases).sum();
+        writeln!(out, "Quality-trimmed:           {:>13} bp ({})", thousands(qual_trimmed), percent(qual_trimmed, total_bp))?;
+    }
+    let written_bp = counts.fate(&Fate::Output).bases;
+    writeln!(out, "Total written (filtered):  {:>13} bp ({})", thousands(written_bp), percent(written_bp, total_bp))?;
+    writeln!(out)?;
+
+    let mut warning = false;
+    for (extra, pipeline) in pipelines.iter() {
+        if let Some(barcodes) = pipeline.barcodes() {
+            writeln!(out, "=== {}Barcodes ===", extra)?;
+            writeln!(out)?;
+            writeln!(out, "barcode\treads")?;
+            for (name, count) in barcodes.names().iter().zip(barcodes.counts().iter()) {
+                writeln!(out, "{}\t{}", name, count)?;
+            }
+            writeln!(out, "none\t{}", barcodes.no_barcode())?;
+            writeln!(out)?;
+            for collision in barcodes.collisions() {
+                writeln!(out, "WARNING:")?;
+                writeln!(out, "    {}", collision)?;
+            }
+            if !barcodes.collisions().is_empty() {
+                writeln!(out)?;
+            }
+        }
+
+        if let Some(adapters) = pipeline.adapters() {
+            for stats in adapters.stats() {
+                warning |= write_adapter(out, extra, stats, n, gc_content)?;
+            }
+        }
+
+        if let Some(contaminants) = pipeline.contaminant_filter() {
+            let stats = contaminants.stats();
+            writeln!(out, "=== {}Contaminants ===", extra)?;
+            writeln!(out)?;
+            writeln!(out, "contaminant\treads")?;
+            for (name, count) in stats.names.iter().zip(stats.reads.iter()) {
+                writeln!(out, "{}\t{}", name, count)?;
+            }
+            writeln!(out, "ambiguous\t{}", stats.ambiguous)?;
+            writeln!(out)?;
+        }
+    }
+
+    if let Some(combinations) = combinations {
+        writeln!(out, "=== Demultiplexing ===")?;
+        writeln!(out)?;
+        writeln!(out, "{}", combinations)?;
+    }
+
+    if warning {
+        writeln!(out, "WARNING:")?;
+        writeln!(out, "    One or more of your adapter sequences may be incomplete.")?;
+        writeln!(out, "    Please see the detailed output above.")?;
+    }
+
+    Ok(())
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct MultiqcReport {
+    id: &'static str,
+    section_name: &'static str,
+    description: &'static str,
+    plot_type: &'static str,
+    pconfig: MultiqcPlotConfig,
+    /// Categories for each sample, in report order
+    #[serde(serialize_with = "ordered_map")]
+    data: Vec<(String, OrderedCounts)>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct MultiqcPlotConfig {
+    id: &'static str,
+    title: &'static str,
+    ylab: &'static str,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct OrderedCounts(#[serde(serialize_with = "ordered_map")] Vec<(&'static str, usize)>);
+
+fn ordered_map<K: Serialize, V: Serialize, S: Serializer>(pairs: &[(K, V)], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(pairs.iter().map(|(k, v)| (k, v)))
+}
+
+impl MultiqcReport {
+    /// Creates a report of the read fates for one sample.
+    pub fn new(sample: &str, counts: &FateCounts) -> Self {
+        let mut fates = vec![("Passing filters", counts.fate(&Fate::Output).reads)];
+        fates.extend(counts.iter().filter(|(fate, _)| !fate.is_output())
+                     .map(|(fate, count)| (fate_label(fate), count.reads)));
+        MultiqcReport {
+            id: "trimrs",
+            section_name: "trimrs",
+            description: "Reads passing filters or assigned a fate by trimrs, counting pairs for paired-end data.",
+            plot_type: "bargraph",
+            pconfig: MultiqcPlotConfig { id: "trimrs_fates", title: "trimrs: Read fates", ylab: "Reads" },
+            data: vec![(sample.to_string(), OrderedCounts(fates))],
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+}
+
+/// Renders quality control statistics, for single-end reads or for
+/// read 1 and read 2, as TSV.
+pub fn qc_tsv(qc: &[ReadQc]) -> String {
+    let mut tsv = String::from("read\tstage\tmetric\tbin\tvalue\n");
+    for (idx, read_qc) in qc.iter().enumerate() {
+        for (stage, seq_qc) in [("raw", &read_qc.raw), ("trimmed", &read_qc.trimmed)].iter() {
+            write_seq_qc(&mut tsv, &format!("{}\t{}", idx + 1, stage), seq_qc)
+                .expect("Writing TSV to a string");
+        }
+    }
+    tsv
+}
+
+fn write_seq_qc(out: &mut String, prefix: &str, qc: &SeqQc) -> fmt::Result {
+    for (idx, position) in qc.positions.iter().enumerate() {
+        if let Some(mean) = position.mean_quality() {
+            writeln!(out, "{}\tmean_quality\t{}\t{:.2}", prefix, idx + 1, mean)?;
+        }
+    }
+    for (base, label) in ["A", "C", "G", "T", "N"].iter().enumerate() {
+        for (idx, position) in qc.positions.iter().enumerate() {
+            writeln!(out, "{}\t{}\t{}\t{}", prefix, label, idx + 1, position.bases[base])?;
+        }
+    }
+    for (len, count) in qc.lengths.iter() {
+        writeln!(out, "{}\tlength\t{}\t{}", prefix, len, count)?;
+    }
+    for (gc, &count) in qc.gc.iter().enumerate().filter(|&(_, &count)| count > 0) {
+        writeln!(out, "{}\tgc\t{}\t{}", prefix, gc, count)?;
+    }
+    Ok(())
+}
+
+fn fate_label(fate: &Fate) -> &'static str {
+    match fate {
+        Fate::Output => "Passing filters",
+        Fate::TooShort => "Too short",
+        Fate::TooLong => "Too long",
+        Fate::NoAdapter => "No adapter",
+        Fate::HasAdapter => "Has adapter",
+        Fate::TooManyExpectedErrors => "Too many expected errors",
+        Fate::TooManyN => "Too many N",
+        Fate::LowComplexity => "Low complexity",
+        Fate::Contaminant => "Contaminant",
+    }
+}
+
+fn summary_line(out: &mut String, label: &str, count: usize, total: Option<usize>) -> fmt::Result {
+    write!(out, "{:<33}{:>13}", label, thousands(count))?;
+    match total {
+        Some(total) => writeln!(out, " ({})", percent(count, total)),
+        None => writeln!(out),
+    }
+}
+
+fn fate_description(fate: &Fate) -> &'static str {
+    match fate {
+        Fate::Output => "written",
+        Fate::TooShort => "that were too short",
+        Fate::TooLong => "that were too long",
+        Fate::NoAdapter => "without adapters",
+        Fate::HasAdapter => "with adapters discarded",
+        Fate::TooManyExpectedErrors => "with too many expected errors",
+        Fate::TooManyN => "with too many N",
+        Fate::LowComplexity => "with low complexity",
+        Fate::Contaminant => "from contaminants",
+    }
+}
+
+fn kind_description(kind: AdapterKind) -> &'static str {
+    match kind {
+        AdapterKind::ThreePrime => "regular 3'",
+        AdapterKind::FivePrime => "regular 5'",
+        AdapterKind::AnchoredThreePrime => "anchored 3'",
+        AdapterKind::AnchoredFivePrime => "anchored 5'",
+    }
+}
+
+/// Writes the section for one adapter, returning `true` when its
+/// sequence appears to be incomplete.
+fn write_adapter(out: &mut String, extra: &str, stats: &AdapterStats, n: usize, gc_content: f64) -> Result<bool, fmt::Error> {
+    writeln!(out, "=== {}Adapter {} ===", extra, stats.name)?;
+    writeln!(out)?;
+    writeln!(out, "Sequence: {}; Type: {}; Length: {}; Trimmed: {} times",
+             stats.sequence, kind_description(stats.kind), stats.sequence.len(), stats.trimmed.reads)?;
+    if let Some(concatemers) = stats.concatemers {
+        writeln!(out, "Trimmed reads with several matches: {}", concatemers)?;
+    }
+    writeln!(out)?;
+    if stats.trimmed.reads == 0 {
+        return Ok(false);
+    }
+
+    writeln!(out, "{}", error_ranges(stats))?;
+    let warning = if stats.kind.trims_end() {
+        write_adjacent_bases(out, &stats.adjacent_bases)?
+    } else {
+        false
+    };
+    writeln!(out, "Overview of removed sequences")?;
+    writeln!(out, "{}", histogram(stats, n, gc_content))?;
+    Ok(warning)
+}
+
+/// Ranges of match lengths allowing each number of errors
+fn error_ranges(stats: &AdapterStats) -> String {
+    let length = stats.effective_length;
+    let max_errors = (stats.max_error_rate * length as f64) as usize;
+    let mut s = String::new();
+    let mut prev = 0;
+    for errors in 1..=max_errors {
+        let r = (errors as f64 / stats.max_error_rate) as usize;
+        s += &format!("{}-{} bp: {}; ", prev, r - 1, errors - 1);
+        prev = r;
+    }
+    if prev == length {
+        s += &format!("{} bp: {}", length, max_errors);
+    } else {
+        s += &format!("{}-{} bp: {}", prev, length, max_errors);
+    }
+    format!("No. of allowed errors:\n{}\n", s)
+}
+
+/// Table of removed sequence lengths, with the number expected by
+/// chance among `n` reads and the counts for each number of errors.
+fn histogram(stats: &AdapterStats, n: usize, gc_content: f64) -> String {
+    let probabilities = stats.random_match_probabilities(gc_content);
+    let mut s = "length\tcount\texpect\tmax.err\terror counts\n".to_string();
+    for (len, errors) in stats.removed.iter() {
+        // The chance of a match stops decreasing once the whole
+        // adapter is removed.
+        let expect = n as f64 * probabilities[len.min(stats.sequence.len())];
+        let errors = errors.iter().map(|count| count.to_string()).collect::<Vec<_>>();
+        s += &format!("{}\t{}\t{:.1}\t{}\t{}\n",
+                      len, stats.removed.count(len), expect, stats.max_errors(len), errors.join(" "));
+    }
+    s
+}
+
+/// Writes the fractions of bases preceding removed 3' adapters,
+/// returning `true` when one base is so common that the adapter
+/// sequence may be incomplete.
+fn write_adjacent_bases(out: &mut String, bases: &[usize; 5]) -> Result<bool, fmt::Error> {
+    let total: usize = bases.iter().sum();
+    if total == 0 {
+        return Ok(false);
+    }
+
+    let names = ["A", "C", "G", "T", "none/other"];
+    let mut warn_base = None;
+    writeln!(out, "Bases preceding removed adapters:")?;
+    for (idx, (name, &count)) in names.iter().zip(bases.iter()).enumerate() {
+        let fraction = count as f64 / total as f64;
+        writeln!(out, "  {}: {:.1}%", name, 100.0 * fraction)?;
+        if fraction > 0.8 && idx < 4 && total >= 20 {
+            warn_base = Some(name);
+        }
+    }
+    if let Some(name) = warn_base {
+        writeln!(out, "WARNING:")?;
+        writeln!(out, "    The adapter is preceded by \"{}\" extremely often.", name)?;
+        writeln!(out, "    The provided adapter sequence could be incomplete at its 3' end.")?;
+    }
+    writeln!(out)?;
+    Ok(warn_base.is_some())
+}
+
+/// Formats `n` with commas separating thousands.
+fn thousands(n: usize) -> String {
+    let digits = n.to_string();
+    let mut s = String::with_capacity(digits.len() + digits.len() / 3);
+    for (idx, digit) in digits.chars().enumerate() {
+        if idx > 0 && (digits.len() - idx).is_multiple_of(3) {
+            s.push(',');
+        }
+        s.push(digit);
+    }
+    s
+}
+
+fn percent(count: usize, total: usize) -> String {
+    let fraction = if total == 0 { 0.0 } else { count as f64 / total as f64 };
+    format!("{:.1}%", 100.0 * fraction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::adapter::{AdapterConf, Adapters};
+    use crate::input::FastqReader;
+    use crate::output::{FateOutputConf, ReadReportConf};
+    use crate::pipeline::PipelineConf;
+    use crate::runner::Runner;
+
+    fn adapter_stats(sequence: &str, max_error_rate: f64) -> AdapterStats {
+        let adapter = AdapterConf {
+            max_error_rate,
+            ..AdapterConf::with_defaults("a", sequence, AdapterKind::ThreePrime)
+        }.adapter().unwrap();
+        Adapters::new(vec![adapter]).unwrap().stats()[0].clone()
+    }
+
+    #[test]
+    fn allowed_errors() {
+        assert_eq!(error_ranges(&adapter_stats("AGATCGGAAGAGC", 0.1)),
+                   "No. of allowed errors:\n0-9 bp: 0; 10-13 bp: 1\n");
+        assert_eq!(error_ranges(&adapter_stats("AGATCGGAAGAGCACAC", 0.2)),
+                   "No. of allowed errors:\n0-4 bp: 0; 5-9 bp: 1; 10-14 bp: 2; 15-17 bp: 3\n");
+        assert_eq!(error_ranges(&adapter_stats("AGATCGGAAG", 0.1)),
+                   "No. of allowed errors:\n0-9 bp: 0; 10 bp: 1\n");
+    }
+
+    #[test]
+    fn number_formats() {
+        assert_eq!(thousands(0), "0");
+        assert_eq!(thousands(999), "999");
+        assert_eq!(thousands(1000), "1,000");
+        assert_eq!(thousands(1234567), "1,234,567");
+        assert_eq!(percent(1, 3), "33.3%");
+        assert_eq!(percent(0, 0), "0.0%");
+    }
+
+    #[test]
+    fn json_report() {
+        let conf: RunConf = toml::from_str(concat!(
+            "input = \"in.fastq\"\noutput = \"out.fastq\"\n",
+            "[pipeline]\nadapters = [{ name = \"ill\", sequence = \"AGATCGGAAG\", kind = \"three_prime\" }]\n",
+            "[pipeline.length_filter]\nmin_length = 5\n")).unwrap();
+        let pipeline_conf: PipelineConf = toml::from_str(concat!(
+            "adapters = [{ name = \"ill\", sequence = \"AGATCGGAAG\", kind = \"three_prime\" }]\n",
+            "[length_filter]\nmin_length = 5\n")).unwrap();
+        let fastq = b"@r1\nCCCCCCAGATCGGAAG\n+\nIIIIIIIIIIIIIIII\n@r2\nCCCAGATCGGAAG\n+\nIIIIIIIIIIIII\n";
+        let summary = Runner::new(1, 10).unwrap()
+            .run(&pipeline_conf.pipeline().unwrap(), &mut FastqReader::new(&fastq[..]), &mut Vec::new(),
+                 &mut FateOutputConf::default().fate_outputs().unwrap(),
+                 &mut ReadReportConf::default().read_reports().unwrap())
+            .unwrap();
+        let report = JsonReport::new(&conf, &RunOutcome::Single(Box::new(summary)), Duration::from_millis(1500));
+        let json = report.to_json().unwrap();
+
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["schema_version"], JSON_SCHEMA_VERSION);
+        assert_eq!(value["conf"]["input"], "in.fastq");
+        assert_eq!(value["counts"]["fates"]["too_short"]["reads"], 1);
+        assert_eq!(value["read1"]["adapters"][0]["trimmed"]["bases"], 20);
+        assert_eq!(value["read1"]["length_filter"]["too_short"], 1);
+        assert!(value["read1"]["n_filter"].is_null());
+        assert!(value["read2"].is_null());
+        assert_eq!(value["qc"][0]["raw"]["positions"][0]["bases"][1], 2);
+        assert_eq!(value["qc"][0]["trimmed"]["lengths"]["counts"][6], 1);
+
+        assert_eq!(serde_json::from_str::<JsonReport>(&json).unwrap(), report);
+    }
+
+    #[test]
+    fn multiqc_report() {
+        let mut counts = FateCounts::new();
+        counts.add(10, &Fate::TooShort, 2);
+        counts.add(10, &Fate::Output, 8);
+        counts.add(10, &Fate::Contaminant, 10);
+        let json = MultiqcReport::new("sample1", &counts).to_json().unwrap();
+
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["plot_type"], "bargraph");
+        assert_eq!(value["data"]["sample1"]["Passing filters"], 1);
+        assert_eq!(value["data"]["sample1"]["Too short"], 1);
+        let order = ["Passing filters", "Too short", "Contaminant"].iter()
+            .map(|label| json.find(&format!("\"{}\"", label)).unwrap())
+            .collect::<Vec<_>>();
+        assert!(order.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn report() {
+        let conf: PipelineConf = toml::from_str(concat!(
+            "adapters = [{ name = \"ill\", sequence = \"AGATCGGAAG\", kind = \"three_prime\",",
+            " count_concatemers = true }]\n",
+            "[length_filter]\nmin_length = 5\n")).unwrap();
+        let fastq = b"@r1\nCCCCCCAGATCGGAAG\n+\nIIIIIIIIIIIIIIII\n@r2\nCCCAGATCGGAAG\n+\nIIIIIIIIIIIII\n@r3\nCCCCCCCC\n+\nIIIIIIII\n";
+        let summary = Runner::new(1, 10).unwrap()
+            .run(&conf.pipeline().unwrap(), &mut FastqReader::new(&fastq[..]), &mut Vec::new(),
+                 &mut FateOutputConf::default().fate_outputs().unwrap(),
+                 &mut ReadReportConf::default().read_reports().unwrap())
+            .unwrap();
+        let report = full_report(&RunOutcome::Single(Box::new(summary)), Duration::from_secs(1), 0.5);
+
+        assert!(report.contains("Total reads processed:                       3\n"));
+        assert!(report.contains("Reads with adapters:                         2 (66.7%)\n"));
+        assert!(report.contains("Reads that were too short:                   1 (33.3%)\n"));
+        assert!(report.contains("Reads written (passing filters):             2 (66.7%)\n"));
+        assert!(report.contains("Total basepairs processed:            37 bp\n"));
+        assert!(report.contains("Total written (filtered):             14 bp (37.8%)\n"));
+        assert!(report.contains("Sequence: AGATCGGAAG; Type: regular 3'; Length: 10; Trimmed: 2 times\n"));
+        assert!(report.contains("Trimmed reads with several matches: 0\n"));
+        assert!(report.contains("length\tcount\texpect\tmax.err\terror counts\n10\t2\t0.0\t1\t2"));
+        assert!(report.contains("  C: 100.0%\n"));
+    }
+
+    #[test]
+    fn barcode_collisions() {
+        let conf: PipelineConf = toml::from_str(concat!(
+            "[barcodes]\nmax_errors = 1\n",
+            "barcodes = [{ name = \"bc1\", sequence = \"AAAA\" }, { name = \"bc2\", sequence = \"AATT\" }]\n")).unwrap();
+        let fastq = b"@r1\nAAAACCCC\n+\nIIIIIIII\n@r2\nAAATCCCC\n+\nIIIIIIII\n";
+        let summary = Runner::new(1, 10).unwrap()
+            .run(&conf.pipeline().unwrap(), &mut FastqReader::new(&fastq[..]), &mut Vec::new(),
+                 &mut FateOutputConf::default().fate_outputs().unwrap(),
+                 &mut ReadReportConf::default().read_reports().unwrap())
+            .unwrap();
+        let report = full_report(&RunOutcome::Single(Box::new(summary)), Duration::from_secs(1), 0.5);
+        assert!(report.contains("barcode\treads\nbc1\t1\nbc2\t0\nnone\t1\n\nWARNING:\n    Barcodes bc1 and bc2 are very similar"));
+    }
+
+    #[test]
+    fn qc() {
+        let mut read_qc = ReadQc::default();
+        read_qc.raw.add(b"AC", Some(b"I5"), 33);
+        read_qc.trimmed.add(b"A", None, 33);
+        let tsv = qc_tsv(&[read_qc]);
+        assert!(tsv.starts_with("read\tstage\tmetric\tbin\tvalue\n1\traw\tmean_quality\t1\t40.00\n"));
+        assert!(tsv.contains("1\traw\tmean_quality\t2\t20.00\n1\traw\tA\t1\t1\n1\traw\tA\t2\t0\n"));
+        assert!(tsv.contains("1\traw\tlength\t2\t1\n1\traw\tgc\t50\t1\n"));
+        assert!(tsv.contains("1\ttrimmed\tA\t1\t1\n"));
+        assert!(!tsv.contains("trimmed\tmean_quality"));
+    }
+}
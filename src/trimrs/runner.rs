@@ -0,0 +1,831 @@
+//! Multi-threaded processing of reads in chunks.
+//!
+//! One thread reads chunks of records, a pool of workers processes
+//! each chunk with its own copy of the pipeline, and the calling
+//! thread writes the processed chunks in input order, as in the
+//! `OrderedChunkWriter` of `Cutadapt`. At most twice as many chunks
+//! as workers are read but not yet written, which bounds memory use
+//! even when one chunk is slow to process. The statistics of the
+//! worker copies of the pipeline are merged at the end.
+
+use std::collections::{BTreeMap, HashSet};
+use std::io::{BufRead, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Serialize, Deserialize};
+
+use crate::barcode::BarcodeCollision;
+use crate::demux::{CombinationCounts, CombinatorialDemultiplexer, CombinatorialDemuxConf};
+use crate::input::{FastqReader, Record};
+use crate::output::{create, write_trimmed, Fate, FateOutputConf, FateOutputs, PairedFateOutputs,
+                    ReadReport, ReadReportConf, ReadReports};
+use crate::pipeline::{PairedPipeline, PairedPipelineConf, Pipeline, PipelineConf};
+use crate::qc::ReadQc;
+use crate::stats::FateCounts;
+use crate::trimming::Trimming;
+
+fn default_threads() -> usize { 1 }
+
+fn default_chunk_size() -> usize { 1000 }
+
+fn default_qc() -> bool { true }
+
+fn default_quality_base() -> u8 { 33 }
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Serialize, Deserialize)]
+pub struct RunnerConf {
+    #[serde(default = "default_threads")]
+    threads: usize,
+    /// Number of reads, or read pairs, in each chunk
+    #[serde(default = "default_chunk_size")]
+    chunk_size: usize,
+    /// Collect quality control statistics on the reads
+    #[serde(default = "default_qc")]
+    qc: bool,
+    /// Offset of the quality scores in the quality control statistics
+    #[serde(default = "default_quality_base")]
+    quality_base: u8,
+}
+
+impl Default for RunnerConf {
+    fn default() -> Self {
+        RunnerConf {
+            threads: default_threads(),
+            chunk_size: default_chunk_size(),
+            qc: default_qc(),
+            quality_base: default_quality_base(),
+        }
+    }
+}
+
+impl RunnerConf {
+    pub fn runner(&self) -> Result<Runner> {
+        let mut runner = Runner::new(self.threads, self.chunk_size)?;
+        runner.set_qc(self.qc);
+        runner.set_quality_base(self.quality_base);
+        Ok(runner)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Runner {
+    threads: usize,
+    chunk_size: usize,
+    qc: bool,
+    quality_base: u8,
+}
+
+/// Merged pipeline statistics, fate counts, and quality control
+/// statistics from a run.
+#[derive(Clone, Debug)]
+pub struct RunSummary<P> {
+    pipeline: P,
+    counts: FateCounts,
+    qc: Vec<ReadQc>,
+    combinations: Option<CombinationCounts>,
+}
+
+impl<P> RunSummary<P> {
+    /// Pipeline holding the statistics merged from all workers
+    pub fn pipeline(&self) -> &P {
+        &self.pipeline
+    }
+
+    pub fn counts(&self) -> &FateCounts {
+        &self.counts
+    }
+
+    /// Quality control statistics on single-end reads, or on read 1
+    /// and read 2 of read pairs, empty when not collected
+    pub fn qc(&self) -> &[ReadQc] {
+        &self.qc
+    }
+
+    /// Read pairs written for each combination of barcodes, when the
+    /// pairs were demultiplexed
+    pub fn combinations(&self) -> Option<&CombinationCounts> {
+        self.combinations.as_ref()
+    }
+}
+
+/// Where read pairs with `Output` fate are written.
+pub enum PairedOutput<'a> {
+    /// Read 1 and read 2 to one file each
+    Files(&'a mut dyn Write, &'a mut dyn Write),
+    /// Each combination of barcodes to its own files
+    Demultiplexed(&'a mut CombinatorialDemultiplexer),
+}
+
+/// Formatted records of a processed chunk, grouped by fate, and its
+/// read report lines, along with its fate counts and quality control
+/// statistics. Records to be demultiplexed are instead grouped by
+/// their read tags and counted.
+struct ChunkOutput<B> {
+    records: BTreeMap<Fate, B>,
+    tagged: BTreeMap<(Option<usize>, Option<usize>), (B, usize)>,
+    reports: BTreeMap<ReadReport, B>,
+    counts: FateCounts,
+    qc: Vec<ReadQc>,
+}
+
+impl<B: Default> ChunkOutput<B> {
+    /// Creates an empty output, with statistics for `qc_reads` reads
+    /// with qualities offset by `quality_base`
+    fn new(qc_reads: usize, quality_base: u8) -> Self {
+        ChunkOutput {
+            records: BTreeMap::new(),
+            tagged: BTreeMap::new(),
+            reports: BTreeMap::new(),
+            counts: FateCounts::new(),
+            qc: vec![ReadQc::new(quality_base); qc_reads],
+        }
+    }
+
+    fn records(&mut self, fate: &Fate) -> &mut B {
+        self.records.entry(fate.clone()).or_default()
+    }
+
+    fn reports(&mut self, report: ReadReport) -> &mut B {
+        self.reports.entry(report).or_default()
+    }
+}
+
+// Writing into a `Vec` cannot fail.
+fn write_to_vec(out: &mut Vec<u8>, trimming: &Trimming) {
+    write_trimmed(out, trimming).expect("Writing record to memory");
+}
+
+fn merge_qc(qc: &mut Vec<ReadQc>, other: &[ReadQc]) {
+    if qc.is_empty() {
+        qc.extend_from_slice(other);
+    } else {
+        qc.iter_mut().zip(other.iter()).for_each(|(qc, other)| qc.merge(other));
+    }
+}
+
+fn write_report_to_vec(out: &mut Vec<u8>, report: ReadReport, trimming: &Trimming) {
+    report.write(out, trimming).expect("Writing read report to memory");
+}
+
+impl Runner {
+    pub fn new(threads: usize, chunk_size: usize) -> Result<Self> {
+        ensure!(threads >= 1, "At least one thread is needed");
+        ensure!(chunk_size >= 1, "Chunk size must be at least 1");
+        Ok(Runner { threads, chunk_size, qc: true, quality_base: 33 })
+    }
+
+    /// Sets whether quality control statistics are collected, which
+    /// they are by default.
+    pub fn set_qc(&mut self, qc: bool) {
+        self.qc = qc;
+    }
+
+    /// Sets the offset of the quality scores in the quality control
+    /// statistics, 33 by default.
+    pub fn set_quality_base(&mut self, quality_base: u8) {
+        self.quality_base = quality_base;
+    }
+
+    /// Processes all reads from `input` with copies of `pipeline`,
+    /// writing reads with `Output` fate to `output` and others to
+    /// `fate_outputs`. Every read is written to `read_reports`.
+    pub fn run<R, W>(&self, pipeline: &Pipeline, input: &mut FastqReader<R>, output: &mut W,
+                     fate_outputs: &mut FateOutputs, read_reports: &mut ReadReports) -> Result<RunSummary<Pipeline>>
+    where R: BufRead + Send, W: Write
+    {
+        let mut written: HashSet<Fate> = fate_outputs.fates().into_iter().collect();
+        written.insert(Fate::Output);
+        let reports = read_reports.reports();
+        let chunk_size = self.chunk_size;
+        let qc_reads = if self.qc { 1 } else { 0 };
+        let quality_base = self.quality_base;
+        let mut counts = FateCounts::new();
+        let mut qc = Vec::new();
+
+        let workers = self.run_chunks(
+            pipeline,
+            || {
+                let chunk = input.read_chunk(chunk_size)?;
+                Ok(if chunk.is_empty() { None } else { Some(chunk) })
+            },
+            |pipeline, chunk: Vec<Record>| {
+                let mut out = ChunkOutput::<Vec<u8>>::new(qc_reads, quality_base);
+                for record in chunk.iter() {
+                    let mut trimming = record.trimming();
+                    pipeline.process(&mut trimming);
+                    out.counts.add(record.seq().len(), trimming.fate(), trimming.len_trimmed());
+                    if let Some(qc) = out.qc.first_mut() {
+                        qc.add(&trimming);
+                    }
+                    if written.contains(trimming.fate()) {
+                        write_to_vec(out.records(trimming.fate()), &trimming);
+                    }
+                    for &report in reports.iter() {
+                        write_report_to_vec(out.reports(report), report, &trimming);
+                    }
+                }
+                out
+            },
+            |out| {
+                for (fate, records) in out.records.iter() {
+                    if fate.is_output() {
+                        output.write_all(records)?;
+                    } else {
+                        fate_outputs.write_raw(fate, records)?;
+                    }
+                }
+                for (&report, lines) in out.reports.iter() {
+                    read_reports.write_raw(report, lines)?;
+                }
+                counts.merge(&out.counts);
+                merge_qc(&mut qc, &out.qc);
+                Ok(())
+            })?;
+
+        output.flush()?;
+        fate_outputs.flush()?;
+        read_reports.flush()?;
+        Ok(RunSummary { pipeline: merge_workers(workers, Pipeline::merge), counts, qc, combinations: None })
+    }
+
+    /// Processes all read pairs from `input1` and `input2` with copies
+    /// of `pipeline`, writing pairs with `Output` fate to `output` and
+    /// others to `fate_outputs`. Every read 1 is written to
+    /// `read_reports1` and every read 2 to `read_reports2`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn run_paired<R1, R2>(&self, pipeline: &PairedPipeline,
+                              input1: &mut FastqReader<R1>, input2: &mut FastqReader<R2>,
+                              mut output: PairedOutput<'_>,
+                              fate_outputs: &mut PairedFateOutputs,
+                              read_reports1: &mut ReadReports,
+                              read_reports2: &mut ReadReports) -> Result<RunSummary<PairedPipeline>>
+    where R1: BufRead + Send, R2: BufRead + Send
+    {
+        let mut written: HashSet<Fate> = fate_outputs.fates().into_iter().collect();
+        written.insert(Fate::Output);
+        let reports1 = read_reports1.reports();
+        let reports2 = read_reports2.reports();
+        let chunk_size = self.chunk_size;
+        let qc_reads = if self.qc { 2 } else { 0 };
+        let demultiplexed = matches!(output, PairedOutput::Demultiplexed(_));
+        let quality_base = self.quality_base;
+        let mut counts = FateCounts::new();
+        let mut qc = Vec::new();
+
+        let workers = self.run_chunks(
+            pipeline,
+            || {
+                let chunk1 = input1.read_chunk(chunk_size)?;
+                let chunk2 = input2.read_chunk(chunk_size)?;
+                if chunk1.len() != chunk2.len() {
+                    bail!("Read 1 and read 2 inputs have different numbers of records");
+                }
+                for (record1, record2) in chunk1.iter().zip(chunk2.iter()) {
+                    if record1.id() != record2.id() {
+                        bail!("Read names {:?} and {:?} do not match",
+                              String::from_utf8_lossy(record1.name()), String::from_utf8_lossy(record2.name()));
+                    }
+                }
+                Ok(if chunk1.is_empty() { None } else { Some((chunk1, chunk2)) })
+            },
+            |pipeline, (chunk1, chunk2): (Vec<Record>, Vec<Record>)| {
+                let mut out = ChunkOutput::<(Vec<u8>, Vec<u8>)>::new(qc_reads, quality_base);
+                for (record1, record2) in chunk1.iter().zip(chunk2.iter()) {
+                    let mut trimming1 = record1.trimming();
+                    let mut trimming2 = record2.trimming();
+                    pipeline.process(&mut trimming1, &mut trimming2);
+                    out.counts.add(record1.seq().len() + record2.seq().len(), trimming1.fate(),
+                                   trimming1.len_trimmed() + trimming2.len_trimmed());
+                    if let [qc1, qc2] = &mut out.qc[..] {
+                        qc1.add(&trimming1);
+                        qc2.add(&trimming2);
+                    }
+                    if demultiplexed && trimming1.fate().is_output() {
+                        let tags = (trimming1.read_tag(), trimming2.read_tag());
+                        let ((records1, records2), pairs) = out.tagged.entry(tags).or_default();
+                        write_to_vec(records1, &trimming1);
+                        write_to_vec(records2, &trimming2);
+                        *pairs += 1;
+                    } else if written.contains(trimming1.fate()) {
+                        let (records1, records2) = out.records(trimming1.fate());
+                        write_to_vec(records1, &trimming1);
+                        write_to_vec(records2, &trimming2);
+                    }
+                    for &report in reports1.iter() {
+                        write_report_to_vec(&mut out.reports(report).0, report, &trimming1);
+                    }
+                    for &report in reports2.iter() {
+                        write_report_to_vec(&mut out.reports(report).1, report, &trimming2);
+                    }
+                }
+                out
+            },
+            |out| {
+                for (fate, (records1, records2)) in out.records.iter() {
+                    match (&mut output, fate.is_output()) {
+                        (PairedOutput::Files(output1, output2), true) => {
+                            output1.write_all(records1)?;
+                            output2.write_all(records2)?;
+                        }
+                        _ => fate_outputs.write_raw(fate, records1, records2)?,
+                    }
+                }
+                if let PairedOutput::Demultiplexed(demux) = &mut output {
+                    for (&tags, ((records1, records2), pairs)) in out.tagged.iter() {
+                        demux.write_raw(tags, records1, records2, *pairs)?;
+                    }
+                }
+                for (&report, (lines1, lines2)) in out.reports.iter() {
+                    read_reports1.write_raw(report, lines1)?;
+                    read_reports2.write_raw(report, lines2)?;
+                }
+                counts.merge(&out.counts);
+                merge_qc(&mut qc, &out.qc);
+                Ok(())
+            })?;
+
+        let combinations = match output {
+            PairedOutput::Files(output1, output2) => {
+                output1.flush()?;
+                output2.flush()?;
+                None
+            }
+            PairedOutput::Demultiplexed(demux) => {
+                demux.flush()?;
+                Some(demux.counts().clone())
+            }
+        };
+        fate_outputs.flush()?;
+        read_reports1.flush()?;
+        read_reports2.flush()?;
+        Ok(RunSummary { pipeline: merge_workers(workers, PairedPipeline::merge), counts, qc, combinations })
+    }
+
+    /// Reads chunks with `read` on one thread until it returns `None`,
+    /// processes them with `process` on the worker threads, each with
+    /// its own copy of `state`, and writes the results with `write` on
+    /// the calling thread in the order the chunks were read. Returns
+    /// the final state of each worker.
+    fn run_chunks<S, C, O>(&self, state: &S,
+                           mut read: impl FnMut() -> Result<Option<C>> + Send,
+                           process: impl Fn(&mut S, C) -> O + Sync,
+                           mut write: impl FnMut(O) -> Result<()>) -> Result<Vec<S>>
+    where S: Clone + Send, C: Send, O: Send
+    {
+        let max_chunks = 2 * self.threads;
+        let (chunk_tx, chunk_rx) = sync_channel::<(usize, C)>(self.threads);
+        let chunk_rx = Arc::new(Mutex::new(chunk_rx));
+        let (done_tx, done_rx) = sync_channel::<(usize, thread::Result<O>)>(self.threads);
+
+        // Each chunk read takes a token, which is returned once the
+        // chunk is written.
+        let (token_tx, token_rx) = sync_channel::<()>(max_chunks);
+        for _ in 0..max_chunks {
+            token_tx.send(()).expect("Token channel has room for all tokens");
+        }
+
+        thread::scope(|scope| {
+            let reader = scope.spawn(move || -> Result<()> {
+                let mut idx = 0;
+                while token_rx.recv().is_ok() {
+                    match read()? {
+                        Some(chunk) => {
+                            if chunk_tx.send((idx, chunk)).is_err() {
+                                break;
+                            }
+                            idx += 1;
+                        }
+                        None => break,
+                    }
+                }
+                Ok(())
+            });
+
+            let process = &process;
+            let workers = (0..self.threads).map(|_| {
+                let chunk_rx = chunk_rx.clone();
+                let done_tx = done_tx.clone();
+                let mut state = state.clone();
+                scope.spawn(move || {
+                    loop {
+                        let next = chunk_rx.lock().expect("Chunk receiver lock").recv();
+                        let (idx, chunk) = match next {
+                            Ok(next) => next,
+                            Err(_) => break,
+                        };
+                        let out = panic::catch_unwind(AssertUnwindSafe(|| process(&mut state, chunk)));
+                        let panicked = out.is_err();
+                        if done_tx.send((idx, out)).is_err() || panicked {
+                            break;
+                        }
+                    }
+                    state
+                })
+            }).collect::<Vec<_>>();
+            // Once the workers finish, the reader and writer see their
+            // channels close.
+            drop(chunk_rx);
+            drop(done_tx);
+
+            let written = write_ordered(done_rx, token_tx, &mut write);
+            let read = reader.join().unwrap_or_else(|e| panic::resume_unwind(e));
+            let states = workers.into_iter()
+                .map(|worker| worker.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+                .collect::<Vec<S>>();
+            written?;
+            read?;
+            Ok(states)
+        })
+    }
+}
+
+/// Writes processed chunks in order as they arrive, returning a token
+/// for each one written. Dropping `done` and `tokens` on an error
+/// stops the workers and the reader.
+fn write_ordered<O>(done: Receiver<(usize, thread::Result<O>)>, tokens: SyncSender<()>,
+                    write: &mut impl FnMut(O) -> Result<()>) -> Result<()> {
+    let mut pending = BTreeMap::new();
+    let mut next = 0;
+    for (idx, out) in done.iter() {
+        pending.insert(idx, out.unwrap_or_else(|e| panic::resume_unwind(e)));
+        while let Some(out) = pending.remove(&next) {
+            write(out)?;
+            next += 1;
+            // The reader may already have finished.
+            let _ = tokens.send(());
+        }
+    }
+    Ok(())
+}
+
+fn merge_workers<S>(workers: Vec<S>, merge: fn(&mut S, &S)) -> S {
+    let mut workers = workers.into_iter();
+    let mut merged = workers.next().expect("At least one worker");
+    for worker in workers {
+        merge(&mut merged, &worker);
+    }
+    merged
+}
+
+/// Configuration for a complete run, reading single-end reads from
+/// `input` or paired-end reads from `input` and `input2`.
+///
+/// Paired-end reads are written either to `output` and `output2` or,
+/// with `demultiplex`, to files for each combination of the barcodes
+/// identified by the read 1 and read 2 pipelines.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct RunConf {
+    input: String,
+    #[serde(default)]
+    input2: Option<String>,
+    /// Output path, required unless demultiplexing
+    #[serde(default)]
+    output: Option<String>,
+    #[serde(default)]
+    output2: Option<String>,
+    /// Demultiplexing of read pairs by barcode combination, in place
+    /// of `output` and `output2`
+    #[serde(default)]
+    demultiplex: Option<CombinatorialDemuxConf>,
+    #[serde(default)]
+    runner: RunnerConf,
+    /// Pipeline for single-end reads
+    #[serde(default)]
+    pipeline: PipelineConf,
+    /// Pipeline for paired-end reads, required with `input2`
+    #[serde(default)]
+    paired_pipeline: Option<PairedPipelineConf>,
+    #[serde(default)]
+    fate_outputs: FateOutputConf,
+    /// Read 2 outputs for paired-end reads
+    #[serde(default)]
+    fate_outputs2: FateOutputConf,
+    #[serde(default)]
+    read_reports: ReadReportConf,
+    /// Read 2 reports for paired-end reads
+    #[serde(default)]
+    read_reports2: ReadReportConf,
+    /// Path for the JSON report
+    #[serde(default)]
+    json_report: Option<String>,
+    /// Path for the MultiQC report, which MultiQC finds when it ends
+    /// in `_mqc.json`
+    #[serde(default)]
+    multiqc_report: Option<String>,
+    /// Path for the quality control statistics as TSV
+    #[serde(default)]
+    qc_tsv: Option<String>,
+    /// Sample name for the MultiQC report, by default the name of the
+    /// input file without its extensions
+    #[serde(default)]
+    sample_name: Option<String>,
+}
+
+pub enum RunOutcome {
+    Single(Box<RunSummary<Pipeline>>),
+    Paired(Box<RunSummary<PairedPipeline>>),
+}
+
+impl RunOutcome {
+    pub fn counts(&self) -> &FateCounts {
+        match self {
+            RunOutcome::Single(summary) => summary.counts(),
+            RunOutcome::Paired(summary) => summary.counts(),
+        }
+    }
+
+    pub fn qc(&self) -> &[ReadQc] {
+        match self {
+            RunOutcome::Single(summary) => summary.qc(),
+            RunOutcome::Paired(summary) => summary.qc(),
+        }
+    }
+
+    pub fn combinations(&self) -> Option<&CombinationCounts> {
+        match self {
+            RunOutcome::Single(_) => None,
+            RunOutcome::Paired(summary) => summary.combinations(),
+        }
+    }
+
+    /// Barcodes with colliding neighborhoods, in the single-end
+    /// pipeline or in the read 1 and then the read 2 pipeline
+    pub fn barcode_collisions(&self) -> Vec<&BarcodeCollision> {
+        let pipelines = match self {
+            RunOutcome::Single(summary) => vec![summary.pipeline()],
+            RunOutcome::Paired(summary) => vec![summary.pipeline().read1(), summary.pipeline().read2()],
+        };
+        pipelines.into_iter()
+            .filter_map(Pipeline::barcodes)
+            .flat_map(|barcodes| barcodes.collisions().iter())
+            .collect()
+    }
+}
+
+impl RunConf {
+    pub fn json_report(&self) -> Option<&str> {
+        self.json_report.as_deref()
+    }
+
+    pub fn multiqc_report(&self) -> Option<&str> {
+        self.multiqc_report.as_deref()
+    }
+
+    pub fn qc_tsv(&self) -> Option<&str> {
+        self.qc_tsv.as_deref()
+    }
+
+    pub fn sample_name(&self) -> String {
+        match &self.sample_name {
+            Some(name) => name.clone(),
+            None => {
+                let file_name = Path::new(&self.input).file_name()
+                    .map_or_else(|| self.input.clone(), |f| f.to_string_lossy().into_owned());
+                file_name.split('.').next().unwrap_or(&file_name).to_string()
+            }
+        }
+    }
+
+    pub fn run(&self) -> Result<RunOutcome> {
+        let runner = self.runner.runner()?;
+        match (&self.input2, &self.paired_pipeline) {
+            (None, None) => {
+                let output = match (&self.output, &self.output2, &self.demultiplex) {
+                    (Some(output), None, None) => output,
+                    _ => bail!("Single-end runs need output, without output2 or demultiplex"),
+                };
+                let mut pipeline = self.pipeline.pipeline()?;
+                pipeline.set_traceback(self.read_reports.info_cigar());
+                let mut input = FastqReader::open(&self.input)?;
+                let mut output = create(output)?;
+                let mut fate_outputs = self.fate_outputs.fate_outputs()?;
+                let mut read_reports = self.read_reports.read_reports()?;
+                Ok(RunOutcome::Single(Box::new(runner.run(&pipeline, &mut input, &mut output,
+                                                          &mut fate_outputs, &mut read_reports)?)))
+            }
+            (Some(input2), Some(paired_pipeline)) => {
+                let mut pipeline = paired_pipeline.paired_pipeline()?;
+                pipeline.set_traceback(self.read_reports.info_cigar(), self.read_reports2.info_cigar());
+                let mut input1 = FastqReader::open(&self.input)?;
+                let mut input2 = FastqReader::open(input2)?;
+                let mut fate_outputs = self.fate_outputs.paired_fate_outputs(&self.fate_outputs2)?;
+                let mut read_reports1 = self.read_reports.read_reports()?;
+                let mut read_reports2 = self.read_reports2.read_reports()?;
+                let summary = match (&self.output, &self.output2, &self.demultiplex) {
+                    (Some(output1), Some(output2), None) => {
+                        let mut output1 = create(output1)?;
+                        let mut output2 = create(output2)?;
+                        runner.run_paired(&pipeline, &mut input1, &mut input2,
+                                          PairedOutput::Files(&mut output1, &mut output2), &mut fate_outputs,
+                                          &mut read_reports1, &mut read_reports2)?
+                    }
+                    (None, None, Some(demux_conf)) => {
+                        let mut demux = demultiplexer(demux_conf, &mut pipeline)?;
+                        runner.run_paired(&pipeline, &mut input1, &mut input2,
+                                          PairedOutput::Demultiplexed(&mut demux), &mut fate_outputs,
+                                          &mut read_reports1, &mut read_reports2)?
+                    }
+                    _ => bail!("Paired-end runs need either output and output2, or demultiplex"),
+                };
+                Ok(RunOutcome::Paired(Box::new(summary)))
+            }
+            _ => bail!("Paired-end runs need input2 and paired_pipeline together"),
+        }
+    }
+}
+
+/// Creates the demultiplexer for a paired-end run, first adding the
+/// barcodes of any sample sheet to `pipeline`.
+fn demultiplexer(conf: &CombinatorialDemuxConf, pipeline: &mut PairedPipeline) -> Result<CombinatorialDemultiplexer> {
+    let mut conf = conf.clone();
+    if let Some(sample_sheet) = &conf.sample_sheet {
+        ensure!(conf.combinations.is_none(), "Demultiplexing combinations cannot be given with a sample sheet");
+        let table = sample_sheet.demux_table()?;
+        let (barcodes1, barcodes2) = sample_sheet.barcodes(&table)?;
+        pipeline.set_barcodes(barcodes1, barcodes2)
+            .context("Sample sheet barcodes cannot be used with barcodes in the pipelines")?;
+        conf.combinations = Some(table.combinations().to_vec());
+    }
+    match (pipeline.read1().barcode_names(), pipeline.read2().barcode_names()) {
+        (Some(names1), Some(names2)) => conf.demultiplexer(names1, names2),
+        _ => bail!("Demultiplexing needs barcodes in both the read 1 and read 2 pipelines, or a sample sheet"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fastq(n: usize) -> Vec<u8> {
+        let mut fastq = Vec::new();
+        for i in 0..n {
+            let len = i % 30;
+            fastq.extend_from_slice(format!("@read{}\n{}\n+\n{}\n", i, "A".repeat(len), "I".repeat(len)).as_bytes());
+        }
+        fastq
+    }
+
+    #[test]
+    fn ordered_output() {
+        let conf: PipelineConf = toml::from_str("[length_filter]\nmin_length = 10\n").unwrap();
+        let pipeline = conf.pipeline().unwrap();
+        let input = fastq(1000);
+
+        for &(threads, chunk_size) in [(1, 1000), (4, 7), (3, 1)].iter() {
+            let runner = Runner::new(threads, chunk_size).unwrap();
+            let mut output = Vec::new();
+            let mut fate_outputs = FateOutputConf::default().fate_outputs().unwrap();
+            let mut read_reports = ReadReportConf::default().read_reports().unwrap();
+            let summary = runner.run(&pipeline, &mut FastqReader::new(&input[..]), &mut output,
+                                     &mut fate_outputs, &mut read_reports).unwrap();
+
+            let mut expected = Vec::new();
+            for i in (0..1000).filter(|i| i % 30 >= 10) {
+                let len = i % 30;
+                expected.extend_from_slice(format!("@read{}\n{}\n+\n{}\n", i, "A".repeat(len), "I".repeat(len)).as_bytes());
+            }
+            assert_eq!(output, expected);
+
+            assert_eq!(summary.counts().input().reads, 1000);
+            assert_eq!(summary.counts().fate(&Fate::TooShort).reads, 340);
+            assert_eq!(summary.pipeline().length_filter().unwrap().stats().too_short, 340);
+            assert_eq!(summary.qc()[0].raw.reads(), 1000);
+            assert_eq!(summary.qc()[0].trimmed.reads(), 660);
+        }
+    }
+
+    #[test]
+    fn quality_trimming() {
+        let conf: PipelineConf = toml::from_str("[qual_trim_end]\nthreshold = 20\n").unwrap();
+        let input = b"@r1\nACGTACGT\n+\nIIIII+++\n";
+        let mut output = Vec::new();
+        let summary = Runner::new(1, 10).unwrap()
+            .run(&conf.pipeline().unwrap(), &mut FastqReader::new(&input[..]), &mut output,
+                 &mut FateOutputConf::default().fate_outputs().unwrap(),
+                 &mut ReadReportConf::default().read_reports().unwrap())
+            .unwrap();
+        assert_eq!(output, b"@r1\nACGTA\n+\nIIIII\n".to_vec());
+        assert_eq!(summary.pipeline().qual_trim_end().unwrap().trimmed().bases, 3);
+    }
+
+    #[test]
+    fn sample_name() {
+        let conf: RunConf = toml::from_str("input = \"data/s1_R1.fastq.gz\"\noutput = \"out.fastq\"\n").unwrap();
+        assert_eq!(conf.sample_name(), "s1_R1");
+        let conf: RunConf = toml::from_str("input = \"s1.fq\"\noutput = \"out.fastq\"\nsample_name = \"x\"\n").unwrap();
+        assert_eq!(conf.sample_name(), "x");
+    }
+
+    #[test]
+    fn paired_mismatch() {
+        let pipeline = PairedPipelineConf::default().paired_pipeline().unwrap();
+        let runner = Runner::new(2, 10).unwrap();
+        let input1 = fastq(20);
+        let input2 = fastq(25);
+        let mut fate_outputs = FateOutputConf::default().paired_fate_outputs(&FateOutputConf::default()).unwrap();
+        let result = runner.run_paired(&pipeline, &mut FastqReader::new(&input1[..]), &mut FastqReader::new(&input2[..]),
+                                       PairedOutput::Files(&mut Vec::new(), &mut Vec::new()), &mut fate_outputs,
+                                       &mut ReadReportConf::default().read_reports().unwrap(),
+                                       &mut ReadReportConf::default().read_reports().unwrap());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn demultiplexed() {
+        let conf: PairedPipelineConf = toml::from_str(concat!(
+            "[read1.barcodes]\nbarcodes = [{ name = \"A\", sequence = \"AAAA\" }, { name = \"C\", sequence = \"CCCC\" }]\n",
+            "[read2.barcodes]\nbarcodes = [{ name = \"G\", sequence = \"GGGG\" }]\n")).unwrap();
+        let pipeline = conf.paired_pipeline().unwrap();
+        let input1 = b"@r1\nAAAATTTT\n+\nIIIIIIII\n@r2\nCCCCTTTT\n+\nIIIIIIII\n@r3\nAAAAGGTT\n+\nIIIIIIII\n";
+        let input2 = b"@r1\nGGGGTTTT\n+\nIIIIIIII\n@r2\nTTTTTTTT\n+\nIIIIIIII\n@r3\nGGGGTTTT\n+\nIIIIIIII\n";
+
+        let dir = std::env::temp_dir().join(format!("trimrs-demultiplexed-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let template = |read| dir.join(format!("{{name1}}-{{name2}}.{}.fastq", read)).to_string_lossy().into_owned();
+        let demux_conf = CombinatorialDemuxConf {
+            output: template(1),
+            paired_output: template(2),
+            unknown_name: Some("unknown".to_string()),
+            combinations: None,
+            sample_sheet: None,
+        };
+        let names1 = pipeline.read1().barcode_names().unwrap();
+        let names2 = pipeline.read2().barcode_names().unwrap();
+        let mut demux = demux_conf.demultiplexer(names1, names2).unwrap();
+        let mut fate_outputs = FateOutputConf::default().paired_fate_outputs(&FateOutputConf::default()).unwrap();
+        let summary = Runner::new(2, 1).unwrap()
+            .run_paired(&pipeline, &mut FastqReader::new(&input1[..]), &mut FastqReader::new(&input2[..]),
+                        PairedOutput::Demultiplexed(&mut demux), &mut fate_outputs,
+                        &mut ReadReportConf::default().read_reports().unwrap(),
+                        &mut ReadReportConf::default().read_reports().unwrap())
+            .unwrap();
+
+        let combinations = summary.combinations().unwrap();
+        assert_eq!(combinations.count(Some(0), Some(0)), 2);
+        assert_eq!(combinations.count(Some(1), None), 1);
+        assert_eq!(combinations.total(), 3);
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("A-G.1.fastq"), "@r1\nTTTT\n+\nIIII\n@r3\nGGTT\n+\nIIII\n");
+        assert_eq!(read("A-G.2.fastq"), "@r1\nTTTT\n+\nIIII\n@r3\nTTTT\n+\nIIII\n");
+        assert_eq!(read("C-unknown.2.fastq"), "@r2\nTTTTTTTT\n+\nIIIIIIII\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sample_sheet_demultiplexed() {
+        let dir = std::env::temp_dir().join(format!("trimrs-sample-sheet-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        std::fs::write(path("SampleSheet.csv"), concat!(
+            "[Header]\nFileFormatVersion,2\nInstrumentPlatform,NovaSeq6000\n\n",
+            "[BCLConvert_Data]\nSample_ID,Index,Index2\nS1,ATTACTCG,TATAGCCT\nS2,TCCGGAGA,ATAGAGGC\n")).unwrap();
+        let fastq = |read| format!(
+            "@r1 {read}:N:0:ATTACTCG+TATAGCCT\nACGT\n+\nIIII\n@r2 {read}:N:0:TCCGGAGA+ATAGAGGG\nCCCC\n+\nIIII\n\
+             @r3 {read}:N:0:ATTACTCG+ATAGAGGC\nGGGG\n+\nIIII\n", read = read);
+        std::fs::write(path("in.1.fastq"), fastq(1)).unwrap();
+        std::fs::write(path("in.2.fastq"), fastq(2)).unwrap();
+
+        let conf: RunConf = toml::from_str(&format!(
+            "input = {:?}\ninput2 = {:?}\n[paired_pipeline.read1]\n[paired_pipeline.read2]\n\
+             [demultiplex]\noutput = {:?}\npaired_output = {:?}\nunknown_name = \"Undetermined\"\n\
+             [demultiplex.sample_sheet]\npath = {:?}\n",
+            path("in.1.fastq"), path("in.2.fastq"), path("{sample}_R1.fastq"), path("{sample}_R2.fastq"),
+            path("SampleSheet.csv"))).unwrap();
+        let outcome = conf.run().unwrap();
+
+        let combinations = outcome.combinations().unwrap();
+        assert_eq!(combinations.count(Some(0), Some(0)), 1);
+        assert_eq!(combinations.count(Some(1), Some(1)), 1);
+        assert_eq!(combinations.count(None, None), 1);
+        let read = |name: &str| std::fs::read_to_string(path(name)).unwrap();
+        assert_eq!(read("S1_R1.fastq"), "@r1 1:N:0:ATTACTCG+TATAGCCT\nACGT\n+\nIIII\n");
+        assert_eq!(read("S2_R2.fastq"), "@r2 2:N:0:TCCGGAGA+ATAGAGGG\nCCCC\n+\nIIII\n");
+        assert_eq!(read("Undetermined_R1.fastq"), "@r3 1:N:0:ATTACTCG+ATAGAGGC\nGGGG\n+\nIIII\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn paired_length_filter() {
+        let conf: PairedPipelineConf = toml::from_str(
+            "pair_filter = \"both\"\n[read1.length_filter]\nmin_length = 5\n[read2.length_filter]\nmin_length = 5\n").unwrap();
+        let input1 = b"@r1\nACG\n+\nIII\n@r2\nACG\n+\nIII\n@r3\nACGTAC\n+\nIIIIII\n";
+        let input2 = b"@r1\nACGTAC\n+\nIIIIII\n@r2\nACG\n+\nIII\n@r3\nACG\n+\nIII\n";
+        let mut fate_outputs = FateOutputConf::default().paired_fate_outputs(&FateOutputConf::default()).unwrap();
+        let summary = Runner::new(1, 10).unwrap()
+            .run_paired(&conf.paired_pipeline().unwrap(), &mut FastqReader::new(&input1[..]),
+                        &mut FastqReader::new(&input2[..]),
+                        PairedOutput::Files(&mut Vec::new(), &mut Vec::new()), &mut fate_outputs,
+                        &mut ReadReportConf::default().read_reports().unwrap(),
+                        &mut ReadReportConf::default().read_reports().unwrap())
+            .unwrap();
+
+        assert_eq!(summary.counts().fate(&Fate::TooShort).reads, 1);
+        assert_eq!(summary.pipeline().read1().length_filter().unwrap().stats().too_short, 1);
+        assert_eq!(summary.pipeline().read2().length_filter().unwrap().stats().too_short, 1);
+    }
+}
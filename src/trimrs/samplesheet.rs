@@ -0,0 +1,477 @@
+//! Import of Illumina `SampleSheet.csv` files as demultiplexing
+//! barcode tables.
+//!
+//! Both the original (v1) format, with samples in a `[Data]`
+//! section, and the BCL Convert (v2) format, with samples in a
+//! `[BCLConvert_Data]` section, are read. Each sample provides an i7
+//! index, used as the read 1 barcode, and an i5 index, used as the
+//! read 2 barcode. Barcodes are named by their sequence, so that
+//! samples sharing an index share a barcode, and each sample becomes
+//! a barcode [`Combination`](../demux/struct.Combination.html) with
+//! the sample ID as its sample name. A sample listed once per lane is
+//! one combination when all lanes are used. Only dual-index sheets
+//! can be demultiplexed.
+//!
+//! Some instruments read the i5 index on the opposite strand from the
+//! sample sheet sequence, which must then be reverse complemented. By
+//! default, this is decided from the instrument named in the
+//! `[Header]` section.
+//!
+//! The indices are sequenced in separate index reads, not at the
+//! start of read 1 and read 2, so the barcodes are matched against
+//! the index sequences that `bcl2fastq` and BCL Convert write at the
+//! end of each read header. A run uses the sample sheet from the
+//! `sample_sheet` key of its `demultiplex` section.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use serde::{Serialize, Deserialize};
+
+use crate::barcode::{Barcode, BarcodeSource, Barcodes};
+use crate::demux::Combination;
+use crate::pipeline::BarcodesConf;
+
+/// Instruments whose i5 index is read as the reverse complement of
+/// the sample sheet sequence.
+const I5_REVERSE_COMPLEMENT_INSTRUMENTS: &[&str] =
+    &["iSeq", "MiniSeq", "NextSeq", "HiSeq 3000", "HiSeq 4000", "HiSeq X", "HiSeq3000", "HiSeq4000", "HiSeqX"];
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum I5Orientation {
+    /// Decide from the instrument named in the sample sheet
+    #[default]
+    Auto,
+    Forward,
+    ReverseComplement,
+}
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Serialize, Deserialize)]
+pub struct SampleSheetConf {
+    path: String,
+    /// Use only samples in this lane, along with samples with no lane
+    #[serde(default)]
+    lane: Option<usize>,
+    #[serde(default)]
+    i5_orientation: I5Orientation,
+    /// Mismatches allowed in each index, 1 by default as in BCL
+    /// Convert
+    #[serde(default = "default_index_errors")]
+    max_errors: usize,
+}
+
+fn default_index_errors() -> usize { 1 }
+
+impl SampleSheetConf {
+    pub fn demux_table(&self) -> Result<DemuxTable> {
+        let sheet = SampleSheet::read(&self.path)?;
+        sheet.demux_table(self.lane, self.i5_orientation)
+    }
+
+    /// Barcodes matching the i7 and i5 indices of `table` in the read
+    /// headers, for read 1 and read 2.
+    pub fn barcodes(&self, table: &DemuxTable) -> Result<(Barcodes, Barcodes)> {
+        let barcodes1 = BarcodesConf::new(table.barcodes1.clone(), self.max_errors, false, BarcodeSource::HeaderIndex);
+        let barcodes2 = BarcodesConf::new(table.barcodes2.clone(), self.max_errors, false, BarcodeSource::HeaderIndex2);
+        Ok((barcodes1.barcodes()?, barcodes2.barcodes()?))
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, Serialize, Deserialize)]
+pub enum SampleSheetVersion {
+    V1,
+    V2,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Sample {
+    pub lane: Option<usize>,
+    pub sample_id: String,
+    pub sample_name: Option<String>,
+    pub index: Option<String>,
+    pub index2: Option<String>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct SampleSheet {
+    version: SampleSheetVersion,
+    header: Vec<(String, String)>,
+    samples: Vec<Sample>,
+}
+
+impl SampleSheet {
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let text = fs::read_to_string(path.as_ref())
+            .with_context(|| format!("Reading sample sheet {:?}", path.as_ref()))?;
+        Self::parse(&text).with_context(|| format!("Parsing sample sheet {:?}", path.as_ref()))
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut header = Vec::new();
+        let mut data_v1 = None;
+        let mut data_v2 = None;
+
+        let mut section = String::new();
+        let mut rows: Vec<Vec<String>> = Vec::new();
+        for line in text.lines().chain(std::iter::once("[]")) {
+            let mut fields = split_csv(line.trim_end_matches('\r'));
+            while fields.last().is_some_and(String::is_empty) {
+                fields.pop();
+            }
+            if fields.is_empty() {
+                continue;
+            }
+
+            if fields[0].starts_with('[') {
+                match section.as_str() {
+                    "Header" => {
+                        for row in rows.iter() {
+                            header.push((row[0].clone(), row.get(1).cloned().unwrap_or_default()));
+                        }
+                    },
+                    "Data" => data_v1 = Some(std::mem::take(&mut rows)),
+                    "BCLConvert_Data" => data_v2 = Some(std::mem::take(&mut rows)),
+                    _ => (),
+                }
+                rows.clear();
+                section = fields[0].trim_start_matches('[').trim_end_matches(']').to_string();
+            } else {
+                rows.push(fields);
+            }
+        }
+
+        let (version, data) = match (data_v1, data_v2) {
+            (_, Some(data)) => (SampleSheetVersion::V2, data),
+            (Some(data), None) => (SampleSheetVersion::V1, data),
+            (None, None) => bail!("No [Data] or [BCLConvert_Data] section"),
+        };
+
+        Ok(SampleSheet { version, header, samples: Self::parse_samples(&data)? })
+    }
+
+    fn parse_samples(data: &[Vec<String>]) -> Result<Vec<Sample>> {
+        let columns = data.first().ok_or_else(|| anyhow!("No column names in sample data"))?;
+        let column = |name: &str| columns.iter().position(|c| c.eq_ignore_ascii_case(name));
+        let lane_col = column("Lane");
+        let id_col = column("Sample_ID").ok_or_else(|| anyhow!("No Sample_ID column"))?;
+        let name_col = column("Sample_Name");
+        let index_col = column("index");
+        let index2_col = column("index2");
+
+        let mut samples = Vec::new();
+        for (line, row) in data.iter().enumerate().skip(1) {
+            let field = |col: Option<usize>| {
+                col.and_then(|c| row.get(c)).filter(|f| !f.is_empty()).cloned()
+            };
+            let lane = match field(lane_col) {
+                Some(lane) => Some(lane.parse::<usize>()
+                                   .with_context(|| format!("Bad lane {:?} in sample row {}", lane, line))?),
+                None => None,
+            };
+            let sample_id = match field(Some(id_col)) {
+                Some(sample_id) => sample_id,
+                None => bail!("No Sample_ID in sample row {}", line),
+            };
+            samples.push(Sample {
+                lane,
+                sample_id,
+                sample_name: field(name_col),
+                index: field(index_col),
+                index2: field(index2_col),
+            });
+        }
+        Ok(samples)
+    }
+
+    pub fn version(&self) -> SampleSheetVersion {
+        self.version
+    }
+
+    /// Value of a `[Header]` entry
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.header.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    pub fn samples(&self) -> &[Sample] {
+        &self.samples
+    }
+
+    /// Instrument named in the `[Header]` section, if any.
+    pub fn instrument(&self) -> Option<&str> {
+        ["InstrumentPlatform", "InstrumentType", "Instrument Type", "Instrument"].iter()
+            .filter_map(|key| self.header(key))
+            .find(|value| !value.is_empty())
+    }
+
+    /// Whether i5 indices must be reverse complemented to match
+    /// reads from the instrument in the sample sheet.
+    pub fn i5_reverse_complement(&self) -> bool {
+        match self.instrument() {
+            Some(instrument) => I5_REVERSE_COMPLEMENT_INSTRUMENTS.iter().any(|i| instrument.starts_with(i)),
+            None => false,
+        }
+    }
+
+    /// Builds the barcode table for the samples in `lane`, or all
+    /// samples when `lane` is `None`. Samples with no lane are used
+    /// for every lane.
+    ///
+    /// A sample listed in several lanes with the same indices, as in
+    /// a sheet for a multi-lane flow cell, becomes one combination.
+    /// Two samples with the same indices, or one sample with
+    /// different indices, cannot be told apart and are rejected.
+    ///
+    /// Only dual-index sample sheets are supported, as combinatorial
+    /// demultiplexing needs a barcode on each read.
+    pub fn demux_table(&self, lane: Option<usize>, i5_orientation: I5Orientation) -> Result<DemuxTable> {
+        ensure!(self.samples.iter().any(|sample| sample.index2.is_some()),
+                "Single-index sample sheets, with no index2 column, are not supported: \
+                 demultiplexing needs both an i7 and an i5 index for each sample");
+        let reverse_complement = match i5_orientation {
+            I5Orientation::Auto => self.i5_reverse_complement(),
+            I5Orientation::Forward => false,
+            I5Orientation::ReverseComplement => true,
+        };
+
+        let mut table = DemuxTable { barcodes1: Vec::new(), barcodes2: Vec::new(), combinations: Vec::new() };
+        for sample in self.samples.iter() {
+            if lane.is_some() && sample.lane.is_some() && lane != sample.lane {
+                continue;
+            }
+
+            let (index, index2) = match (&sample.index, &sample.index2) {
+                (Some(index), Some(index2)) => (index.to_ascii_uppercase(), index2.to_ascii_uppercase()),
+                _ => bail!("Sample {:?} lacks an i7 or i5 index, and only dual-index sample sheets are supported",
+                           sample.sample_id),
+            };
+            let index2 = if reverse_complement { reverse_complement_seq(&index2)? } else { index2 };
+
+            for (barcodes, seq) in [(&mut table.barcodes1, &index), (&mut table.barcodes2, &index2)].iter_mut() {
+                if !barcodes.iter().any(|bc| &bc.sequence == *seq) {
+                    barcodes.push(Barcode { name: seq.to_string(), sequence: seq.to_string() });
+                }
+            }
+
+            let combination = Combination { name1: index, name2: index2, sample: Some(sample.sample_id.clone()) };
+            if let Some(other) = table.combinations.iter()
+                .find(|c| c.sample == combination.sample || (c.name1 == combination.name1 && c.name2 == combination.name2))
+            {
+                ensure!(other.sample == combination.sample,
+                        "Samples {:?} and {:?} have the same indices", other.sample.as_ref().unwrap(), sample.sample_id);
+                ensure!(other == &combination,
+                        "Sample {:?} appears twice with different indices", sample.sample_id);
+                continue;
+            }
+            table.combinations.push(combination);
+        }
+
+        ensure!(!table.combinations.is_empty(), "No samples in sample sheet for lane {:?}", lane);
+        Ok(table)
+    }
+}
+
+/// Demultiplexing barcodes for read 1 and read 2, along with the
+/// barcode combination of each sample.
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct DemuxTable {
+    barcodes1: Vec<Barcode>,
+    barcodes2: Vec<Barcode>,
+    combinations: Vec<Combination>,
+}
+
+impl DemuxTable {
+    /// Read 1 (i7 index) barcodes
+    pub fn barcodes1(&self) -> &[Barcode] {
+        &self.barcodes1
+    }
+
+    /// Read 2 (i5 index) barcodes
+    pub fn barcodes2(&self) -> &[Barcode] {
+        &self.barcodes2
+    }
+
+    /// Barcode combinations, named by sample ID
+    pub fn combinations(&self) -> &[Combination] {
+        &self.combinations
+    }
+}
+
+/// Splits a CSV line into its fields, which may be quoted to include
+/// commas, with `""` for a quote inside a quoted field. Unquoted
+/// fields are trimmed of surrounding whitespace.
+fn split_csv(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut was_quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted => {
+                if chars.next_if_eq(&'"').is_some() {
+                    field.push('"');
+                } else {
+                    quoted = false;
+                }
+            },
+            '"' if field.trim().is_empty() && !was_quoted => {
+                field.clear();
+                quoted = true;
+                was_quoted = true;
+            },
+            c if was_quoted && !quoted && c.is_whitespace() => (),
+            ',' if !quoted => {
+                fields.push(if was_quoted { std::mem::take(&mut field) } else { field.trim().to_string() });
+                field.clear();
+                was_quoted = false;
+            },
+            c => field.push(c),
+        }
+    }
+    fields.push(if was_quoted { field } else { field.trim().to_string() });
+    fields
+}
+
+fn reverse_complement_seq(seq: &str) -> Result<String> {
+    seq.bytes().rev()
+        .map(|nt| match nt {
+            b'A' => Ok('T'),
+            b'C' => Ok('G'),
+            b'G' => Ok('C'),
+            b'T' => Ok('A'),
+            b'N' => Ok('N'),
+            _ => Err(anyhow!("Bad index sequence {:?}", seq)),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHEET_V1: &str = "[Header],,,,,
+IEMFileVersion,4,,,,
+Instrument Type,NextSeq 550,,,,
+,,,,,
+[Reads],,,,,
+75,,,,,
+[Data],,,,,
+Lane,Sample_ID,Sample_Name,I7_Index_ID,index,index2
+1,S1,Sample one,D701,ATTACTCG,TATAGCCT
+1,S2,,D702,TCCGGAGA,TATAGCCT
+2,S3,Sample three,D701,ATTACTCG,ATAGAGGC
+";
+
+    const SHEET_V2: &str = "[Header]
+FileFormatVersion,2
+InstrumentPlatform,NovaSeq6000
+
+[BCLConvert_Settings]
+AdapterRead1,CTGTCTCTTATACACATCT
+
+[BCLConvert_Data]
+Sample_ID,Index,Index2
+S1,ATTACTCG,TATAGCCT
+S2,TCCGGAGA,ATAGAGGC
+";
+
+    #[test]
+    fn parse_v1() {
+        let sheet = SampleSheet::parse(SHEET_V1).unwrap();
+        assert_eq!(sheet.version(), SampleSheetVersion::V1);
+        assert_eq!(sheet.instrument(), Some("NextSeq 550"));
+        assert!(sheet.i5_reverse_complement());
+        assert_eq!(sheet.samples().len(), 3);
+        assert_eq!(sheet.samples()[0],
+                   Sample { lane: Some(1), sample_id: "S1".to_string(), sample_name: Some("Sample one".to_string()),
+                            index: Some("ATTACTCG".to_string()), index2: Some("TATAGCCT".to_string()) });
+        assert_eq!(sheet.samples()[1].sample_name, None);
+    }
+
+    #[test]
+    fn parse_v2() {
+        let sheet = SampleSheet::parse(SHEET_V2).unwrap();
+        assert_eq!(sheet.version(), SampleSheetVersion::V2);
+        assert!(!sheet.i5_reverse_complement());
+        assert_eq!(sheet.samples().len(), 2);
+        assert_eq!(sheet.samples()[1].lane, None);
+        assert_eq!(sheet.samples()[1].index2.as_deref(), Some("ATAGAGGC"));
+    }
+
+    #[test]
+    fn demux_table() {
+        let sheet = SampleSheet::parse(SHEET_V1).unwrap();
+
+        let table = sheet.demux_table(Some(1), I5Orientation::Auto).unwrap();
+        assert_eq!(table.barcodes1().iter().map(|bc| bc.name.as_str()).collect::<Vec<_>>(),
+                   vec!["ATTACTCG", "TCCGGAGA"]);
+        assert_eq!(table.barcodes2().iter().map(|bc| bc.name.as_str()).collect::<Vec<_>>(),
+                   vec!["AGGCTATA"]);
+        assert_eq!(table.combinations()[1],
+                   Combination { name1: "TCCGGAGA".to_string(), name2: "AGGCTATA".to_string(),
+                                 sample: Some("S2".to_string()) });
+
+        let table = sheet.demux_table(None, I5Orientation::Forward).unwrap();
+        assert_eq!(table.combinations().len(), 3);
+        assert_eq!(table.barcodes1().len(), 2);
+        assert_eq!(table.barcodes2().iter().map(|bc| bc.name.as_str()).collect::<Vec<_>>(),
+                   vec!["TATAGCCT", "ATAGAGGC"]);
+
+        assert!(sheet.demux_table(Some(3), I5Orientation::Auto).is_err());
+    }
+
+    const SHEET_LANES: &str = "[Header]
+Instrument Type,HiSeq 2500
+
+[Data]
+Lane,Sample_ID,Sample_Name,index,index2,Description
+1,S1,\"Liver, rep 1\",ATTACTCG,TATAGCCT,\"a \"\"quoted\"\" note\"
+1,S2,Liver rep 2,TCCGGAGA,TATAGCCT,
+2,S1,\"Liver, rep 1\",ATTACTCG,TATAGCCT,
+2,S2,Liver rep 2,TCCGGAGA,TATAGCCT,
+2,S3,Kidney,ATTACTCG,ATAGAGGC,
+";
+
+    #[test]
+    fn demux_lanes() {
+        let sheet = SampleSheet::parse(SHEET_LANES).unwrap();
+        assert_eq!(sheet.samples().len(), 5);
+        assert_eq!(sheet.samples()[0].sample_name.as_deref(), Some("Liver, rep 1"));
+        assert_eq!(sheet.samples()[0].index.as_deref(), Some("ATTACTCG"));
+
+        // Samples repeated across lanes are merged.
+        let table = sheet.demux_table(None, I5Orientation::Auto).unwrap();
+        assert_eq!(table.combinations().iter().map(|c| c.sample.as_deref().unwrap()).collect::<Vec<_>>(),
+                   vec!["S1", "S2", "S3"]);
+        assert_eq!(table.barcodes1().len(), 2);
+        assert_eq!(table.barcodes2().len(), 2);
+        assert_eq!(sheet.demux_table(Some(1), I5Orientation::Auto).unwrap().combinations().len(), 2);
+
+        let conflict = SHEET_LANES.replace("2,S3,Kidney,ATTACTCG,ATAGAGGC", "2,S3,Kidney,TCCGGAGA,TATAGCCT");
+        let sheet = SampleSheet::parse(&conflict).unwrap();
+        assert!(sheet.demux_table(None, I5Orientation::Auto).is_err());
+        assert!(sheet.demux_table(Some(1), I5Orientation::Auto).is_ok());
+
+        let conflict = SHEET_LANES.replace("2,S1,\"Liver, rep 1\",ATTACTCG", "2,S1,\"Liver, rep 1\",GCTACGCT");
+        assert!(SampleSheet::parse(&conflict).unwrap().demux_table(None, I5Orientation::Auto).is_err());
+    }
+
+    #[test]
+    fn csv_fields() {
+        assert_eq!(split_csv("a, b ,\"c, d\",\"e \"\"f\"\"\" ,,"), vec!["a", "b", "c, d", "e \"f\"", "", ""]);
+        assert_eq!(split_csv(""), vec![""]);
+    }
+
+    #[test]
+    fn bad_sheets() {
+        assert!(SampleSheet::parse("[Header]\nIEMFileVersion,4\n").is_err());
+        assert!(SampleSheet::parse("[Data]\nLane,Sample_ID\nx,S1\n").is_err());
+        let single_index = SampleSheet::parse("[Data]\nSample_ID,index\nS1,ACGT\n").unwrap()
+            .demux_table(None, I5Orientation::Auto).unwrap_err();
+        assert!(single_index.to_string().contains("Single-index"));
+        assert!(SampleSheet::parse("[Data]\nSample_ID,index,index2\nS1,ACGT,TTTT\nS2,ACGA,\n").unwrap()
+                .demux_table(None, I5Orientation::Auto).is_err());
+    }
+}
@@ -0,0 +1,227 @@
+//! Statistics accumulated while processing reads.
+//!
+//! Statistics are collected separately by each copy of a pipeline
+//! and combined afterwards with `merge`.
+
+use std::collections::BTreeMap;
+
+use serde::{Serialize, Deserialize};
+
+use crate::output::Fate;
+
+/// Number of reads of each length.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct LengthHistogram {
+    counts: Vec<usize>,
+}
+
+impl LengthHistogram {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, len: usize) {
+        if len >= self.counts.len() {
+            self.counts.resize(len + 1, 0);
+        }
+        self.counts[len] += 1;
+    }
+
+    pub fn merge(&mut self, other: &LengthHistogram) {
+        if other.counts.len() > self.counts.len() {
+            self.counts.resize(other.counts.len(), 0);
+        }
+        for (count, other_count) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *count += *other_count;
+        }
+    }
+
+    /// Number of reads of length `len`
+    pub fn count(&self, len: usize) -> usize {
+        self.counts.get(len).copied().unwrap_or(0)
+    }
+
+    /// Total number of reads
+    pub fn total(&self) -> usize {
+        self.counts.iter().sum()
+    }
+
+    /// Total length of all reads
+    pub fn total_len(&self) -> usize {
+        self.counts.iter().enumerate().map(|(len, count)| len * count).sum()
+    }
+
+    /// Iterates over `(length, count)` for each length with a
+    /// non-zero count, shortest first.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.counts.iter().copied().enumerate().filter(|&(_, count)| count > 0)
+    }
+}
+
+/// Number of reads and their total length.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+pub struct ReadCount {
+    pub reads: usize,
+    pub bases: usize,
+}
+
+impl ReadCount {
+    pub fn add(&mut self, bases: usize) {
+        self.reads += 1;
+        self.bases += bases;
+    }
+
+    pub fn merge(&mut self, other: &ReadCount) {
+        self.reads += other.reads;
+        self.bases += other.bases;
+    }
+}
+
+/// Number of sequences of each length, such as removed adapters,
+/// broken down by the number of errors in their alignment.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ErrorHistogram {
+    counts: BTreeMap<usize, Vec<usize>>,
+}
+
+impl ErrorHistogram {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, len: usize, errors: usize) {
+        let counts = self.counts.entry(len).or_default();
+        if errors >= counts.len() {
+            counts.resize(errors + 1, 0);
+        }
+        counts[errors] += 1;
+    }
+
+    pub fn merge(&mut self, other: &ErrorHistogram) {
+        for (len, other_counts) in other.counts.iter() {
+            let counts = self.counts.entry(*len).or_default();
+            if other_counts.len() > counts.len() {
+                counts.resize(other_counts.len(), 0);
+            }
+            for (count, other_count) in counts.iter_mut().zip(other_counts.iter()) {
+                *count += *other_count;
+            }
+        }
+    }
+
+    /// Number of sequences of length `len`
+    pub fn count(&self, len: usize) -> usize {
+        self.counts.get(&len).map_or(0, |counts| counts.iter().sum())
+    }
+
+    /// Total number of sequences
+    pub fn total(&self) -> usize {
+        self.counts.values().flatten().sum()
+    }
+
+    /// Iterates over `(length, counts)` for each length seen,
+    /// shortest first, where `counts[e]` is the number of sequences
+    /// with `e` errors.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &[usize])> + '_ {
+        self.counts.iter().map(|(len, counts)| (*len, counts.as_slice()))
+    }
+}
+
+/// Counts of input reads and of trimmed reads with each fate. For
+/// paired-end reads, each pair is counted once with the bases of
+/// both reads.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct FateCounts {
+    input: ReadCount,
+    fates: BTreeMap<Fate, ReadCount>,
+}
+
+impl FateCounts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts a read of `input_bases` whose trimmed sequence of
+    /// `bases` has fate `fate`.
+    pub fn add(&mut self, input_bases: usize, fate: &Fate, bases: usize) {
+        self.input.add(input_bases);
+        self.fates.entry(fate.clone()).or_default().add(bases);
+    }
+
+    pub fn merge(&mut self, other: &FateCounts) {
+        self.input.merge(&other.input);
+        for (fate, count) in other.fates.iter() {
+            self.fates.entry(fate.clone()).or_default().merge(count);
+        }
+    }
+
+    pub fn input(&self) -> ReadCount {
+        self.input
+    }
+
+    pub fn fate(&self, fate: &Fate) -> ReadCount {
+        self.fates.get(fate).copied().unwrap_or_default()
+    }
+
+    /// Iterates over the fates with any reads, in `Fate` order.
+    pub fn iter(&self) -> impl Iterator<Item = (&Fate, ReadCount)> + '_ {
+        self.fates.iter().map(|(fate, count)| (fate, *count))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn length_histogram() {
+        let mut hist = LengthHistogram::new();
+        hist.add(3);
+        hist.add(5);
+        hist.add(3);
+
+        let mut other = LengthHistogram::new();
+        other.add(7);
+        other.add(0);
+        hist.merge(&other);
+
+        assert_eq!(hist.iter().collect::<Vec<_>>(), vec![(0, 1), (3, 2), (5, 1), (7, 1)]);
+        assert_eq!(hist.count(3), 2);
+        assert_eq!(hist.count(9), 0);
+        assert_eq!(hist.total(), 5);
+        assert_eq!(hist.total_len(), 18);
+    }
+
+    #[test]
+    fn error_histogram() {
+        let mut hist = ErrorHistogram::new();
+        hist.add(5, 0);
+        hist.add(5, 1);
+
+        let mut other = ErrorHistogram::new();
+        other.add(5, 0);
+        other.add(3, 0);
+        hist.merge(&other);
+
+        assert_eq!(hist.iter().collect::<Vec<_>>(), vec![(3, &[1][..]), (5, &[2, 1][..])]);
+        assert_eq!(hist.count(5), 3);
+        assert_eq!(hist.count(4), 0);
+        assert_eq!(hist.total(), 4);
+    }
+
+    #[test]
+    fn fate_counts() {
+        let mut counts = FateCounts::new();
+        counts.add(10, &Fate::Output, 8);
+        counts.add(10, &Fate::TooShort, 2);
+
+        let mut other = FateCounts::new();
+        other.add(12, &Fate::Output, 12);
+        counts.merge(&other);
+
+        assert_eq!(counts.input(), ReadCount { reads: 3, bases: 32 });
+        assert_eq!(counts.fate(&Fate::Output), ReadCount { reads: 2, bases: 20 });
+        assert_eq!(counts.fate(&Fate::TooLong), ReadCount::default());
+        assert_eq!(counts.iter().count(), 2);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::adapter::AdapterMatch;
 use crate::output::Fate;
 
 #[derive(Clone, Debug)]
@@ -9,6 +10,8 @@ pub struct Trimming<'a> {
     trim_len: usize,
     umi_indices: Vec<usize>,
     read_tag: Option<usize>,
+    adapter_matches: Vec<AdapterMatch>,
+    n_masks: Vec<(usize, usize)>,
     fate: Fate,
 }
 
@@ -21,6 +24,8 @@ impl <'a> Trimming<'a> {
                    trim_len: seq.len(),
                    umi_indices: Vec::new(),
                    read_tag: None,
+                   adapter_matches: Vec::new(),
+                   n_masks: Vec::new(),
                    fate: Fate::Output,
         }
     }
@@ -33,6 +38,9 @@ impl <'a> Trimming<'a> {
     pub fn qual_trimmed(&self) -> Option<&'a [u8]> { self.qual.map(|q| &q[self.trim_start..self.trim_end()]) }
 
     pub fn len_trimmed(&self) -> usize { self.trim_len }
+
+    /// Starting position of the trimmed sequence within the raw read.
+    pub fn trim_start(&self) -> usize { self.trim_start }
     
     pub fn seq_raw(&self) -> &'a [u8] { self.seq }
 
@@ -40,6 +48,43 @@ impl <'a> Trimming<'a> {
 
     pub fn name_raw(&self) -> &'a [u8] { self.name }
 
+    pub fn fate(&self) -> &Fate { &self.fate }
+
+    pub fn set_fate(&mut self, fate: Fate) { self.fate = fate; }
+
+    /// Index of the tag (e.g., barcode) identified in the read, if any.
+    pub fn read_tag(&self) -> Option<usize> { self.read_tag }
+
+    pub fn set_read_tag(&mut self, read_tag: Option<usize>) { self.read_tag = read_tag; }
+
+    /// Adapter matches found in the read, in the order they were found.
+    pub fn adapter_matches(&self) -> &[AdapterMatch] { &self.adapter_matches }
+
+    pub fn add_adapter_match(&mut self, adapter_match: AdapterMatch) { self.adapter_matches.push(adapter_match); }
+
+    /// Regions of the raw read to be masked with `N` on output, as
+    /// half-open intervals.
+    pub fn n_masks(&self) -> &[(usize, usize)] { &self.n_masks }
+
+    /// Masks `start..stop` of the trimmed sequence with `N` on output.
+    pub fn mask_trimmed(&mut self, start: usize, stop: usize) {
+        let stop = stop.min(self.trim_len);
+        if start < stop {
+            self.n_masks.push((self.trim_start + start, self.trim_start + stop));
+        }
+    }
+
+    /// Trimmed sequence with masked regions replaced by `N`.
+    pub fn seq_trimmed_masked(&self, seq: &mut Vec<u8>) {
+        seq.clear();
+        seq.extend_from_slice(self.seq_trimmed());
+        for &(start, stop) in self.n_masks.iter() {
+            let start = start.clamp(self.trim_start, self.trim_end()) - self.trim_start;
+            let stop = stop.clamp(self.trim_start, self.trim_end()) - self.trim_start;
+            seq[start..stop].iter_mut().for_each(|nt| *nt = b'N');
+        }
+    }
+
     pub fn trim_from_start(&mut self, len: usize) -> usize {
         let reallen = len.min(self.trim_len);
         self.trim_start += reallen;
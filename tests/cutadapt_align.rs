@@ -339,7 +339,12 @@ fn test_align(reference: &[u8],
             reference_ends: ref_ends,
             query_ends: query_ends,
             matching: matching,
-            indel_cost: INDEL_COST as usize,
+            query_n: None,
+            mismatch_cost: 1,
+            insertion_cost: INDEL_COST as usize,
+            deletion_cost: INDEL_COST as usize,
+            gap_open_cost: 0,
+            mismatch_only: false,
             min_overlap: min_overlap,
         };
     let mut new_aligner = align::Aligner::new(&new_aligner_conf, reference).unwrap();
@@ -1111,3 +1116,499 @@ impl <'a> Aligner<'a> {
                                                                                 //             return None
                                                                                 //         _, length, _, _, matches, errors = result
                                                                                 //         return (self.m - length, self.m, n - length, n, matches, errors)
+
+/// Small deterministic generator for random sequences
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn pick<T: Copy>(&mut self, items: &[T]) -> T {
+        items[self.below(items.len())]
+    }
+
+    fn seq(&mut self, len: usize, alphabet: &[u8]) -> Vec<u8> {
+        (0..len).map(|_| alphabet[self.below(alphabet.len())]).collect()
+    }
+
+    /// Copy of `seq` with random substitutions, insertions, and deletions
+    fn mutate(&mut self, seq: &[u8], edits: usize) -> Vec<u8> {
+        let mut seq = seq.to_vec();
+        for _ in 0..edits {
+            let pos = self.below(seq.len() + 1);
+            match self.below(3) {
+                0 if pos < seq.len() => seq[pos] = b"ACGT"[self.below(4)],
+                1 => seq.insert(pos, b"ACGT"[self.below(4)]),
+                _ if pos < seq.len() => { seq.remove(pos); },
+                _ => (),
+            }
+        }
+        seq
+    }
+
+    /// Copy of `seq` with up to 3 random substitutions, including `N`
+    fn substitute(&mut self, seq: &[u8]) -> Vec<u8> {
+        let mut seq = seq.to_vec();
+        for _ in 0..self.below(4) {
+            let pos = self.below(seq.len());
+            seq[pos] = b"ACGTN"[self.below(5)];
+        }
+        seq
+    }
+
+    /// Random part of `adapter` between random flanks shorter than
+    /// `max_flank`, with `N` only in the first one
+    fn query(&mut self, adapter: &[u8], max_flank: usize) -> Vec<u8> {
+        let start = self.below(adapter.len() + 1);
+        let stop = start + self.below(adapter.len() - start + 1);
+        let (prefix_len, suffix_len) = (self.below(max_flank), self.below(max_flank));
+        let mut query = self.seq(prefix_len, b"ACGTN");
+        query.extend_from_slice(&adapter[start..stop]);
+        query.extend_from_slice(&self.seq(suffix_len, b"ACGT"));
+        query
+    }
+
+    /// Random ends, matching, and error rate with unit costs
+    fn conf(&mut self) -> align::AlignerConf {
+        align::AlignerConf {
+            max_error_rate: self.pick(&[0.0, 0.1, 0.2, 0.3]),
+            min_overlap: 1 + self.below(5),
+            ..conf(self.pick(&ALL_ENDS), self.pick(&ALL_ENDS), self.pick(&ALL_MATCHING))
+        }
+    }
+}
+
+const ALL_ENDS: [align::AlignEnds; 4] = [align::AlignEnds::Global, align::AlignEnds::LocalStart,
+                                         align::AlignEnds::LocalStop, align::AlignEnds::Local];
+
+const ALL_MATCHING: [align::AlignMatching; 4] = [align::AlignMatching::NoWildcard, align::AlignMatching::RefWildcard,
+                                                 align::AlignMatching::QueryWildcard, align::AlignMatching::BothWildcard];
+
+/// Aligner configuration with unit costs, an error rate of 0.1, and
+/// a minimum overlap of 3
+fn conf(reference_ends: align::AlignEnds, query_ends: align::AlignEnds,
+        matching: align::AlignMatching) -> align::AlignerConf {
+    align::AlignerConf {
+        max_error_rate: 0.1,
+        reference_ends,
+        query_ends,
+        matching,
+        query_n: None,
+        mismatch_cost: 1,
+        insertion_cost: 1,
+        deletion_cost: 1,
+        gap_open_cost: 0,
+        mismatch_only: false,
+        min_overlap: 3,
+    }
+}
+
+/// Asserts that two aligners, or other ways of locating `query`, agree
+#[track_caller]
+fn assert_same<T: PartialEq + std::fmt::Debug>(actual: T, expected: T, reference: &[u8], query: &[u8],
+                                               conf: &align::AlignerConf) {
+    assert_eq!(actual, expected, "reference {:?} query {:?} conf {:?}",
+               String::from_utf8_lossy(reference), String::from_utf8_lossy(query), conf);
+}
+
+#[test]
+fn bit_parallel_align() {
+    let mut rng = XorShift(0x2545_f491_4f6c_dd1d);
+    for _ in 0..3000 {
+        let ref_len = 1 + rng.below(70);
+        let reference = rng.seq(ref_len, b"ACGTACGTACGTN");
+        let conf = align::AlignerConf {
+            query_n: rng.pick(&[None, Some(align::QueryN::Mismatch), Some(align::QueryN::Match)]),
+            ..rng.conf()
+        };
+        let mut aligner = match align::Aligner::new(&conf, &reference) {
+            Ok(aligner) => aligner,
+            Err(_) => continue,
+        };
+
+        for _ in 0..10 {
+            let edits = rng.below(4);
+            let adapter = rng.mutate(&reference, edits);
+            let query = rng.query(&adapter, 40);
+            assert_same(aligner.locate(&query), aligner.locate_dp(&query), &reference, &query, &conf);
+        }
+    }
+}
+
+#[test]
+fn mismatch_only_align() {
+    let mut rng = XorShift(0x9e37_79b9_7f4a_7c15);
+    for _ in 0..3000 {
+        let ref_len = 1 + rng.below(40);
+        let conf = align::AlignerConf {
+            query_n: rng.pick(&[None, Some(align::QueryN::Mismatch), Some(align::QueryN::Match),
+                                Some(align::QueryN::Ignore)]),
+            mismatch_only: true,
+            ..rng.conf()
+        };
+        let reference = rng.seq(ref_len, b"ACGTACGTACGTN");
+        // Indels costing more than any alignment are never used.
+        let dp_conf = align::AlignerConf { insertion_cost: 200, deletion_cost: 200, mismatch_only: false, ..conf.clone() };
+        let (mut aligner, mut dp_aligner) = match (align::Aligner::new(&conf, &reference),
+                                                   align::Aligner::new(&dp_conf, &reference)) {
+            (Ok(aligner), Ok(dp_aligner)) => (aligner, dp_aligner),
+            _ => continue,
+        };
+
+        for _ in 0..10 {
+            let adapter = rng.substitute(&reference);
+            let query = rng.query(&adapter, 20);
+            assert_same(aligner.locate(&query), dp_aligner.locate(&query), &reference, &query, &conf);
+        }
+    }
+}
+
+#[test]
+fn mismatch_only_wildcards_align() {
+    // A partial match reaching the end of the reference leaves out
+    // the wildcards of the reference prefix of the same length from
+    // its effective length, as in the DP matrix, so that 1 error in
+    // `ACGTAC` is not allowed here.
+    let conf = align::AlignerConf {
+        max_error_rate: 0.2,
+        ..conf(align::AlignEnds::Local, align::AlignEnds::Local, align::AlignMatching::RefWildcard)
+    };
+    let reference = b"NNNNACGTAC";
+    let query = b"ACTTACGGGG";
+    let location = |l: align::Location| (l.refstart(), l.refstop(), l.querystart(), l.querystop(), l.errors());
+    let mut dp_aligner = align::Aligner::new(&conf, reference).unwrap();
+    assert_eq!(dp_aligner.locate(query).map(location), Some((0, 4, 6, 10, 0)));
+    let mismatch_conf = align::AlignerConf { mismatch_only: true, ..conf.clone() };
+    let mut aligner = align::Aligner::new(&mismatch_conf, reference).unwrap();
+    assert_eq!(aligner.locate(query).map(location), Some((0, 4, 6, 10, 0)));
+}
+
+#[test]
+fn traceback_align() {
+    let conf = align::AlignerConf {
+        max_error_rate: 0.2,
+        ..conf(align::AlignEnds::LocalStop, align::AlignEnds::Local, align::AlignMatching::RefWildcard)
+    };
+    let mut aligner = align::Aligner::new(&conf, b"AGATCNGAAGAGC").unwrap();
+    let query = b"TTTTAGATCTGACAGCTT";
+    let location = aligner.locate(query).unwrap();
+    let alignment = aligner.traceback(query, &location);
+    assert_eq!(alignment.cigar(), "7=1D1=1X3=");
+    assert_eq!(alignment.to_string(), "AGATCNGAAGAGC\n||||||| | |||\nAGATCTG-ACAGC");
+
+    let mismatch_conf = align::AlignerConf { mismatch_only: true, ..conf.clone() };
+    let mut aligner = align::Aligner::new(&mismatch_conf, b"AGATCNGAAGAGC").unwrap();
+    let query = b"TTTTAGTTCTGAAGAGCTT";
+    let location = aligner.locate(query).unwrap();
+    let alignment = aligner.traceback(query, &location);
+    assert_eq!(alignment.cigar(), "2=1X10=");
+    assert_eq!(alignment.to_string(), "AGATCNGAAGAGC\n|| ||||||||||\nAGTTCTGAAGAGC");
+
+    // The cost of the alignment is the number of errors, and it
+    // covers the matching parts of both sequences.
+    let mut rng = XorShift(0x1234_5678_9abc_def1);
+    for _ in 0..2000 {
+        let ref_len = 3 + rng.below(30);
+        let reference = rng.seq(ref_len, b"ACGT");
+        let mut aligner = align::Aligner::new(&conf, &reference).unwrap();
+        let edits = rng.below(4);
+        let adapter = rng.mutate(&reference, edits);
+        let prefix_len = rng.below(20);
+        let mut query = rng.seq(prefix_len, b"ACGT");
+        query.extend_from_slice(&adapter);
+        if let Some(location) = aligner.locate(&query) {
+            let alignment = aligner.traceback(&query, &location);
+            let ops = alignment.ops();
+            let cost = ops.iter().filter(|&&op| op != align::AlignOp::Match).count();
+            assert_eq!(cost, location.errors());
+            let consumes_ref = ops.iter().filter(|&&op| op != align::AlignOp::Insertion).count();
+            let consumes_query = ops.iter().filter(|&&op| op != align::AlignOp::Deletion).count();
+            assert_eq!(consumes_ref, location.refstop() - location.refstart());
+            assert_eq!(consumes_query, location.querystop() - location.querystart());
+        }
+    }
+}
+
+/// Cost of alignment operations with the gap costs of `conf`
+fn ops_cost(ops: &[align::AlignOp], conf: &align::AlignerConf) -> usize {
+    let mut cost = 0;
+    let mut previous = None;
+    for &op in ops {
+        cost += match op {
+            align::AlignOp::Match => 0,
+            align::AlignOp::Mismatch => conf.mismatch_cost,
+            align::AlignOp::Insertion => conf.insertion_cost,
+            align::AlignOp::Deletion => conf.deletion_cost,
+        };
+        if (op == align::AlignOp::Insertion || op == align::AlignOp::Deletion) && previous != Some(op) {
+            cost += conf.gap_open_cost;
+        }
+        previous = Some(op);
+    }
+    cost
+}
+
+#[test]
+fn gap_cost_align() {
+    let conf = align::AlignerConf {
+        max_error_rate: 0.4,
+        ..conf(align::AlignEnds::Global, align::AlignEnds::Global, align::AlignMatching::NoWildcard)
+    };
+    let reference = b"ACGTTGCAGGTCAATCCGTA";
+    let query = b"ACGTTGCACAATCCGTA";
+    let mut aligner = align::Aligner::new(&conf, reference).unwrap();
+    let location = aligner.locate(query).unwrap();
+    assert_eq!(location.errors(), 3);
+    assert_eq!(aligner.traceback(query, &location).cigar(), "8=3D9=");
+
+    let affine_conf = align::AlignerConf { deletion_cost: 2, gap_open_cost: 1, ..conf.clone() };
+    let mut aligner = align::Aligner::new(&affine_conf, reference).unwrap();
+    let location = aligner.locate(query).unwrap();
+    assert_eq!(location.errors(), 7);
+    assert_eq!(aligner.traceback(query, &location).cigar(), "8=3D9=");
+
+    let query = b"ACGTTGCAGGTTCAATCCGTA";
+    let insertion_conf = align::AlignerConf { insertion_cost: 2, ..conf.clone() };
+    let mut aligner = align::Aligner::new(&insertion_conf, reference).unwrap();
+    assert_eq!(aligner.locate(query).unwrap().errors(), 2);
+    let zero_conf = align::AlignerConf { max_error_rate: 0.0, ..insertion_conf.clone() };
+    assert_eq!(align::Aligner::new(&zero_conf, reference).unwrap().locate(query), None);
+
+    // Multiplying all costs and the error rate finds the same
+    // alignments, and the cost of each alignment is that of its
+    // traceback.
+    let mut rng = XorShift(0x0bad_5eed_dead_beef);
+    for _ in 0..5000 {
+        let ref_len = 3 + rng.below(30);
+        let reference = rng.seq(ref_len, b"ACGT");
+        let edits = rng.below(6);
+        let mut query = rng.mutate(&reference, edits);
+        query.truncate(ref_len);
+        if query.is_empty() {
+            continue;
+        }
+        let conf = align::AlignerConf {
+            max_error_rate: rng.pick(&[0.0, 0.125, 0.25, 0.5]),
+            reference_ends: rng.pick(&ALL_ENDS),
+            query_ends: rng.pick(&ALL_ENDS),
+            min_overlap: 1 + rng.below(3),
+            ..conf.clone()
+        };
+        let scale = 2 + rng.below(3);
+        let scaled_conf = align::AlignerConf {
+            max_error_rate: conf.max_error_rate * scale as f64,
+            mismatch_cost: scale,
+            insertion_cost: scale,
+            deletion_cost: scale,
+            ..conf.clone()
+        };
+        let location = align::Aligner::new(&conf, &reference).unwrap().locate(&query);
+        let scaled_location = align::Aligner::new(&scaled_conf, &reference).unwrap().locate(&query);
+        assert_same(location.as_ref().map(|l| (l.refstart(), l.refstop(), l.querystart(), l.querystop(), l.matches(), l.errors() * scale)),
+                    scaled_location.as_ref().map(|l| (l.refstart(), l.refstop(), l.querystart(), l.querystop(), l.matches(), l.errors())),
+                    &reference, &query, &scaled_conf);
+
+        let (insertion_cost, deletion_cost) = (1 + rng.below(3), 1 + rng.below(3));
+        let mismatch_cost = (1 + rng.below(3)).min(insertion_cost + deletion_cost);
+        let gap_conf = align::AlignerConf {
+            max_error_rate: conf.max_error_rate * 1.5 * mismatch_cost as f64,
+            mismatch_cost,
+            insertion_cost,
+            deletion_cost,
+            gap_open_cost: rng.below(4),
+            ..conf.clone()
+        };
+        let mut aligner = align::Aligner::new(&gap_conf, &reference).unwrap();
+        if let Some(location) = aligner.locate(&query) {
+            let ops = aligner.traceback(&query, &location).ops().to_vec();
+            assert_same(ops_cost(&ops, &gap_conf), location.errors(), &reference, &query, &gap_conf);
+            let matches = ops.iter().filter(|&&op| op == align::AlignOp::Match).count();
+            assert!(matches >= location.matches());
+        }
+    }
+}
+
+#[test]
+fn reusable_align() {
+    let mut rng = XorShift(0x5eed_0123_4567_89ab);
+    for _ in 0..200 {
+        let conf = align::AlignerConf { mismatch_only: rng.below(2) == 0, ..rng.conf() };
+        let mut reused = align::Aligner::new(&conf, b"ACGT").unwrap();
+        let query_len = 1 + rng.below(100);
+        let query = rng.seq(query_len, b"ACGTN");
+        let mut bquery = Vec::new();
+        reused.encode_query(&query, &mut bquery);
+        for _ in 0..10 {
+            let ref_len = 1 + rng.below(80);
+            let reference = rng.seq(ref_len, b"ACGTN");
+            let mut fresh = match align::Aligner::new(&conf, &reference) {
+                Ok(aligner) => aligner,
+                Err(_) => {
+                    assert!(reused.set_reference(&reference).is_err());
+                    continue;
+                }
+            };
+            reused.set_reference(&reference).unwrap();
+            assert_eq!(reused.effective_length(), fresh.effective_length());
+            let expected = fresh.locate(&query);
+            assert_same(reused.locate_encoded(&bquery), expected.clone(), &reference, &query, &conf);
+            assert_same(reused.locate(&query), expected, &reference, &query, &conf);
+        }
+    }
+}
+
+#[test]
+fn locate_all_align() {
+    let conf = conf(align::AlignEnds::LocalStop, align::AlignEnds::Local, align::AlignMatching::RefWildcard);
+    let adapter = b"AGATCGGAAGAGC";
+    let mut aligner = align::Aligner::new(&conf, adapter).unwrap();
+    let query = b"TTTTTAGATCGGAAGAGCCCCCCCCCAGATCTGAAGAGCGGGGGAGAT";
+    let locations = aligner.locate_all(query)
+        .map(|l| (l.querystart(), l.querystop(), l.errors()))
+        .collect::<Vec<_>>();
+    assert_eq!(locations, vec![(5, 18, 0), (26, 39, 1), (44, 48, 0)]);
+    assert_eq!(aligner.locate_all(b"CCCCCCCCCC").next(), None);
+
+    // Matches are ordered and non-overlapping, within the error
+    // rate, and include the best match in the whole query.
+    let mut rng = XorShift(0xfeed_face_cafe_f00d);
+    for _ in 0..1000 {
+        let ref_len = 3 + rng.below(20);
+        let reference = rng.seq(ref_len, b"ACGT");
+        let mut query = Vec::new();
+        for _ in 0..rng.below(5) {
+            let spacer_len = rng.below(15);
+            query.extend(rng.seq(spacer_len, b"ACGT"));
+            let edits = rng.below(3);
+            query.extend(rng.mutate(&reference, edits));
+        }
+        let mut aligner = align::Aligner::new(&align::AlignerConf { max_error_rate: 0.2, ..conf.clone() }, &reference).unwrap();
+        let best = aligner.locate(&query);
+        let locations = aligner.locate_all(&query).collect::<Vec<_>>();
+        assert_eq!(best.is_some(), !locations.is_empty());
+        if let Some(best) = best {
+            assert!(locations.contains(&best));
+        }
+        for pair in locations.windows(2) {
+            assert!(pair[0].querystop() <= pair[1].querystart());
+        }
+        for location in locations.iter() {
+            assert!(location.errors() as f64 <= (location.refstop() - location.refstart()) as f64 * 0.2);
+        }
+    }
+}
+
+#[test]
+fn query_n_align() {
+    let conf = conf(align::AlignEnds::Global, align::AlignEnds::Local, align::AlignMatching::BothWildcard);
+    let reference = b"AGATCGRAAGAGC";
+    let locate = |conf: &align::AlignerConf, query: &[u8]| {
+        align::Aligner::new(conf, reference).unwrap().locate(query).map(|l| (l.querystart(), l.matches(), l.errors()))
+    };
+
+    // Wildcards on both sides match each other.
+    assert_eq!(locate(&conf, b"TTTTAGATCGNAAGAGCTTTT"), Some((4, 13, 0)));
+    assert_eq!(locate(&conf, b"TTTTAGATCGRAAGAGCTTTT"), Some((4, 13, 0)));
+    assert_eq!(locate(&conf, b"TTTTAGATCGSAAGAGCTTTT"), Some((4, 13, 0)));
+    assert_eq!(locate(&conf, b"TTTTAGATCGYAAGAGCTTTT"), Some((4, 12, 1)));
+
+    let query = b"TTTTANATCNGAAGAGCTTTT";
+    assert_eq!(locate(&conf, query), Some((4, 13, 0)));
+    let mismatch_conf = align::AlignerConf { query_n: Some(align::QueryN::Mismatch), ..conf.clone() };
+    assert_eq!(locate(&mismatch_conf, query), None);
+    let ignore_conf = align::AlignerConf { query_n: Some(align::QueryN::Ignore), ..conf.clone() };
+    assert_eq!(locate(&ignore_conf, query), Some((4, 11, 0)));
+    let exact_conf = align::AlignerConf { max_error_rate: 0.0, ..ignore_conf.clone() };
+    assert_eq!(locate(&exact_conf, query), Some((4, 11, 0)));
+
+    // Ignored Ns are left out of the length for the error rate, which
+    // here allows one error in 10 bases.
+    let query = b"TTTTANATCNGAANAGCATTT";
+    assert_eq!(locate(&ignore_conf, b"TTTTANATCNGTANAGCTTTT"), Some((4, 9, 1)));
+    assert_eq!(locate(&ignore_conf, b"TTTTANATCNGTTNAGCTTTT"), None);
+    assert_eq!(locate(&align::AlignerConf { mismatch_only: true, ..ignore_conf.clone() }, query), Some((4, 10, 0)));
+    let mut aligner = align::Aligner::new(&ignore_conf, reference).unwrap();
+    let location = aligner.locate(query).unwrap();
+    assert_eq!(aligner.traceback(query, &location).to_string(), "AGATCGRAAGAGC\n| ||| ||| |||\nANATCNGAANAGC");
+
+    let nowildcard_conf = align::AlignerConf { matching: align::AlignMatching::NoWildcard, ..conf.clone() };
+    assert_eq!(locate(&nowildcard_conf, b"TTTTAGANCGGAAGAGCTTTT"), None);
+    let match_conf = align::AlignerConf { query_n: Some(align::QueryN::Match), ..nowildcard_conf.clone() };
+    assert_eq!(locate(&match_conf, b"TTTTAGANCGGAAGAGCTTTT"), Some((4, 12, 1)));
+}
+
+#[test]
+fn comparer_align() {
+    let conf = align::AlignerConf {
+        mismatch_only: true,
+        min_overlap: 10,
+        ..conf(align::AlignEnds::Global, align::AlignEnds::LocalStop, align::AlignMatching::RefWildcard)
+    };
+    let reference = b"ACGTNACGTACGT";
+    let mut prefix = align::PrefixComparer::new(&conf, reference).unwrap();
+    let mut suffix = align::SuffixComparer::new(&conf, reference).unwrap();
+    let location = |l: align::Location| (l.refstart(), l.refstop(), l.querystart(), l.querystop(), l.matches(), l.errors());
+    assert_eq!(prefix.locate(b"ACGTGACGTTCGTGGGGG").map(location), Some((0, 13, 0, 13, 12, 1)));
+    assert_eq!(prefix.locate(b"ACGTGACGTTCGAGGGGG").map(location), None);
+    assert_eq!(prefix.locate(b"GACGTGACGTACGTGGGG").map(location), None);
+    assert_eq!(suffix.locate(b"GGGGGACGTGACGTTCGT").map(location), Some((0, 13, 5, 18, 12, 1)));
+    // Partial matches of at least `min_overlap` bases
+    assert_eq!(prefix.locate(b"ACGTGACGTAC").map(location), Some((0, 11, 0, 11, 11, 0)));
+    assert_eq!(suffix.locate(b"TGACGTACGT").map(location), Some((3, 13, 0, 10, 10, 0)));
+    assert_eq!(suffix.locate(b"GACGTACGT").map(location), None);
+    assert!(align::PrefixComparer::new(&align::AlignerConf { max_error_rate: 1.0, ..conf.clone() }, reference).is_err());
+    assert!(align::SuffixComparer::new(&conf, b"NNN").is_err());
+
+    // The comparers find the same matches as mismatch-only aligners
+    // with the corresponding ends.
+    let mut rng = XorShift(0x6a09_e667_f3bc_c908);
+    for _ in 0..2000 {
+        let ref_len = 1 + rng.below(40);
+        let reference = rng.seq(ref_len, b"ACGTACGTACGTN");
+        let conf = align::AlignerConf {
+            max_error_rate: rng.pick(&[0.0, 0.1, 0.2, 0.3]),
+            matching: rng.pick(&ALL_MATCHING),
+            query_n: rng.pick(&[None, Some(align::QueryN::Mismatch), Some(align::QueryN::Match),
+                                Some(align::QueryN::Ignore)]),
+            mismatch_cost: 1 + rng.below(2),
+            min_overlap: 1 + rng.below(ref_len),
+            ..conf.clone()
+        };
+        let prefix_conf = align::AlignerConf { reference_ends: align::AlignEnds::LocalStop,
+                                               query_ends: align::AlignEnds::LocalStop, ..conf.clone() };
+        let suffix_conf = align::AlignerConf { reference_ends: align::AlignEnds::LocalStart,
+                                               query_ends: align::AlignEnds::LocalStart, ..conf.clone() };
+        let (mut prefix, mut prefix_aligner, mut suffix, mut suffix_aligner) =
+            match (align::PrefixComparer::new(&conf, &reference), align::Aligner::new(&prefix_conf, &reference),
+                   align::SuffixComparer::new(&conf, &reference), align::Aligner::new(&suffix_conf, &reference)) {
+                (Ok(prefix), Ok(prefix_aligner), Ok(suffix), Ok(suffix_aligner)) =>
+                    (prefix, prefix_aligner, suffix, suffix_aligner),
+                _ => continue,
+            };
+
+        for _ in 0..10 {
+            let adapter = rng.substitute(&reference);
+            let start = rng.below(adapter.len() + 1);
+            let (prefix_len, suffix_len) = (rng.below(20), rng.below(20));
+            let mut query = adapter[start..].to_vec();
+            query.extend_from_slice(&rng.seq(suffix_len, b"ACGTN"));
+            assert_same(prefix.locate(&query), prefix_aligner.locate(&query), &reference, &query, &conf);
+            let mut bquery = Vec::new();
+            prefix_aligner.encode_query(&query, &mut bquery);
+            assert_eq!(prefix.locate_encoded(&bquery), prefix.locate(&query));
+
+            let mut query = rng.seq(prefix_len, b"ACGTN");
+            query.extend_from_slice(&adapter[..adapter.len() - start]);
+            assert_same(suffix.locate(&query), suffix_aligner.locate(&query), &reference, &query, &conf);
+        }
+    }
+}